@@ -1,4 +1,5 @@
 use lambda_http::{run, service_fn, Error, IntoResponse, Request};
+use lambda_proxy_metrics as metrics;
 use reqwest::{header::CONTENT_TYPE, Client, Response};
 use std::env;
 use tokio::process::Command;
@@ -6,14 +7,13 @@ use tracing::info;
 
 /// Invoke the router locally by sending the event to the router's local HTTP server.
 async fn invoke(event: &Request) -> Result<Response, Error> {
-    let url = format!("http://127.0.0.1:4000/graphql");
+    let url = "http://127.0.0.1:4000/graphql";
 
     let body = event.body();
     let event_payload = std::str::from_utf8(body).expect("invalid utf-8 sequence");
 
     let client = Client::new();
     info!("Proxying request to router: {:?}", event_payload);
-    println!("Proxying request to router: {:?}", event_payload);
 
     let resp = client
         .post(url)
@@ -23,15 +23,16 @@ async fn invoke(event: &Request) -> Result<Response, Error> {
         .send()
         .await?;
     info!("Response from router: {:?}", resp);
-    println!("Response from router: {:?}", resp);
     Ok(resp)
 }
 
 /// Pass on the Lambda event to the router and return the response.
 ///
 /// NOTE: We keep retrying the request every 10ms until we get a response from
-/// the router. This is because the router takes a short time to start up.
+/// the router. This is because the router takes a short time to start up. How long we waited
+/// ends up in the metrics of the request.
 async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
+    let operation = metrics::Operation::start(&event);
     let mut retries = 0;
     let mut response = invoke(&event).await;
     while retries < 500 && response.is_err() {
@@ -39,13 +40,21 @@ async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
         response = invoke(&event).await;
         retries += 1;
     }
-    println!("Retries: {}, waited a total {}ms", retries, retries * 10);
-    let resp = response?;
-    let status = resp.status();
-    let payload = resp.json::<serde_json::Value>().await?;
+    let result: Result<_, Error> = async {
+        let resp = response?;
+        let status = resp.status();
+        let payload = resp.json::<serde_json::Value>().await?;
+        Ok((status, payload))
+    }
+    .await;
+    let failed = match &result {
+        Ok((status, payload)) => metrics::failed(*status, payload),
+        Err(_) => true,
+    };
+    operation.finish(retries, failed);
 
     // TODO: Return whitelisted headers from the Router response.
-    Ok((status, payload))
+    result
 }
 
 #[tokio::main]
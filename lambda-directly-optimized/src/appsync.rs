@@ -0,0 +1,335 @@
+use apollo_router::services::router;
+use graphql_parser::schema::{Definition, TypeDefinition};
+use lambda_runtime::Error;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The argument types of every field on the root operation types of the supergraph, which we
+/// need to declare variables when reconstructing an operation from an AppSync event.
+#[derive(Debug, Default)]
+pub struct FieldArguments {
+    // Keyed by `(parent type name, field name)`, with the argument names and their types.
+    fields: HashMap<(String, String), Vec<(String, String)>>,
+}
+
+impl FieldArguments {
+    pub fn from_schema(schema: &str) -> Result<Self, Error> {
+        let document = graphql_parser::parse_schema::<String>(schema)
+            .map_err(|e| format!("failed to parse the supergraph schema: {e}"))?;
+        let mut fields = HashMap::new();
+        for definition in document.definitions {
+            if let Definition::TypeDefinition(TypeDefinition::Object(object)) = definition {
+                if object.name != "Query" && object.name != "Mutation" {
+                    continue;
+                }
+                for field in object.fields {
+                    let arguments = field
+                        .arguments
+                        .into_iter()
+                        .map(|argument| (argument.name, argument.value_type.to_string()))
+                        .collect();
+                    fields.insert((object.name.clone(), field.name), arguments);
+                }
+            }
+        }
+        Ok(FieldArguments { fields })
+    }
+}
+
+/// An AppSync direct Lambda resolver event. We only need the parts that tell us which field to
+/// resolve, see https://docs.aws.amazon.com/appsync/latest/devguide/resolver-context-reference.html.
+#[derive(Debug, Deserialize)]
+pub struct AppSyncEvent {
+    #[serde(default)]
+    arguments: Option<Map<String, Value>>,
+    info: AppSyncInfo,
+    #[serde(default)]
+    request: Option<AppSyncRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AppSyncInfo {
+    field_name: String,
+    parent_type_name: String,
+    #[serde(rename = "selectionSetGraphQL", default)]
+    selection_set_graphql: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AppSyncRequest {
+    #[serde(default)]
+    headers: Option<HashMap<String, String>>,
+}
+
+/// Check if an event is an AppSync resolver event, or a batch of them when the resolver has
+/// batching enabled.
+pub fn is_appsync_event(event: &Value) -> bool {
+    let is_single = |event: &Value| {
+        event.pointer("/info/fieldName").is_some()
+            && event.pointer("/info/parentTypeName").is_some()
+    };
+    match event {
+        Value::Array(events) => !events.is_empty() && events.iter().all(is_single),
+        _ => is_single(event),
+    }
+}
+
+impl AppSyncEvent {
+    /// Reconstruct a GraphQL operation against the supergraph that selects only the field that
+    /// AppSync asked us to resolve, passing the arguments as variables.
+    fn graphql_request(
+        &self,
+        field_arguments: &FieldArguments,
+    ) -> Result<apollo_router::graphql::Request, String> {
+        let info = &self.info;
+        let operation_type = match info.parent_type_name.as_str() {
+            "Query" => "query",
+            "Mutation" => "mutation",
+            parent_type_name => {
+                return Err(format!(
+                    "only fields on Query and Mutation can be resolved, not {parent_type_name}.{}",
+                    info.field_name
+                ))
+            }
+        };
+        let argument_types = field_arguments
+            .fields
+            .get(&(info.parent_type_name.clone(), info.field_name.clone()))
+            .ok_or_else(|| {
+                format!(
+                    "{}.{} does not exist in the supergraph",
+                    info.parent_type_name, info.field_name
+                )
+            })?;
+
+        // Only declare the arguments that AppSync actually gave us, so that default values in the
+        // schema keep working for the rest.
+        let arguments = self.arguments.clone().unwrap_or_default();
+        let (definitions, passed): (Vec<_>, Vec<_>) = argument_types
+            .iter()
+            .filter(|(name, _)| arguments.contains_key(name))
+            .map(|(name, ty)| (format!("${name}: {ty}"), format!("{name}: ${name}")))
+            .unzip();
+        let definitions = if definitions.is_empty() {
+            String::new()
+        } else {
+            format!("({})", definitions.join(", "))
+        };
+        let passed =
+            if passed.is_empty() { String::new() } else { format!("({})", passed.join(", ")) };
+        let selection_set = info.selection_set_graphql.as_deref().unwrap_or_default();
+        let query = format!(
+            "{operation_type} AppSync{definitions} {{ {}{passed} {selection_set} }}",
+            info.field_name
+        );
+
+        serde_json::from_value(json!({
+            "query": query,
+            "operationName": "AppSync",
+            "variables": arguments,
+        }))
+        .map_err(|e| format!("invalid GraphQL request: {e}"))
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let request_headers = self.request.as_ref().and_then(|request| request.headers.as_ref());
+        for (name, value) in request_headers.into_iter().flatten() {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value))
+            {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Resolve the field through the supergraph, and pick out the field's value from the
+    /// response. Any GraphQL errors are turned into an error message, which AppSync adds to the
+    /// `errors` of its own response.
+    async fn resolve(
+        &self,
+        supergraph: Arc<Mutex<router::BoxCloneService>>,
+        field_arguments: &FieldArguments,
+    ) -> Result<Value, String> {
+        let request = self.graphql_request(field_arguments)?;
+        let headers = self.headers();
+        let verified = crate::auth::authenticate(&headers).await;
+        let (_status, response) =
+            crate::execute(supergraph, None, request, &headers, verified, Vec::new())
+                .await
+                .map_err(|e| e.to_string())?;
+        let messages: Vec<&str> = response
+            .get("errors")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|error| error.get("message").and_then(Value::as_str))
+            .collect();
+        if !messages.is_empty() {
+            return Err(messages.join("; "));
+        }
+        Ok(response
+            .pointer(&format!("/data/{}", self.info.field_name))
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Handle an AppSync resolver event, or a batch of them.
+pub async fn handle(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    field_arguments: &FieldArguments,
+    event: Value,
+) -> Result<Value, Error> {
+    if event.is_array() {
+        // Batched resolvers expect a result for each event in the same order, where errors are
+        // reported per event rather than failing the whole batch.
+        let events: Vec<AppSyncEvent> = serde_json::from_value(event)?;
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            let result = match event.resolve(supergraph.clone(), field_arguments).await {
+                Ok(data) => json!({ "data": data }),
+                Err(message) => {
+                    json!({ "data": null, "errorMessage": message, "errorType": "GraphQLError" })
+                }
+            };
+            results.push(result);
+        }
+        Ok(Value::from(results))
+    } else {
+        let event: AppSyncEvent = serde_json::from_value(event)?;
+        Ok(event.resolve(supergraph, field_arguments).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        type Query {
+          product(id: ID!): Product
+          products(first: Int = 10, after: String, filter: ProductFilter): [Product!]!
+        }
+        type Mutation {
+          addReview(product: ID!, review: ReviewInput!): Review
+        }
+        type Product { id: ID! name: String price: Int reviews: [Review!]! }
+        type Review { id: ID! body: String author: User }
+        type User { id: ID! name: String }
+        input ProductFilter { category: String }
+        input ReviewInput { body: String! }
+    "#;
+
+    fn event(json: Value) -> AppSyncEvent {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn graphql_request(event: &AppSyncEvent) -> Result<apollo_router::graphql::Request, String> {
+        event.graphql_request(&FieldArguments::from_schema(SCHEMA).unwrap())
+    }
+
+    #[test]
+    fn selects_the_resolved_field_with_its_arguments_as_variables() {
+        let event: Value = serde_json::from_str(include_str!("../../events/appsync.json")).unwrap();
+        assert!(is_appsync_event(&event));
+        let request = graphql_request(&serde_json::from_value(event).unwrap()).unwrap();
+        assert_eq!(
+            request.query.as_deref(),
+            Some("query AppSync($id: ID!) { product(id: $id) {\n  id\n  name\n  price\n} }")
+        );
+        assert_eq!(request.operation_name.as_deref(), Some("AppSync"));
+        assert_eq!(serde_json::to_value(&request.variables).unwrap(), json!({ "id": "1" }));
+    }
+
+    #[test]
+    fn keeps_nested_selections_and_schema_defaults() {
+        // Only the arguments AppSync passed are declared, so `first` keeps its default.
+        let request = graphql_request(&event(json!({
+            "arguments": { "filter": { "category": "books" }, "after": "abc" },
+            "info": {
+                "parentTypeName": "Query",
+                "fieldName": "products",
+                "selectionSetGraphQL": "{ id reviews { body author { name } } }",
+            },
+        })))
+        .unwrap();
+        assert_eq!(
+            request.query.as_deref(),
+            Some(
+                "query AppSync($after: String, $filter: ProductFilter) \
+                 { products(after: $after, filter: $filter) { id reviews { body author { name } } } }"
+            )
+        );
+
+        let request = graphql_request(&event(json!({
+            "arguments": { "product": "1", "review": { "body": "Great" } },
+            "info": {
+                "parentTypeName": "Mutation",
+                "fieldName": "addReview",
+                "selectionSetGraphQL": "{ id }",
+            },
+        })))
+        .unwrap();
+        assert_eq!(
+            request.query.as_deref(),
+            Some(
+                "mutation AppSync($product: ID!, $review: ReviewInput!) \
+                 { addReview(product: $product, review: $review) { id } }"
+            )
+        );
+        assert_eq!(request.variables.get("review"), Some(&json!({ "body": "Great" }).into()));
+
+        // Scalar fields have no selection set, and fields without arguments no parentheses.
+        let request = graphql_request(&event(json!({
+            "info": { "parentTypeName": "Query", "fieldName": "products" },
+        })))
+        .unwrap();
+        assert_eq!(request.query.as_deref(), Some("query AppSync { products  }"));
+        assert!(request.variables.is_empty());
+    }
+
+    #[test]
+    fn rejects_fields_outside_the_supergraph_roots() {
+        let error = graphql_request(&event(json!({
+            "info": { "parentTypeName": "Subscription", "fieldName": "onReview" },
+        })))
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "only fields on Query and Mutation can be resolved, not Subscription.onReview"
+        );
+        let error = graphql_request(&event(json!({
+            "info": { "parentTypeName": "Query", "fieldName": "reviews" },
+        })))
+        .unwrap_err();
+        assert_eq!(error, "Query.reviews does not exist in the supergraph");
+    }
+
+    #[test]
+    fn detects_single_and_batched_events() {
+        let single: Value =
+            serde_json::from_str(include_str!("../../events/appsync.json")).unwrap();
+        assert!(is_appsync_event(&json!([single.clone(), single.clone()])));
+        assert!(!is_appsync_event(&json!([])));
+        assert!(!is_appsync_event(&json!([single, { "info": {} }])));
+        assert!(!is_appsync_event(&json!({ "query": "{ me { name } }" })));
+    }
+
+    #[test]
+    fn passes_on_the_request_headers() {
+        let event = event(json!({
+            "request": { "headers": { "authorization": "Bearer abc", "bad header": "x" } },
+            "info": { "parentTypeName": "Query", "fieldName": "product" },
+        }));
+        let headers = event.headers();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["authorization"], "Bearer abc");
+    }
+}
@@ -0,0 +1,84 @@
+use graphql_parser::query::{Definition, Document, OperationDefinition, SelectionSet, Type};
+
+/// The name of the type that a field or argument holds, whether or not it's in a list or
+/// non-null.
+pub fn named_type(value_type: &Type<'_, String>) -> String {
+    match value_type {
+        Type::NamedType(name) => name.clone(),
+        Type::ListType(inner) | Type::NonNullType(inner) => named_type(inner),
+    }
+}
+
+/// The name of an operation, which the query shorthand (`{ ... }`) doesn't have.
+pub fn operation_name<'a>(operation: &'a OperationDefinition<'_, String>) -> Option<&'a str> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name.as_deref(),
+        OperationDefinition::Mutation(mutation) => mutation.name.as_deref(),
+        OperationDefinition::Subscription(subscription) => subscription.name.as_deref(),
+    }
+}
+
+/// The operation that a request runs: the one with the given name, or otherwise the first one.
+pub fn select_operation<'a, 'b>(
+    document: &'a Document<'b, String>,
+    name: Option<&str>,
+) -> Option<&'a OperationDefinition<'b, String>> {
+    let mut operations = document.definitions.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(operation),
+        _ => None,
+    });
+    match name {
+        Some(name) => operations.find(|operation| operation_name(operation) == Some(name)),
+        None => operations.next(),
+    }
+}
+
+/// The root operation type of an operation, e.g. `query`, and its top-level selections.
+pub fn root_selection_set<'a, 'b>(
+    operation: &'a OperationDefinition<'b, String>,
+) -> (&'static str, &'a SelectionSet<'b, String>) {
+    match operation {
+        OperationDefinition::SelectionSet(selection_set) => ("query", selection_set),
+        OperationDefinition::Query(query) => ("query", &query.selection_set),
+        OperationDefinition::Mutation(mutation) => ("mutation", &mutation.selection_set),
+        OperationDefinition::Subscription(subscription) => {
+            ("subscription", &subscription.selection_set)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_the_named_or_the_first_operation() {
+        let document = graphql_parser::parse_query::<String>(
+            "query A { a } mutation B { b } fragment F on Query { c }",
+        )
+        .unwrap();
+        let selected = |name| select_operation(&document, name).map(root_selection_set);
+        assert_eq!(selected(None).map(|(root, _)| root), Some("query"));
+        assert_eq!(selected(Some("B")).map(|(root, _)| root), Some("mutation"));
+        assert!(selected(Some("F")).is_none());
+        assert_eq!(select_operation(&document, Some("A")).and_then(operation_name), Some("A"));
+
+        let shorthand = graphql_parser::parse_query::<String>("{ a }").unwrap();
+        let operation = select_operation(&shorthand, None).unwrap();
+        assert_eq!(operation_name(operation), None);
+        assert_eq!(root_selection_set(operation).0, "query");
+    }
+
+    #[test]
+    fn unwraps_lists_and_non_null_types() {
+        let schema = graphql_parser::parse_schema::<String>("type Q { a: [[B!]]! }").unwrap();
+        let graphql_parser::schema::Definition::TypeDefinition(
+            graphql_parser::schema::TypeDefinition::Object(object),
+        ) = &schema.definitions[0]
+        else {
+            unreachable!()
+        };
+        assert_eq!(named_type(&object.fields[0].field_type), "B");
+    }
+}
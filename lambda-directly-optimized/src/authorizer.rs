@@ -0,0 +1,218 @@
+use lambda_runtime::Error;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{Map, Value};
+use std::env;
+use std::sync::OnceLock;
+use tracing::warn;
+
+static MAPPING: OnceLock<Mapping> = OnceLock::new();
+
+/// Maps the identity that an API Gateway authorizer put in the `requestContext` of an event onto
+/// the router request, so that subgraphs can trust it without validating the token again.
+///
+/// Both are configured as comma-separated lists of `claim=target` pairs:
+///
+/// - `APOLLO_ROUTER_AUTHORIZER_HEADERS` sets a header on the supergraph request, e.g.
+///   `sub=x-user-id`, which the `headers` rules can then propagate to the subgraphs.
+/// - `APOLLO_ROUTER_AUTHORIZER_CONTEXT` inserts a router context entry, e.g. `sub=user_id`.
+///
+/// Clients could send those headers themselves, so any incoming header with a mapped name is
+/// dropped, whether or not the authorizer provided the claim.
+struct Mapping {
+    headers: Vec<(String, HeaderName)>,
+    context: Vec<(String, String)>,
+}
+
+fn pairs(var: &str, pairs: Option<String>) -> Result<Vec<(String, String)>, Error> {
+    let Some(pairs) = pairs else {
+        return Ok(Vec::new());
+    };
+    pairs
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((claim, target)) => Ok((claim.trim().to_string(), target.trim().to_string())),
+            None => Err(format!("{var} must be a list of claim=target pairs, got {pair}").into()),
+        })
+        .collect()
+}
+
+impl Mapping {
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let headers_var = "APOLLO_ROUTER_AUTHORIZER_HEADERS";
+        let headers = pairs(headers_var, var(headers_var))?
+            .into_iter()
+            .map(|(claim, header)| Ok((claim, HeaderName::from_bytes(header.as_bytes())?)))
+            .collect::<Result<_, Error>>()?;
+        let context_var = "APOLLO_ROUTER_AUTHORIZER_CONTEXT";
+        let context = pairs(context_var, var(context_var))?;
+        Ok(Mapping { headers, context })
+    }
+
+    fn apply(&self, request_context: &Value, headers: &mut HeaderMap) -> Vec<(String, Value)> {
+        let claims = claims(request_context);
+        let claim = |name: &str| claims.and_then(|claims| claims.get(name));
+
+        for (name, header) in &self.headers {
+            headers.remove(header);
+            let Some(value) = claim(name) else {
+                continue;
+            };
+            match HeaderValue::from_str(&claim_as_string(value)) {
+                Ok(value) => {
+                    headers.insert(header.clone(), value);
+                }
+                Err(_) => warn!("The authorizer claim {} isn't a valid value for {}", name, header),
+            }
+        }
+        self.context
+            .iter()
+            .filter_map(|(name, key)| Some((key.clone(), claim(name)?.clone())))
+            .collect()
+    }
+}
+
+/// Read the mapping from the environment during init.
+pub fn init() -> Result<(), Error> {
+    let _ = MAPPING.set(Mapping::from_vars(|name| env::var(name).ok())?);
+    Ok(())
+}
+
+/// The headers that the authorizer claims are mapped onto.
+pub fn headers() -> Vec<HeaderName> {
+    let mapping = MAPPING.get().into_iter().flat_map(|mapping| &mapping.headers);
+    mapping.map(|(_, header)| header.clone()).collect()
+}
+
+/// The router context entries that the authorizer claims are mapped onto.
+pub fn context_keys() -> Vec<String> {
+    let mapping = MAPPING.get().into_iter().flat_map(|mapping| &mapping.context);
+    mapping.map(|(_, key)| key.clone()).collect()
+}
+
+/// The claims of whichever authorizer handled the request. Each authorizer type puts them in a
+/// different place, and a REST API Lambda authorizer mixes its context in with the principal.
+pub fn claims(request_context: &Value) -> Option<&Map<String, Value>> {
+    let authorizer = request_context.get("authorizer")?;
+    // HTTP API JWT authorizers, HTTP API Lambda authorizers, and REST API Cognito authorizers.
+    ["/jwt/claims", "/lambda", "/claims"]
+        .iter()
+        .find_map(|pointer| authorizer.pointer(pointer))
+        .unwrap_or(authorizer)
+        .as_object()
+}
+
+/// Authorizer contexts only hold strings, numbers and booleans, which we pass on as their text.
+fn claim_as_string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Set the mapped headers on the request, and return the router context entries to insert.
+pub fn apply(request_context: &Value, headers: &mut HeaderMap) -> Vec<(String, Value)> {
+    match MAPPING.get() {
+        Some(mapping) => mapping.apply(request_context, headers),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn mapping() -> Mapping {
+        Mapping::from_vars(vars(&[
+            ("APOLLO_ROUTER_AUTHORIZER_HEADERS", "sub=x-user-id, tenant=X-Tenant"),
+            ("APOLLO_ROUTER_AUTHORIZER_CONTEXT", "sub=user_id,admin=is_admin,"),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_the_mapping_from_the_environment() {
+        let mapping = mapping();
+        assert_eq!(
+            mapping.headers,
+            [
+                ("sub".to_string(), HeaderName::from_static("x-user-id")),
+                ("tenant".to_string(), HeaderName::from_static("x-tenant")),
+            ]
+        );
+        assert_eq!(
+            mapping.context,
+            [
+                ("sub".to_string(), "user_id".to_string()),
+                ("admin".to_string(), "is_admin".to_string()),
+            ]
+        );
+
+        let empty = Mapping::from_vars(vars(&[])).unwrap();
+        assert!(empty.headers.is_empty() && empty.context.is_empty());
+        for (name, value) in [
+            ("APOLLO_ROUTER_AUTHORIZER_HEADERS", "sub"),
+            ("APOLLO_ROUTER_AUTHORIZER_HEADERS", "sub=not a header"),
+            ("APOLLO_ROUTER_AUTHORIZER_CONTEXT", "sub=user_id,admin"),
+        ] {
+            assert!(Mapping::from_vars(vars(&[(name, value)])).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn finds_the_claims_of_each_authorizer() {
+        let event: Value =
+            serde_json::from_str(include_str!("../../events/apigw-v2-authorizer.json")).unwrap();
+        for (request_context, sub) in [
+            // HTTP API JWT authorizer.
+            (event["requestContext"].clone(), json!("1")),
+            // HTTP API Lambda authorizer.
+            (json!({ "authorizer": { "lambda": { "sub": "2" } } }), json!("2")),
+            // REST API Cognito authorizer.
+            (json!({ "authorizer": { "claims": { "sub": "3" } } }), json!("3")),
+            // REST API Lambda authorizer, with its context next to the principal.
+            (json!({ "authorizer": { "principalId": "me", "sub": 4 } }), json!(4)),
+        ] {
+            let claims = claims(&request_context).unwrap();
+            assert_eq!(claims.get("sub"), Some(&sub), "{request_context}");
+        }
+        assert!(claims(&json!({ "stage": "$default" })).is_none());
+        assert!(claims(&json!({ "authorizer": null })).is_none());
+    }
+
+    #[test]
+    fn maps_claims_onto_headers_and_context() {
+        let request_context = json!({
+            "authorizer": { "lambda": { "sub": 42, "admin": true, "tenant": "shop\nevil" } },
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", HeaderValue::from_static("spoofed"));
+        headers.insert("x-tenant", HeaderValue::from_static("spoofed"));
+        headers.insert("x-other", HeaderValue::from_static("kept"));
+        let context = mapping().apply(&request_context, &mut headers);
+
+        // Claims become their text, and a claim that can't be a header value drops the header
+        // rather than letting a client's value through.
+        assert_eq!(headers["x-user-id"], "42");
+        assert!(!headers.contains_key("x-tenant"));
+        assert_eq!(headers["x-other"], "kept");
+        assert_eq!(
+            context,
+            [("user_id".to_string(), json!(42)), ("is_admin".to_string(), json!(true))]
+        );
+
+        // Without an authorizer, mapped headers are still dropped.
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", HeaderValue::from_static("spoofed"));
+        assert!(mapping().apply(&json!({}), &mut headers).is_empty());
+        assert!(headers.is_empty());
+    }
+}
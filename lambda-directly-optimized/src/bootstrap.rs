@@ -0,0 +1,560 @@
+use crate::event::{EventKind, HttpRequest, HttpResponse};
+use crate::telemetry::Telemetry;
+use crate::{
+    appsync, auth, authorizer, cache, coprocessor, cors, direct, entities, extension, limits,
+    logging, metrics, plans, queue, ratelimit, rhai, telemetry, tenants, usage, xray,
+};
+use apollo_router::services::{router, subgraph, supergraph};
+use apollo_router::{Configuration, TestHarness};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tower::{BoxError, Layer, Service, ServiceExt};
+use tracing::{info, warn, Instrument, Span};
+use tracing_subscriber::prelude::*;
+
+/// Everything we set up during the initialization of the Lambda, and reuse across invocations.
+struct State {
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    /// The tenants that HTTP requests are routed to, if this function serves several supergraphs.
+    /// Other event sources always use the default supergraph.
+    tenants: Option<tenants::Tenants>,
+    field_arguments: appsync::FieldArguments,
+    cors: cors::Cors,
+    lifecycle: Arc<extension::Lifecycle>,
+}
+
+/// Run a GraphQL request through the supergraph, passing on the incoming HTTP headers so that
+/// the `headers` rules in the Router configuration can propagate them to the subgraphs, and
+/// inserting the given entries and the claims of the verified token into the router request
+/// context. Every operation is recorded in the metrics, the logs and, unless it's for a tenant,
+/// the usage reports. Requests with an invalid bearer token, or whose operation exceeds the
+/// limits of the tenant's supergraph, or of the default one, are turned away before they reach
+/// the supergraph, while anonymous queries may be answered from the response cache.
+pub(crate) async fn execute(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    tenant: Option<&tenants::Tenant>,
+    graphql_request: apollo_router::graphql::Request,
+    headers: &HeaderMap,
+    verified: auth::Verified,
+    mut context: Vec<(String, serde_json::Value)>,
+) -> Result<(StatusCode, serde_json::Value), Error> {
+    let started = Instant::now();
+    let operation = metrics::Operation::start(graphql_request.operation_name.as_deref(), headers);
+    let limits = match tenant {
+        Some(tenant) => tenant.limits.as_ref(),
+        None => limits::default_limits(),
+    };
+    let tenant = tenant.map(|tenant| tenant.name.as_str());
+    let result = match verified {
+        Ok(claims) => match limits::check(limits, &graphql_request) {
+            Ok(()) => {
+                let public = claims.is_none() && context.is_empty();
+                let claims = claims.map(|claims| (auth::CLAIMS_CONTEXT_KEY.to_string(), claims));
+                context.extend(claims);
+                let call = call_supergraph(supergraph, &graphql_request, headers, context);
+                cache::through(&graphql_request, headers, tenant, public, call).await
+            }
+            Err(rejection) => Ok(rejection),
+        },
+        Err(message) => Ok(auth::unauthorized(&message)),
+    };
+    let failed = match &result {
+        Ok((status, resp)) => metrics::failed(*status, resp),
+        Err(_) => true,
+    };
+    operation.finish(failed);
+    // Usage reports are for the graph of the default supergraph.
+    if tenant.is_none() {
+        usage::record(&graphql_request, headers, started.elapsed(), failed);
+    }
+    let (status, resp) = match &result {
+        Ok((status, resp)) => (Some(*status), Some(resp)),
+        Err(_) => (None, None),
+    };
+    logging::operation(&graphql_request, started.elapsed(), status, resp);
+    result
+}
+
+pub(crate) async fn call_supergraph(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    graphql_request: &apollo_router::graphql::Request,
+    headers: &HeaderMap,
+    context: Vec<(String, serde_json::Value)>,
+) -> Result<(StatusCode, serde_json::Value), Error> {
+    let builder = supergraph::Request::fake_builder()
+        .header(CONTENT_TYPE, "application/json")
+        .query(graphql_request.query.clone().unwrap_or("".to_string()))
+        .variables(graphql_request.variables.clone())
+        .extensions(graphql_request.extensions.clone());
+    let mut request = if let Some(operation_name) = graphql_request.operation_name.clone() {
+        builder.operation_name(operation_name).build().unwrap()
+    } else {
+        builder.build().unwrap()
+    };
+    for (name, value) in headers {
+        if *name != CONTENT_TYPE {
+            request.supergraph_request.headers_mut().append(name, value.clone());
+        }
+    }
+    xray::propagate(request.supergraph_request.headers_mut(), &request.context);
+    for (key, value) in context {
+        request.context.insert(key, value)?;
+    }
+
+    // Lock the supergraph for the brief time we are calling it. This will have no real-world
+    // impact on performance because the Lambda only ever serves one request at a time. Layers
+    // of a custom bootstrap may apply backpressure, so we wait for the supergraph to be ready.
+    let response = {
+        let mut s = supergraph.lock().await;
+        s.ready().await?.call(request.try_into().unwrap())
+    };
+    let mut r = response.await?;
+    let status = r.response.status();
+
+    let resp: serde_json::Value =
+        serde_json::from_slice(r.next_response().await.unwrap().unwrap().to_vec().as_slice())?;
+    Ok((status, resp))
+}
+
+/// Handle a GraphQL over HTTP request, from any of the supported front doors.
+async fn handle_request(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    tenant: Option<&tenants::Tenant>,
+    request: &HttpRequest,
+    verified: auth::Verified,
+) -> Result<HttpResponse, Error> {
+    if request.method != Method::GET && request.method != Method::POST {
+        return Ok(HttpResponse::graphql_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET and POST requests are supported",
+        ));
+    }
+    if let Some(response) = ratelimit::check(request, auth::claims(&verified)).await {
+        return Ok(response);
+    }
+    let graphql_request = match request.graphql_request() {
+        Ok(graphql_request) => graphql_request,
+        Err((status, message)) => return Ok(HttpResponse::graphql_error(status, &message)),
+    };
+    // Identity from an API Gateway authorizer has already been verified, so we hand it on.
+    let mut headers = request.headers.clone();
+    let context = authorizer::apply(&request.request_context, &mut headers);
+    let (status, resp) =
+        execute(supergraph, tenant, graphql_request, &headers, verified, context).await?;
+    let mut response = HttpResponse::json(status, &resp);
+    cache::decorate(&mut response);
+    Ok(response)
+}
+
+/// Detect what kind of event we were invoked with and handle it. HTTP events get their response
+/// shaped back into the format that their front door expects, while the other event sources get
+/// the response that their integration expects.
+async fn dispatch(state: &State, payload: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let kind = EventKind::detect(&payload).ok_or("unsupported event type")?;
+    info!("Received {:?} event", kind);
+    let trigger = match kind {
+        EventKind::Http(_) => "http",
+        EventKind::Sqs | EventKind::EventBridge => "pubsub",
+        EventKind::Direct | EventKind::AppSync | EventKind::InvalidateEntities => "other",
+    };
+    Span::current().record("faas.trigger", trigger);
+    let supergraph = Arc::clone(&state.supergraph);
+    match kind {
+        EventKind::Http(source) => {
+            let request = HttpRequest::from_event(source, payload)?;
+            // Routing, rate limiting and the supergraph all want the claims of the token.
+            let verified = auth::authenticate(&request.headers).await;
+            let (supergraph, cors, tenant) = match &state.tenants {
+                None => (supergraph, &state.cors, None),
+                Some(tenants) => match tenants.route(&request, auth::claims(&verified)) {
+                    Some(tenant) => (Arc::clone(&tenant.supergraph), &tenant.cors, Some(tenant)),
+                    None => return Ok(tenants::unknown().into_event(&request)),
+                },
+            };
+            if let Some(response) = cors.preflight(&request) {
+                return Ok(response.into_event(&request));
+            }
+            telemetry::set_parent_from_headers(&Span::current(), &request.headers);
+            let mut response = handle_request(supergraph, tenant, &request, verified).await?;
+            cors.decorate(&request, &mut response);
+            Ok(response.into_event(&request))
+        }
+        EventKind::Direct => direct::handle(supergraph, payload).await,
+        EventKind::AppSync => appsync::handle(supergraph, &state.field_arguments, payload).await,
+        EventKind::Sqs => queue::handle_sqs(supergraph, payload).await,
+        EventKind::EventBridge => queue::handle_eventbridge(supergraph, payload).await,
+        EventKind::InvalidateEntities => entities::invalidate(&payload),
+    }
+}
+
+/// Handle a single invocation, keeping track of it so that a shutdown can wait for it to finish.
+/// The invocation gets its own root span, which is exported after the response has gone out.
+async fn handle_event(
+    state: Arc<State>,
+    event: LambdaEvent<serde_json::Value>,
+) -> Result<serde_json::Value, Error> {
+    let (payload, context) = event.into_parts();
+    logging::set_request_id(Some(&context.request_id));
+    let _in_flight = state.lifecycle.start_invocation();
+    let span = telemetry::invocation_span(&context);
+    // Continue the trace that Lambda started for this invocation, unless an incoming HTTP
+    // request carries its own `traceparent`.
+    let trace_header = xray::from_invocation(context.xray_trace_id.as_deref());
+    if let Some(trace_header) = &trace_header {
+        telemetry::set_parent_from_headers(&span, &trace_header.headers());
+    }
+    let result = xray::scope(trace_header, dispatch(&state, payload).instrument(span)).await;
+    if result.is_err() {
+        state.lifecycle.record_error();
+    }
+    // The extension flushes buffered telemetry once the response has gone out, but without it
+    // nothing runs between returning and Lambda freezing the execution environment.
+    if !state.lifecycle.is_registered() {
+        state.lifecycle.run_after_invocation().await;
+    }
+    logging::set_request_id(None);
+    result
+}
+
+/// Before we cast the Router YAML configuration to a strongly typed struct, we need to manually
+/// handle any environment variables that are used in the configuration. This is normally handled
+/// by the regular Router, but is missing when manually loading the config.
+fn expand_env(config: &mut serde_yaml::Value) {
+    if let Some(map) = config.as_mapping_mut() {
+        if let Some(serde_yaml::Value::Mapping(ref mut nested_map)) =
+            map.get_mut(&serde_yaml::Value::from("override_subgraph_url"))
+        {
+            for (_key, nested_value) in nested_map.iter_mut() {
+                // Remove any env. parts of the string, which is specific to the Apollo configuration
+                // format (e.g. "${env.SUBGRAPH_USERS_URL:-http://127.0.0.1:3065/}").
+                let subgraph_override =
+                    serde_yaml::to_string(nested_value).unwrap().replace("${env.", "${");
+                // Expand any environment variables, and fallbacks, using the shell environment.
+                let expanded_env = shellexpand::env(&subgraph_override).unwrap();
+                // Replace the current original value with the expanded value.
+                *nested_value = serde_yaml::Value::from(expanded_env);
+            }
+        }
+    }
+}
+
+/// Build a router service for a supergraph, with our instrumentation and the layers of the
+/// bootstrap. The entities of a tenant are cached apart from those of other supergraphs, since
+/// their subgraphs may share names.
+async fn build_router(
+    bootstrap: &Arc<Bootstrap>,
+    configuration: Configuration,
+    schema: &str,
+    tenant: Option<&str>,
+) -> Result<router::BoxCloneService, Error> {
+    let xray_subsegments = xray::subsegments_enabled();
+    let tenant = tenant.map(str::to_string);
+    let supergraph_bootstrap = Arc::clone(bootstrap);
+    let subgraph_bootstrap = Arc::clone(bootstrap);
+    let supergraph = TestHarness::builder()
+        .configuration(Arc::new(configuration))
+        .schema(schema)
+        // Without this all subgraphs get an empty response by default.
+        .with_subgraph_network_requests()
+        .supergraph_hook(move |service| {
+            let layers = &supergraph_bootstrap.supergraph_layers;
+            layers.iter().fold(service, |service, layer| layer(service))
+        })
+        // The router's own telemetry isn't set up on this path, so we trace the fetches ourselves.
+        .subgraph_hook(move |name, service| {
+            let service = metrics::instrument_subgraph(name, service);
+            let service = telemetry::instrument_subgraph(name, service);
+            let service =
+                if xray_subsegments { xray::instrument_subgraph(name, service) } else { service };
+            // Outside of the metrics and the traces, so that they only show actual fetches.
+            let service = match &tenant {
+                Some(tenant) => entities::instrument_subgraph(&format!("{tenant}/{name}"), service),
+                None => entities::instrument_subgraph(name, service),
+            };
+            let service = cache::instrument_subgraph(service);
+            // The layers of a custom bootstrap wrap ours, so they see every fetch first.
+            let layers = &subgraph_bootstrap.subgraph_layers;
+            let service = layers.iter().fold(service, |service, layer| layer(service));
+            // Outermost, so that fetches skipped while warming up the plans aren't recorded.
+            plans::instrument_subgraph(service)
+        })
+        .build_router()
+        .await?;
+    Ok(bootstrap.router_layers.iter().fold(supergraph, |service, layer| layer(service)))
+}
+
+/// Set up the supergraph of a tenant from its own configuration and schema, going through the
+/// same steps as the default one, and warm up its query planner. Tenants that exclude tags get a
+/// contract of their schema.
+async fn build_tenant(
+    bootstrap: &Arc<Bootstrap>,
+    name: &str,
+    tenant: &tenants::TenantConfig,
+    plan_cache: &plans::PlanCache,
+) -> Result<tenants::Tenant, Error> {
+    let config_path = tenant.config.to_string_lossy();
+    let config = fs::read_to_string(&tenant.config)?;
+    let schema = tenant.schema()?;
+    let mut untyped_config = serde_yaml::from_str::<serde_yaml::Value>(&config)
+        .map_err(|e| format!("invalid configuration of tenant {name}: {e}"))?;
+    expand_env(&mut untyped_config);
+    bootstrap.configure_plugins(&mut untyped_config)?;
+    coprocessor::init(&mut untyped_config).await?;
+    rhai::resolve(&mut untyped_config, &config_path)?;
+    let cors = cors::Cors::from_config(&untyped_config)?;
+    let configuration = serde_yaml::from_value::<Configuration>(untyped_config)
+        .map_err(|e| format!("invalid configuration of tenant {name}: {e}"))?;
+
+    let supergraph = build_router(bootstrap, configuration, &schema, Some(name)).await?;
+    let supergraph = Arc::new(Mutex::new(supergraph));
+    let mut operations = vec![serde_json::from_value(serde_json::json!({
+        "query": "{ __typename }"
+    }))?];
+    operations.extend(plan_cache.operations(&schema));
+    operations.extend(plans::load_warmup(&config_path)?);
+    plans::warm(&supergraph, &operations).await;
+    let limits = limits::Limits::from_env(&schema)?;
+    Ok(tenants::Tenant { name: name.to_string(), supergraph, cors, limits })
+}
+
+async fn handler(bootstrap: Bootstrap, telemetry: Telemetry) -> Result<(), Error> {
+    let mut phases = metrics::InitPhases::start();
+    let config_path = env::var("APOLLO_ROUTER_CONFIG_PATH").unwrap_or("./router.yaml".to_string());
+    let schema_path =
+        env::var("APOLLO_ROUTER_SUPERGRAPH_PATH").unwrap_or("./supergraph.graphql".to_string());
+    let config = fs::read_to_string(&config_path)?;
+    let schema = fs::read_to_string(&schema_path)?;
+    phases.end("ConfigRead");
+
+    let mut untyped_config = serde_yaml::from_str::<serde_yaml::Value>(&config).unwrap();
+    expand_env(&mut untyped_config);
+    bootstrap.configure_plugins(&mut untyped_config)?;
+    phases.end("EnvExpansion");
+
+    // The Router's coprocessor plugin only calls HTTP endpoints, so we bridge it to the Invoke
+    // API when it's pointed at a Lambda function.
+    coprocessor::init(&mut untyped_config).await?;
+    // Rhai scripts are bundled next to `router.yaml`, and compiled when we build the router.
+    rhai::resolve(&mut untyped_config, &config_path)?;
+
+    // The Router applies its CORS configuration in its HTTP server, which we bypass.
+    let cors = cors::Cors::from_config(&untyped_config)?;
+
+    // We can finally convert our untyped YAML configuration into a strongly typed Configuration
+    // struct.
+    let configuration = serde_yaml::from_value::<Configuration>(untyped_config).unwrap();
+    phases.end("ConfigDeserialization");
+
+    // Look up the argument types of the root fields once, so we can turn AppSync resolver events
+    // into operations against the supergraph.
+    let field_arguments = appsync::FieldArguments::from_schema(&schema)?;
+    // Usage reporting needs to follow operations through the schema too.
+    usage::init(&schema)?;
+    // And so does computing the cost of operations.
+    limits::init(&schema)?;
+    phases.end("SchemaParse");
+
+    // Load the JWKS once, so that warm invocations only validate tokens.
+    auth::init().await?;
+    authorizer::init()?;
+    phases.end("Authentication");
+
+    ratelimit::init(&config_path).await?;
+    phases.end("RateLimit");
+
+    cache::init(&config_path).await?;
+    entities::init()?;
+    phases.end("ResponseCache");
+
+    // We set up the supergraph during the initialization of the Lambda, and reuse
+    // it across invocations.
+    let bootstrap = Arc::new(bootstrap);
+    let supergraph = build_router(&bootstrap, configuration, &schema, None).await?;
+    // Wrap our supergraph in Arc(Mutex(..)), so we can safely pass it across async boundaries.
+    let supergraph = Arc::new(Mutex::new(supergraph));
+    phases.end("BuildRouter");
+
+    // Plan a trivial operation, so that the first invocation doesn't pay for setting up the query
+    // planner.
+    let warm_up = serde_json::from_value(serde_json::json!({ "query": "{ __typename }" }))?;
+    if let Err(e) =
+        call_supergraph(Arc::clone(&supergraph), &warm_up, &HeaderMap::new(), Vec::new()).await
+    {
+        warn!("Failed to warm up the query planner: {}", e);
+    }
+    phases.end("FirstQueryPlan");
+
+    // Plan the operations of the plan cache, if there is one for this supergraph, and those we
+    // were asked to warm up the query planner with.
+    let plan_cache = plans::PlanCache::load().await;
+    let mut operations = plan_cache.operations(&schema);
+    operations.extend(plans::load_warmup(&config_path)?);
+    plans::warm(&supergraph, &operations).await;
+    phases.end("PlanWarmup");
+
+    // The supergraphs of the tenants, if this function serves several, are set up the same way.
+    let tenants = match tenants::load(&config_path, &schema_path)? {
+        Some(config) => {
+            let mut tenants = Vec::new();
+            for (name, tenant) in &config.tenants {
+                tenants.push(build_tenant(&bootstrap, name, tenant, &plan_cache).await?);
+            }
+            Some(tenants::Tenants::new(&config, tenants)?)
+        }
+        None => None,
+    };
+    phases.end("Tenants");
+
+    // Register our internal extension, so we get a chance to flush telemetry after each response,
+    // and to finish in-flight work before the execution environment is shut down.
+    let lifecycle = Arc::new(extension::Lifecycle::new());
+    if let Err(e) = extension::register(Arc::clone(&lifecycle)).await {
+        warn!("Failed to register the Lambda extension: {}", e);
+    }
+    let invocation_telemetry = telemetry.clone();
+    lifecycle.after_invocation(move || {
+        let telemetry = invocation_telemetry.clone();
+        Box::pin(async move { telemetry.flush().await })
+    });
+    lifecycle.after_invocation(|| Box::pin(usage::flush_if_due()));
+    let shutdown_telemetry = telemetry.clone();
+    lifecycle.on_shutdown(move || {
+        let telemetry = shutdown_telemetry.clone();
+        Box::pin(async move { telemetry.shutdown().await })
+    });
+    lifecycle.on_shutdown(|| Box::pin(usage::flush()));
+
+    phases.end("Extension");
+    phases.finish();
+
+    // Set up the Lambda event handler.
+    let state = Arc::new(State { supergraph, tenants, field_arguments, cors, lifecycle });
+    run(service_fn(|event: LambdaEvent<serde_json::Value>| async {
+        let s = Arc::clone(&state);
+        handle_event(s, event).await
+    }))
+    .await
+}
+
+type RouterLayer = Box<dyn Fn(router::BoxCloneService) -> router::BoxCloneService + Send + Sync>;
+type SupergraphLayer = Box<dyn Fn(supergraph::BoxService) -> supergraph::BoxService + Send + Sync>;
+type SubgraphLayer = Box<dyn Fn(subgraph::BoxService) -> subgraph::BoxService + Send + Sync>;
+
+/// Builds and runs the Lambda bootstrap. The `bootstrap` binary of this crate runs it as is,
+/// while a team can depend on this crate to ship its own bootstrap, with Rust plugins and tower
+/// layers of its own:
+///
+/// ```no_run
+/// use apollo_router_lambda::Bootstrap;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), lambda_runtime::Error> {
+///     Bootstrap::new()
+///         // A plugin that the bootstrap registers with `register_plugin!("acme", "tenancy", ..)`.
+///         .plugin("acme.tenancy", serde_json::json!({ "header": "x-tenant-id" }))
+///         .subgraph_layer(tower::timeout::TimeoutLayer::new(std::time::Duration::from_secs(5)))
+///         .run()
+///         .await
+/// }
+/// ```
+#[derive(Default)]
+pub struct Bootstrap {
+    plugins: Vec<(String, serde_json::Value)>,
+    router_layers: Vec<RouterLayer>,
+    supergraph_layers: Vec<SupergraphLayer>,
+    subgraph_layers: Vec<SubgraphLayer>,
+}
+
+impl Bootstrap {
+    pub fn new() -> Self {
+        Bootstrap::default()
+    }
+
+    /// Enable a plugin that was registered with `register_plugin!`, as if it was listed in the
+    /// `plugins` section of `router.yaml`. An entry for the same plugin in `router.yaml` takes
+    /// precedence, so that it can still be configured per deployment.
+    pub fn plugin(mut self, name: impl Into<String>, config: serde_json::Value) -> Self {
+        self.plugins.push((name.into(), config));
+        self
+    }
+
+    /// Wrap the router service, which every GraphQL request goes through, whatever the event
+    /// it came with.
+    pub fn router_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<router::BoxCloneService> + Send + Sync + 'static,
+        L::Service: Service<router::Request, Response = router::Response, Error = BoxError>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as Service<router::Request>>::Future: Send + 'static,
+    {
+        self.router_layers
+            .push(Box::new(move |service| router::BoxCloneService::new(layer.layer(service))));
+        self
+    }
+
+    /// Wrap the supergraph service, like the `supergraph_service` hook of a plugin.
+    pub fn supergraph_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<supergraph::BoxService> + Send + Sync + 'static,
+        L::Service: Service<supergraph::Request, Response = supergraph::Response, Error = BoxError>
+            + Send
+            + 'static,
+        <L::Service as Service<supergraph::Request>>::Future: Send + 'static,
+    {
+        self.supergraph_layers.push(Box::new(move |service| layer.layer(service).boxed()));
+        self
+    }
+
+    /// Wrap the service of every subgraph, like the `subgraph_service` hook of a plugin.
+    pub fn subgraph_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<subgraph::BoxService> + Send + Sync + 'static,
+        L::Service: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>
+            + Send
+            + 'static,
+        <L::Service as Service<subgraph::Request>>::Future: Send + 'static,
+    {
+        self.subgraph_layers.push(Box::new(move |service| layer.layer(service).boxed()));
+        self
+    }
+
+    /// Run the Lambda, or the `warm-plans` command if the bootstrap was started with it.
+    pub async fn run(self) -> Result<(), Error> {
+        let args: Vec<String> = env::args().collect();
+        if args.get(1).is_some_and(|command| command == "warm-plans") {
+            return plans::generate(&args[2..]);
+        }
+
+        // Logs follow the function's log level, while spans at INFO and above are exported if
+        // tracing is enabled.
+        let telemetry = Telemetry::from_env()?;
+        tracing_subscriber::registry().with(logging::layer()).with(telemetry.layer()).init();
+        handler(self, telemetry).await
+    }
+
+    fn configure_plugins(&self, config: &mut serde_yaml::Value) -> Result<(), Error> {
+        if self.plugins.is_empty() {
+            return Ok(());
+        }
+        let config = config.as_mapping_mut().ok_or("the Router configuration isn't a mapping")?;
+        let plugins = config.entry("plugins".into()).or_insert(serde_yaml::Value::Null);
+        if plugins.is_null() {
+            *plugins = serde_yaml::Mapping::new().into();
+        }
+        let plugins = plugins.as_mapping_mut().ok_or("the plugins section isn't a mapping")?;
+        for (name, plugin_config) in &self.plugins {
+            if !plugins.contains_key(name.as_str()) {
+                plugins.insert(name.clone().into(), serde_yaml::to_value(plugin_config)?);
+            }
+        }
+        Ok(())
+    }
+}
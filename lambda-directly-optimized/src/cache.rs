@@ -0,0 +1,532 @@
+use crate::event::HttpResponse;
+use apollo_router::graphql;
+use apollo_router::services::subgraph;
+use aws_sdk_dynamodb::types::AttributeValue;
+use graphql_parser::query::{Definition, OperationDefinition};
+use lambda_runtime::Error;
+use lru::LruCache;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::warn;
+
+/// Tells clients whether their response came from the cache.
+const STATUS_HEADER: &str = "x-cache";
+
+static CACHE: OnceLock<ResponseCache> = OnceLock::new();
+
+/// How long the subgraph responses of the operation that is currently being executed can be
+/// shared for, which is `None` until a subgraph answered. The Lambda only ever executes one
+/// operation at a time, so there's no need to tell operations apart.
+static FRESHNESS: Mutex<Option<Freshness>> = Mutex::new(None);
+
+/// Whether the last operation was answered from the cache, for the header of its HTTP response.
+static STATUS: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// The response caching configuration, read from `response_cache.yaml` next to the Router
+/// configuration, or from `APOLLO_ROUTER_RESPONSE_CACHE_PATH`. Response caching is disabled if
+/// there is no such file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The request headers that responses differ by, e.g. `accept-language`.
+    #[serde(default)]
+    vary: Vec<String>,
+    /// Operations to cache by name, with how long to cache them for, e.g. `Products: 5m`.
+    /// Other operations are cached for as long as all of their subgraph responses allow.
+    #[serde(default)]
+    operations: HashMap<String, String>,
+    /// The longest any response is cached for.
+    #[serde(default)]
+    max_ttl: Option<String>,
+    #[serde(default)]
+    in_memory: Option<InMemoryConfig>,
+    #[serde(default)]
+    dynamodb: Option<DynamoDbConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InMemoryConfig {
+    /// How many responses each container keeps, evicting the least recently used ones.
+    capacity: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DynamoDbConfig {
+    /// The DynamoDB table holding the responses, with a string partition key named `key`.
+    table: String,
+    /// Where to reach DynamoDB, e.g. `http://127.0.0.1:8000` for DynamoDB Local.
+    #[serde(default)]
+    endpoint_url: Option<String>,
+}
+
+/// How long the subgraph responses of an operation can be shared for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Freshness {
+    /// For the smallest `max-age` (or `s-maxage`) of the responses.
+    MaxAge(Duration),
+    /// Not at all, since a response was marked `private`, `no-store` or `no-cache`.
+    Private,
+}
+
+#[derive(Clone)]
+struct Entry {
+    response: Value,
+    /// When the entry expires, in seconds since the epoch.
+    expires_at: u64,
+}
+
+/// Caches whole responses of queries. Warm containers answer from their own memory first, and
+/// then from DynamoDB, which is shared by all containers.
+struct ResponseCache {
+    vary: Vec<HeaderName>,
+    /// The request headers that tell who the client is.
+    identities: Vec<HeaderName>,
+    operations: HashMap<String, Duration>,
+    max_ttl: Option<Duration>,
+    memory: Option<Mutex<LruCache<String, Entry>>>,
+    dynamodb: Option<DynamoDb>,
+}
+
+struct DynamoDb {
+    table: String,
+    client: aws_sdk_dynamodb::Client,
+}
+
+/// Set up response caching, if it's configured.
+pub async fn init(config_path: &str) -> Result<(), Error> {
+    let path = match env::var("APOLLO_ROUTER_RESPONSE_CACHE_PATH") {
+        Ok(path) => path.into(),
+        Err(_) => Path::new(config_path).with_file_name("response_cache.yaml"),
+    };
+    if !path.exists() {
+        return Ok(());
+    }
+    let config: Config = serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| format!("invalid response cache configuration in {}: {e}", path.display()))?;
+    if config.in_memory.is_none() && config.dynamodb.is_none() {
+        return Err("the response cache needs in_memory, dynamodb or both".into());
+    }
+
+    let duration = |duration: &str| {
+        humantime::parse_duration(duration)
+            .map_err(|e| format!("invalid response cache duration {duration}: {e}"))
+    };
+    let operations = config
+        .operations
+        .iter()
+        .map(|(name, ttl)| Ok((name.clone(), duration(ttl)?)))
+        .collect::<Result<_, Error>>()?;
+    let max_ttl = config.max_ttl.as_deref().map(duration).transpose()?;
+    let vary = config
+        .vary
+        .iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()))
+        .collect::<Result<_, _>>()?;
+    let memory = match config.in_memory {
+        Some(in_memory) => {
+            let capacity = NonZeroUsize::new(in_memory.capacity)
+                .ok_or("the in-memory response cache capacity must be at least 1")?;
+            Some(Mutex::new(LruCache::new(capacity)))
+        }
+        None => None,
+    };
+    let dynamodb = match config.dynamodb {
+        Some(DynamoDbConfig { table, endpoint_url }) => {
+            let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+            if let Some(endpoint_url) = &endpoint_url {
+                loader = loader.endpoint_url(endpoint_url);
+            }
+            let client = aws_sdk_dynamodb::Client::new(&loader.load().await);
+            Some(DynamoDb { table, client })
+        }
+        None => None,
+    };
+    let identities = crate::entities::Identities::configured().headers;
+    let _ = CACHE.set(ResponseCache { vary, identities, operations, max_ttl, memory, dynamodb });
+    Ok(())
+}
+
+/// Keep track of how long the subgraphs allow their responses to be cached for, going by their
+/// `Cache-Control` headers, e.g. the ones Apollo Server sets from `@cacheControl` hints.
+pub fn instrument_subgraph(service: subgraph::BoxService) -> subgraph::BoxService {
+    if CACHE.get().is_none() {
+        return service;
+    }
+    ServiceBuilder::new()
+        .map_response(|response: subgraph::Response| {
+            let freshness = match crate::entities::max_age(response.response.headers()) {
+                Some(max_age) => Freshness::MaxAge(max_age),
+                None => Freshness::Private,
+            };
+            let mut operation = FRESHNESS.lock().unwrap();
+            *operation = Some(match (*operation, freshness) {
+                (Some(Freshness::MaxAge(a)), Freshness::MaxAge(b)) => Freshness::MaxAge(a.min(b)),
+                (Some(Freshness::Private), _) => Freshness::Private,
+                (_, freshness) => freshness,
+            });
+            response
+        })
+        .service(service)
+        .boxed()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Answer a query from the cache, or execute it and cache its response if it can be. Only
+/// `public` requests share responses, that is requests that no token or authorizer identified.
+/// Tenants never share responses with each other.
+pub async fn through<F>(
+    request: &graphql::Request,
+    headers: &HeaderMap,
+    tenant: Option<&str>,
+    public: bool,
+    execute: F,
+) -> Result<(StatusCode, Value), Error>
+where
+    F: Future<Output = Result<(StatusCode, Value), Error>>,
+{
+    let cache = CACHE.get().filter(|_| public);
+    let Some((cache, key)) =
+        cache.and_then(|cache| Some((cache, cache.key(request, headers, tenant)?)))
+    else {
+        *STATUS.lock().unwrap() = None;
+        return execute.await;
+    };
+    if let Some(response) = cache.get(&key).await {
+        *STATUS.lock().unwrap() = Some("HIT");
+        return Ok((StatusCode::OK, response));
+    }
+    *STATUS.lock().unwrap() = Some("MISS");
+
+    FRESHNESS.lock().unwrap().take();
+    let result = execute.await;
+    let freshness = FRESHNESS.lock().unwrap().take();
+    if let Ok((status, response)) = &result {
+        if let Some(ttl) = cache.ttl(request, *status, response, freshness) {
+            cache.put(&key, response, ttl).await;
+        }
+    }
+    result
+}
+
+/// Tell the client whether the response came from the cache. Requests that were turned away
+/// before they reached the cache don't get the header.
+pub fn decorate(response: &mut HttpResponse) {
+    let status = STATUS.lock().unwrap().take();
+    if let Some(status) = status.filter(|_| response.status == StatusCode::OK) {
+        response.headers.insert(STATUS_HEADER, HeaderValue::from_static(status));
+    }
+}
+
+impl ResponseCache {
+    /// The key of the response to a query, or `None` if it's not a query, or if the response
+    /// could depend on who is asking.
+    fn key(
+        &self,
+        request: &graphql::Request,
+        headers: &HeaderMap,
+        tenant: Option<&str>,
+    ) -> Option<String> {
+        // Responses to requests that tell who the client is aren't shared, unless they vary by the
+        // header that does.
+        let identified = self
+            .identities
+            .iter()
+            .any(|name| headers.contains_key(name) && !self.vary.contains(name));
+        if identified {
+            return None;
+        }
+        if !is_query(request) {
+            return None;
+        }
+        let vary: Vec<Option<&str>> = self
+            .vary
+            .iter()
+            .map(|name| headers.get(name).and_then(|value| value.to_str().ok()))
+            .collect();
+        let key = json!([
+            tenant,
+            crate::logging::operation_hash(request.query.as_deref()),
+            request.operation_name,
+            request.variables,
+            vary,
+        ]);
+        Some(hex::encode(Sha256::digest(key.to_string().as_bytes())))
+    }
+
+    /// How long to cache a response for, if at all. Operations that are listed in the
+    /// configuration are always cached, and others for as long as all their subgraphs allow.
+    fn ttl(
+        &self,
+        request: &graphql::Request,
+        status: StatusCode,
+        response: &Value,
+        freshness: Option<Freshness>,
+    ) -> Option<Duration> {
+        let errors =
+            response.get("errors").and_then(|e| e.as_array()).is_some_and(|e| !e.is_empty());
+        if status != StatusCode::OK || errors {
+            return None;
+        }
+        let listed = request.operation_name.as_ref().and_then(|name| self.operations.get(name));
+        // A subgraph response that is meant for the client alone is never shared, even if the
+        // operation is listed.
+        let max_age = match freshness {
+            Some(Freshness::MaxAge(max_age)) => Some(max_age),
+            Some(Freshness::Private) => return None,
+            None => None,
+        };
+        let ttl = listed.copied().or(max_age)?;
+        let ttl = self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl));
+        (ttl.as_secs() > 0).then_some(ttl)
+    }
+
+    async fn get(&self, key: &str) -> Option<Value> {
+        let now = now();
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            if let Some(entry) = memory.get(key).filter(|entry| entry.expires_at > now) {
+                return Some(entry.response.clone());
+            }
+        }
+        let dynamodb = self.dynamodb.as_ref()?;
+        let entry = match dynamodb.get(key).await {
+            Ok(entry) => entry.filter(|entry| entry.expires_at > now)?,
+            Err(e) => {
+                warn!("Failed to get a cached response from DynamoDB: {}", e);
+                return None;
+            }
+        };
+        if let Some(memory) = &self.memory {
+            memory.lock().unwrap().put(key.to_string(), entry.clone());
+        }
+        Some(entry.response)
+    }
+
+    async fn put(&self, key: &str, response: &Value, ttl: Duration) {
+        let entry = Entry { response: response.clone(), expires_at: now() + ttl.as_secs() };
+        if let Some(dynamodb) = &self.dynamodb {
+            if let Err(e) = dynamodb.put(key, &entry).await {
+                warn!("Failed to cache a response in DynamoDB: {}", e);
+            }
+        }
+        if let Some(memory) = &self.memory {
+            memory.lock().unwrap().put(key.to_string(), entry);
+        }
+    }
+}
+
+impl DynamoDb {
+    async fn get(&self, key: &str) -> Result<Option<Entry>, Error> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .send()
+            .await?
+            .item;
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        let (Some(AttributeValue::S(response)), Some(AttributeValue::N(expires_at))) =
+            (item.get("response"), item.get("expires_at"))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(Entry {
+            response: serde_json::from_str(response)?,
+            expires_at: expires_at.parse()?,
+        }))
+    }
+
+    /// Store the entry, letting DynamoDB delete it once it expires if TTL is enabled on
+    /// `expires_at`. Items are limited to 400 KB, so the largest responses aren't shared.
+    async fn put(&self, key: &str, entry: &Entry) -> Result<(), Error> {
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("response", AttributeValue::S(entry.response.to_string()))
+            .item("expires_at", AttributeValue::N(entry.expires_at.to_string()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Whether the request selects a query, rather than a mutation or a subscription.
+fn is_query(request: &graphql::Request) -> bool {
+    let Some(query) = &request.query else {
+        return false;
+    };
+    let Ok(document) = graphql_parser::parse_query::<String>(query) else {
+        return false;
+    };
+    document.definitions.iter().any(|definition| match definition {
+        Definition::Operation(operation) => {
+            let selected = request.operation_name.is_none()
+                || crate::ast::operation_name(operation) == request.operation_name.as_deref();
+            selected
+                && matches!(
+                    operation,
+                    OperationDefinition::SelectionSet(_) | OperationDefinition::Query(_)
+                )
+        }
+        Definition::Fragment(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+
+    fn cache(vary: &[&'static str]) -> ResponseCache {
+        ResponseCache {
+            vary: vary.iter().map(|name| HeaderName::from_static(name)).collect(),
+            identities: ["authorization", "cookie", "x-api-key", "x-user-id"]
+                .into_iter()
+                .map(HeaderName::from_static)
+                .collect(),
+            operations: HashMap::from([("Products".to_string(), Duration::from_secs(300))]),
+            max_ttl: Some(Duration::from_secs(3600)),
+            memory: Some(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+            dynamodb: None,
+        }
+    }
+
+    fn request(query: &str, operation_name: Option<&str>) -> graphql::Request {
+        let request = json!({ "query": query, "operationName": operation_name });
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn only_shares_responses_that_dont_depend_on_the_client() {
+        let cache = cache(&["accept-language"]);
+        let query = request("{ products { id } }", None);
+        let key = cache.key(&query, &HeaderMap::new(), None).unwrap();
+        assert_eq!(cache.key(&query, &headers(&[("x-request-id", "1")]), None), Some(key.clone()));
+        assert_ne!(cache.key(&query, &HeaderMap::new(), Some("shop")), Some(key.clone()));
+        let french = cache.key(&query, &headers(&[("accept-language", "fr")]), None);
+        assert_ne!(french, Some(key));
+
+        for credentials in [("authorization", "Bearer abc"), ("cookie", "session=abc")] {
+            assert_eq!(cache.key(&query, &headers(&[credentials]), None), None);
+        }
+        assert_eq!(cache.key(&query, &headers(&[("x-api-key", "abc")]), None), None);
+        // A header that an authorizer claim is mapped onto.
+        assert_eq!(cache.key(&query, &headers(&[("x-user-id", "ada")]), None), None);
+
+        let mutation = request("query Q { a } mutation M { b }", Some("M"));
+        assert_eq!(cache.key(&mutation, &HeaderMap::new(), None), None);
+        let query = request("query Q { a } mutation M { b }", Some("Q"));
+        assert!(cache.key(&query, &HeaderMap::new(), None).is_some());
+    }
+
+    #[test]
+    fn varies_by_the_listed_credentials() {
+        let cache = cache(&["cookie"]);
+        let query = request("{ products { id } }", None);
+        let ada = cache.key(&query, &headers(&[("cookie", "user=ada")]), None);
+        let bob = cache.key(&query, &headers(&[("cookie", "user=bob")]), None);
+        assert!(ada.is_some());
+        assert_ne!(ada, bob);
+        assert_eq!(cache.key(&query, &headers(&[("authorization", "Bearer abc")]), None), None);
+
+        let cache = self::cache(&["x-user-id"]);
+        let ada = cache.key(&query, &headers(&[("x-user-id", "ada")]), None);
+        let bob = cache.key(&query, &headers(&[("x-user-id", "bob")]), None);
+        assert!(ada.is_some());
+        assert_ne!(ada, bob);
+    }
+
+    #[test]
+    fn caches_for_as_long_as_allowed() {
+        let cache = cache(&[]);
+        let products = request("query Products { products { id } }", Some("Products"));
+        let me = request("query Me { me { id } }", Some("Me"));
+        let ok = json!({ "data": { "me": { "id": "1" } } });
+        let minutes = |minutes: u64| Some(Duration::from_secs(minutes * 60));
+        let max_age = |minutes: u64| Some(Freshness::MaxAge(Duration::from_secs(minutes * 60)));
+
+        assert_eq!(cache.ttl(&products, StatusCode::OK, &ok, None), minutes(5));
+        assert_eq!(cache.ttl(&products, StatusCode::OK, &ok, max_age(1)), minutes(5));
+        assert_eq!(cache.ttl(&me, StatusCode::OK, &ok, max_age(1)), minutes(1));
+        assert_eq!(cache.ttl(&me, StatusCode::OK, &ok, max_age(120)), minutes(60));
+        assert_eq!(cache.ttl(&me, StatusCode::OK, &ok, None), None);
+        assert_eq!(cache.ttl(&me, StatusCode::OK, &ok, max_age(0)), None);
+        // Neither listed operations nor others are cached if a subgraph said `private`.
+        let private = Some(Freshness::Private);
+        assert_eq!(cache.ttl(&products, StatusCode::OK, &ok, private), None);
+        assert_eq!(cache.ttl(&me, StatusCode::OK, &ok, private), None);
+
+        let errors = json!({ "data": null, "errors": [{ "message": "boom" }] });
+        assert_eq!(cache.ttl(&products, StatusCode::OK, &errors, None), None);
+        assert_eq!(cache.ttl(&products, StatusCode::BAD_REQUEST, &ok, None), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_responses_in_memory_until_they_expire() {
+        let cache = cache(&[]);
+        let response = json!({ "data": { "products": [] } });
+        cache.put("fresh", &response, Duration::from_secs(60)).await;
+        assert_eq!(cache.get("fresh").await, Some(response.clone()));
+
+        let expired = Entry { response, expires_at: now() - 1 };
+        cache.memory.as_ref().unwrap().lock().unwrap().put("expired".to_string(), expired);
+        assert_eq!(cache.get("expired").await, None);
+        assert_eq!(cache.get("missing").await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DynamoDB Local, started with `just dynamodb-local`"]
+    async fn shares_responses_through_dynamodb() {
+        let config: Config =
+            serde_yaml::from_str(include_str!("../../response_cache.example.yaml")).unwrap();
+        let DynamoDbConfig { table, endpoint_url } = config.dynamodb.unwrap();
+        let client = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("local", "local", None, None, "tests"))
+            .endpoint_url(endpoint_url.unwrap())
+            .build();
+        let dynamodb = DynamoDb { table, client: aws_sdk_dynamodb::Client::from_conf(client) };
+        let cache = ResponseCache { memory: None, dynamodb: Some(dynamodb), ..cache(&[]) };
+
+        let key =
+            format!("test:{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+        let response = json!({ "data": { "products": [{ "id": "1" }] } });
+        assert_eq!(cache.get(&key).await, None);
+        cache.put(&key, &response, Duration::from_secs(60)).await;
+        assert_eq!(cache.get(&key).await, Some(response.clone()));
+
+        // Entries that DynamoDB hasn't deleted yet are still ignored once they expire.
+        let expired = Entry { response, expires_at: now() - 1 };
+        cache.dynamodb.as_ref().unwrap().put(&key, &expired).await.unwrap();
+        assert_eq!(cache.get(&key).await, None);
+    }
+}
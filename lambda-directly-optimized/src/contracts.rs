@@ -0,0 +1,346 @@
+use crate::ast::named_type;
+use graphql_parser::query::{Type, Value};
+use graphql_parser::schema::{Definition, Directive, Field, InputValue, TypeDefinition};
+use graphql_parser::Pos;
+use lambda_runtime::Error;
+use std::collections::HashSet;
+use tracing::info;
+
+/// The spec that the Router hides elements with, like GraphOS contracts do.
+const INACCESSIBLE_SPEC: &str = "https://specs.apollo.dev/inaccessible/";
+const INACCESSIBLE_VERSION: &str = "v0.2";
+
+const INACCESSIBLE_DEFINITION: &str = "directive @inaccessible on FIELD_DEFINITION | OBJECT \
+    | INTERFACE | UNION | ARGUMENT_DEFINITION | SCALAR | ENUM | ENUM_VALUE | INPUT_OBJECT \
+    | INPUT_FIELD_DEFINITION";
+
+/// Derive a contract from the supergraph, by marking the types, fields, arguments and enum values
+/// tagged with any of the `exclude` tags `@inaccessible`. The Router leaves them out of the API
+/// schema and rejects operations that use them, while the query planner can still use them, e.g.
+/// for keys. Elements that can't be used without an excluded one are hidden too: fields of an
+/// excluded type, required arguments of one, input types whose required fields are excluded,
+/// and types that have nothing left to show.
+pub fn derive(schema: &str, exclude: &[String]) -> Result<String, Error> {
+    let mut document = graphql_parser::parse_schema::<String>(schema)
+        .map_err(|e| format!("failed to parse the supergraph schema: {e}"))?;
+    let mut hidden_types = HashSet::new();
+    let mut hidden = 0;
+    // Hiding an element can leave others unusable, so we go on until nothing changes.
+    loop {
+        let mut changed = false;
+        for definition in &mut document.definitions {
+            let Definition::TypeDefinition(definition) = definition else {
+                continue;
+            };
+            let (name, directives, empty) = match definition {
+                TypeDefinition::Object(object) => {
+                    changed |= hide_fields(&mut object.fields, &hidden_types, exclude, &mut hidden);
+                    let empty = object.fields.iter().all(|field| is_hidden(&field.directives));
+                    (&object.name, &mut object.directives, empty)
+                }
+                TypeDefinition::Interface(interface) => {
+                    changed |=
+                        hide_fields(&mut interface.fields, &hidden_types, exclude, &mut hidden);
+                    let empty = interface.fields.iter().all(|field| is_hidden(&field.directives));
+                    (&interface.name, &mut interface.directives, empty)
+                }
+                TypeDefinition::InputObject(input) => {
+                    // Clients can't leave out a required field, so without it the type can't be
+                    // used at all.
+                    let mut incomplete = false;
+                    for field in &mut input.fields {
+                        let unusable = hidden_types.contains(&named_type(&field.value_type));
+                        if tagged(&field.directives, exclude) || unusable {
+                            changed |= hide(&mut field.directives, &mut hidden);
+                            incomplete |= is_required(field);
+                        }
+                    }
+                    let empty = input.fields.iter().all(|field| is_hidden(&field.directives));
+                    (&input.name, &mut input.directives, empty || incomplete)
+                }
+                TypeDefinition::Enum(enum_type) => {
+                    for value in &mut enum_type.values {
+                        if tagged(&value.directives, exclude) {
+                            changed |= hide(&mut value.directives, &mut hidden);
+                        }
+                    }
+                    let empty = enum_type.values.iter().all(|value| is_hidden(&value.directives));
+                    (&enum_type.name, &mut enum_type.directives, empty)
+                }
+                TypeDefinition::Union(union) => {
+                    let empty = union.types.iter().all(|member| hidden_types.contains(member));
+                    (&union.name, &mut union.directives, empty)
+                }
+                TypeDefinition::Scalar(scalar) => (&scalar.name, &mut scalar.directives, false),
+            };
+            if (empty || tagged(directives, exclude)) && hidden_types.insert(name.clone()) {
+                hide(directives, &mut hidden);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    if hidden > 0 {
+        link_inaccessible(&mut document.definitions)?;
+    }
+    info!(hidden, tags = ?exclude, "derived a contract from the supergraph");
+    Ok(document.to_string())
+}
+
+/// Hide the fields that are tagged, return an excluded type, or require an excluded argument,
+/// and the optional arguments that are tagged or of an excluded type.
+fn hide_fields(
+    fields: &mut [Field<'_, String>],
+    hidden_types: &HashSet<String>,
+    exclude: &[String],
+    hidden: &mut usize,
+) -> bool {
+    let mut changed = false;
+    for field in fields {
+        let mut unusable = hidden_types.contains(&named_type(&field.field_type));
+        for argument in &mut field.arguments {
+            if !tagged(&argument.directives, exclude)
+                && !hidden_types.contains(&named_type(&argument.value_type))
+            {
+                continue;
+            }
+            if is_required(argument) {
+                unusable = true;
+            } else {
+                changed |= hide(&mut argument.directives, hidden);
+            }
+        }
+        if tagged(&field.directives, exclude) || unusable {
+            changed |= hide(&mut field.directives, hidden);
+        }
+    }
+    changed
+}
+
+/// Whether an element has a `@tag` with one of the given names.
+fn tagged(directives: &[Directive<'_, String>], tags: &[String]) -> bool {
+    directives.iter().any(|directive| {
+        directive.name == "tag"
+            && directive.arguments.iter().any(|(name, value)| match value {
+                Value::String(tag) => name == "name" && tags.contains(tag),
+                _ => false,
+            })
+    })
+}
+
+fn is_hidden(directives: &[Directive<'_, String>]) -> bool {
+    directives.iter().any(|directive| directive.name == "inaccessible")
+}
+
+/// Mark an element `@inaccessible`, returning whether it wasn't already.
+fn hide(directives: &mut Vec<Directive<'_, String>>, hidden: &mut usize) -> bool {
+    if is_hidden(directives) {
+        return false;
+    }
+    directives.push(Directive {
+        position: Pos::default(),
+        name: "inaccessible".to_string(),
+        arguments: Vec::new(),
+    });
+    *hidden += 1;
+    true
+}
+
+fn is_required(argument: &InputValue<'_, String>) -> bool {
+    matches!(argument.value_type, Type::NonNullType(_)) && argument.default_value.is_none()
+}
+
+/// Link the inaccessible spec and define its directive, unless the supergraph already does.
+fn link_inaccessible(definitions: &mut Vec<Definition<'_, String>>) -> Result<(), Error> {
+    let Some(schema) = definitions.iter_mut().find_map(|definition| match definition {
+        Definition::SchemaDefinition(schema) => Some(schema),
+        _ => None,
+    }) else {
+        return Err("the supergraph schema has no schema definition to link specs with".into());
+    };
+    let linked = schema.directives.iter().any(|directive| {
+        directive.name == "link"
+            && directive.arguments.iter().any(|(name, value)| match value {
+                Value::String(url) => name == "url" && url.starts_with(INACCESSIBLE_SPEC),
+                _ => false,
+            })
+    });
+    if linked {
+        return Ok(());
+    }
+    schema.directives.push(Directive {
+        position: Pos::default(),
+        name: "link".to_string(),
+        arguments: vec![
+            (
+                "url".to_string(),
+                Value::String(format!("{INACCESSIBLE_SPEC}{INACCESSIBLE_VERSION}")),
+            ),
+            ("for".to_string(), Value::Enum("SECURITY".to_string())),
+        ],
+    });
+    let inaccessible = graphql_parser::parse_schema::<String>(INACCESSIBLE_DEFINITION)?;
+    definitions.extend(inaccessible.definitions);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPERGRAPH: &str = r#"
+        schema @link(url: "https://specs.apollo.dev/link/v1.0") { query: Query }
+
+        directive @tag(name: String!) repeatable on FIELD_DEFINITION | OBJECT | INTERFACE
+            | UNION | ARGUMENT_DEFINITION | SCALAR | ENUM | ENUM_VALUE | INPUT_OBJECT
+            | INPUT_FIELD_DEFINITION
+
+        type Query {
+            products(scope: Scope): [Product!]!
+            secret: Secret
+            orders(filter: OrderFilter!): [String]
+            search(filter: OrderFilter): [String]
+            result: Result
+        }
+        type Product { id: ID! name: String cost: Float @tag(name: "internal") }
+        type Secret @tag(name: "internal") { code: String }
+        input OrderFilter @tag(name: "internal") { id: ID }
+        enum Scope { ALL STAFF @tag(name: "internal") }
+        union Result = Secret
+    "#;
+
+    /// The elements of the schema that are `@inaccessible`, e.g. `Query.orders(filter)`.
+    fn hidden(schema: &str) -> Vec<String> {
+        let document = graphql_parser::parse_schema::<String>(schema).unwrap();
+        let mut hidden = Vec::new();
+        let mut add = |directives: &[Directive<'_, String>], name: String| {
+            if is_hidden(directives) {
+                hidden.push(name);
+            }
+        };
+        for definition in &document.definitions {
+            let Definition::TypeDefinition(definition) = definition else {
+                continue;
+            };
+            match definition {
+                TypeDefinition::Object(object) => {
+                    add(&object.directives, object.name.clone());
+                    for field in &object.fields {
+                        add(&field.directives, format!("{}.{}", object.name, field.name));
+                        for argument in &field.arguments {
+                            let name = format!("{}.{}({})", object.name, field.name, argument.name);
+                            add(&argument.directives, name);
+                        }
+                    }
+                }
+                TypeDefinition::InputObject(input) => {
+                    add(&input.directives, input.name.clone());
+                    for field in &input.fields {
+                        add(&field.directives, format!("{}.{}", input.name, field.name));
+                    }
+                }
+                TypeDefinition::Enum(enum_type) => {
+                    add(&enum_type.directives, enum_type.name.clone());
+                    for value in &enum_type.values {
+                        add(&value.directives, format!("{}.{}", enum_type.name, value.name));
+                    }
+                }
+                TypeDefinition::Union(union) => add(&union.directives, union.name.clone()),
+                _ => {}
+            }
+        }
+        hidden
+    }
+
+    fn inaccessible_links(schema: &str) -> usize {
+        schema.matches(INACCESSIBLE_SPEC).count()
+    }
+
+    #[test]
+    fn hides_tagged_elements_and_what_depends_on_them() {
+        let contract = derive(SUPERGRAPH, &["internal".to_string()]).unwrap();
+        assert_eq!(
+            hidden(&contract),
+            [
+                "Query.secret",
+                "Query.orders",
+                "Query.search(filter)",
+                "Query.result",
+                "Product.cost",
+                "Secret",
+                "OrderFilter",
+                "Scope.STAFF",
+                "Result",
+            ]
+        );
+    }
+
+    #[test]
+    fn hides_input_types_that_lose_a_required_field() {
+        let supergraph = r#"
+            schema @link(url: "https://specs.apollo.dev/link/v1.0") { query: Query }
+
+            type Query {
+                checkout(input: CheckoutInput!): String
+                quote(input: QuoteInput!): String
+                pay(payment: PaymentInput): String
+                page(page: Page): String
+            }
+            input PaymentInput { card: String token: String! @tag(name: "internal") }
+            input CheckoutInput { cart: ID! payment: PaymentInput! }
+            input QuoteInput { cart: ID! payment: PaymentInput }
+            input Page { first: Int note: String! = "" @tag(name: "internal") }
+        "#;
+        let contract = derive(supergraph, &["internal".to_string()]).unwrap();
+        // An optional field, or a required one with a default, can still be left out.
+        assert_eq!(
+            hidden(&contract),
+            [
+                "Query.checkout",
+                "Query.pay(payment)",
+                "PaymentInput",
+                "PaymentInput.token",
+                "CheckoutInput",
+                "CheckoutInput.payment",
+                "QuoteInput.payment",
+                "Page.note",
+            ]
+        );
+    }
+
+    #[test]
+    fn links_the_inaccessible_spec_once() {
+        let contract = derive(SUPERGRAPH, &["internal".to_string()]).unwrap();
+        assert_eq!(inaccessible_links(&contract), 1);
+        assert!(contract.contains(&format!(
+            "@link(url: \"{INACCESSIBLE_SPEC}{INACCESSIBLE_VERSION}\", for: SECURITY)"
+        )));
+        assert_eq!(contract.matches("directive @inaccessible on").count(), 1);
+
+        // A supergraph that already links the spec, e.g. because a subgraph uses it, keeps its
+        // own link and definition.
+        let linked = SUPERGRAPH.replace(
+            "{ query: Query }",
+            "@link(url: \"https://specs.apollo.dev/inaccessible/v0.2\", for: SECURITY) \
+             { query: Query }",
+        ) + INACCESSIBLE_DEFINITION;
+        let contract = derive(&linked, &["internal".to_string()]).unwrap();
+        assert_eq!(inaccessible_links(&contract), 1);
+        assert_eq!(contract.matches("directive @inaccessible on").count(), 1);
+        assert_eq!(hidden(&contract).len(), 9);
+    }
+
+    #[test]
+    fn leaves_the_supergraph_alone_without_matching_tags() {
+        let contract = derive(SUPERGRAPH, &["partner".to_string()]).unwrap();
+        assert!(hidden(&contract).is_empty());
+        assert_eq!(inaccessible_links(&contract), 0);
+
+        let schema = r#"schema @link(url: "https://specs.apollo.dev/link/v1.0") { query: Query }"#;
+        let without_schema = SUPERGRAPH.replace(schema, "");
+        assert!(derive(&without_schema, &["internal".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use aws_sdk_lambda::primitives::Blob;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use lambda_runtime::Error;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use std::convert::Infallible;
+use std::env;
+use std::net::TcpListener;
+use std::sync::Arc;
+use tracing::warn;
+
+/// The scheme of a coprocessor `url` that points at a Lambda function rather than an HTTP
+/// endpoint, e.g. `lambda://my-coprocessor` or `lambda://arn:aws:lambda:...:function:my-coprocessor`.
+const SCHEME: &str = "lambda://";
+
+/// A coprocessor that is a Lambda function, called with the Invoke API.
+struct Coprocessor {
+    function: String,
+    client: aws_sdk_lambda::Client,
+}
+
+/// Let the Router's coprocessor plugin call a Lambda function. The plugin only speaks HTTP, so
+/// when the `url` of the `coprocessor` section is a `lambda://` URL, we serve the plugin on a
+/// loopback port and pass each of its requests on to the function as is. The function receives
+/// the same JSON payloads as an HTTP coprocessor at every stage, and answers with the same JSON
+/// that an HTTP coprocessor would respond with. Each configuration gets a bridge of its own.
+pub async fn init(config: &mut serde_yaml::Value) -> Result<(), Error> {
+    let Some(url) =
+        config.get_mut("coprocessor").and_then(|coprocessor| coprocessor.get_mut("url"))
+    else {
+        return Ok(());
+    };
+    let Some(function) = url.as_str().and_then(|url| url.strip_prefix(SCHEME)) else {
+        return Ok(());
+    };
+
+    // Reach a local emulator, e.g. `cargo lambda watch` or `sam local start-lambda`, instead.
+    let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Ok(endpoint_url) = env::var("APOLLO_ROUTER_COPROCESSOR_ENDPOINT_URL") {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+    let client = aws_sdk_lambda::Client::new(&loader.load().await);
+    let coprocessor = Coprocessor { function: function.to_string(), client };
+    bridge(url, coprocessor)
+}
+
+/// Serve the plugin on a loopback port, and point the `url` at it.
+fn bridge(url: &mut serde_yaml::Value, coprocessor: Coprocessor) -> Result<(), Error> {
+    let coprocessor = Arc::new(coprocessor);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    *url = serde_yaml::Value::from(format!("http://{}", listener.local_addr()?));
+    let server = Server::from_tcp(listener)?.serve(make_service_fn(move |_| {
+        let coprocessor = Arc::clone(&coprocessor);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                forward(Arc::clone(&coprocessor), request)
+            }))
+        }
+    }));
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("The coprocessor bridge stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Answer the plugin with whatever the function returned. Failures get a 500, which fails the
+/// request just like an HTTP coprocessor that is down would.
+async fn forward(
+    coprocessor: Arc<Coprocessor>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match coprocessor.invoke(request).await {
+        Ok(payload) => Response::builder().header(CONTENT_TYPE, "application/json").body(payload),
+        Err(e) => {
+            warn!("Failed to invoke the coprocessor {}: {}", coprocessor.function, e);
+            Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty())
+        }
+    };
+    Ok(response.unwrap_or_default())
+}
+
+impl Coprocessor {
+    async fn invoke(&self, request: Request<Body>) -> Result<Body, Error> {
+        let payload = hyper::body::to_bytes(request.into_body()).await?;
+        let output = self
+            .client
+            .invoke()
+            .function_name(&self.function)
+            .payload(Blob::new(payload.to_vec()))
+            .send()
+            .await?;
+        let payload = output.payload.map(Blob::into_inner).unwrap_or_default();
+        if let Some(function_error) = output.function_error {
+            let payload = String::from_utf8_lossy(&payload);
+            return Err(format!("the function failed with {function_error}: {payload}").into());
+        }
+        Ok(Body::from(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_lambda::config::{BehaviorVersion, Credentials, Region};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    type Invocations = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// A stand-in for the Invoke API, whose function sends the payload back as is, unless the
+    /// payload asks it to throw.
+    fn invoke_api() -> (String, Invocations) {
+        let invocations = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&invocations);
+        let make_service = make_service_fn(move |_| {
+            let invocations = Arc::clone(&received);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let invocations = Arc::clone(&invocations);
+                    async move {
+                        let path = request.uri().path().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let payload: Value = serde_json::from_slice(&body).unwrap();
+                        invocations.lock().unwrap().push((path, payload.clone()));
+                        let response = if payload["throw"] == json!(true) {
+                            Response::builder()
+                                .header("x-amz-function-error", "Unhandled")
+                                .body(Body::from(r#"{"errorMessage":"boom"}"#))
+                        } else {
+                            Response::builder().body(Body::from(body))
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (format!("http://{address}"), invocations)
+    }
+
+    fn coprocessor(endpoint_url: &str) -> Coprocessor {
+        let config = aws_sdk_lambda::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("local", "local", None, None, "tests"))
+            .endpoint_url(endpoint_url)
+            .build();
+        let client = aws_sdk_lambda::Client::from_conf(config);
+        Coprocessor { function: "my-coprocessor".to_string(), client }
+    }
+
+    #[tokio::test]
+    async fn leaves_http_coprocessors_alone() {
+        let yaml = "coprocessor:\n  url: http://127.0.0.1:8081\n  router:\n    request:\n      \
+            headers: true";
+        let mut config: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        init(&mut config).await.unwrap();
+        assert_eq!(config, serde_yaml::from_str::<serde_yaml::Value>(yaml).unwrap());
+
+        let mut config = serde_yaml::from_str("supergraph:\n  listen: 127.0.0.1:4000").unwrap();
+        init(&mut config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_payloads_on_to_the_function() {
+        let (endpoint_url, invocations) = invoke_api();
+        let mut url = serde_yaml::Value::from("lambda://my-coprocessor");
+        bridge(&mut url, coprocessor(&endpoint_url)).unwrap();
+        let url = url.as_str().unwrap();
+        assert!(url.starts_with("http://127.0.0.1:"), "{url}");
+
+        let payload = json!({
+            "version": 1,
+            "stage": "RouterRequest",
+            "control": "continue",
+            "id": "1b19c05f",
+            "headers": { "x-tenant": ["shop"] },
+            "body": "{\"query\":\"{ me { name } }\"}",
+        });
+        let response = reqwest::Client::new().post(url).json(&payload).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.json::<Value>().await.unwrap(), payload);
+
+        let invocations = invocations.lock().unwrap();
+        let path = "/2015-03-31/functions/my-coprocessor/invocations".to_string();
+        assert_eq!(*invocations, [(path, payload)]);
+    }
+
+    #[tokio::test]
+    async fn fails_the_stage_when_the_function_does() {
+        let (endpoint_url, _) = invoke_api();
+        let mut url = serde_yaml::Value::from("lambda://my-coprocessor");
+        bridge(&mut url, coprocessor(&endpoint_url)).unwrap();
+        let client = reqwest::Client::new();
+        let response = client.post(url.as_str().unwrap()).json(&json!({ "throw": true }));
+        assert_eq!(response.send().await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // An Invoke API that can't be reached fails the same way.
+        let mut url = serde_yaml::Value::from("lambda://my-coprocessor");
+        bridge(&mut url, coprocessor("http://127.0.0.1:9")).unwrap();
+        let response = client.post(url.as_str().unwrap()).json(&json!({}));
+        assert_eq!(response.send().await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
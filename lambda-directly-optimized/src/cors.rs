@@ -0,0 +1,291 @@
+use crate::event::{HttpRequest, HttpResponse};
+use lambda_runtime::Error;
+use regex::Regex;
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ORIGIN, VARY,
+};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+/// The `cors` section of the Router configuration. The Router applies it in its HTTP server,
+/// which we bypass, so we apply it ourselves with the same options and defaults, see
+/// https://www.apollographql.com/docs/router/configuration/cors.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CorsConfig {
+    allow_any_origin: bool,
+    allow_credentials: bool,
+    /// The headers a preflight allows, or the ones the preflight asks for if empty.
+    allow_headers: Vec<String>,
+    expose_headers: Option<Vec<String>>,
+    origins: Vec<String>,
+    match_origins: Option<Vec<String>>,
+    methods: Vec<String>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allow_any_origin: false,
+            allow_credentials: false,
+            allow_headers: Vec::new(),
+            expose_headers: None,
+            origins: vec!["https://studio.apollographql.com".to_string()],
+            match_origins: None,
+            methods: vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()],
+            max_age: None,
+        }
+    }
+}
+
+/// The Router writes durations like `max_age: 10m`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let Some(duration) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    humantime::parse_duration(&duration).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Answers CORS preflights and adds the CORS headers to responses, so that browsers can call a
+/// Function URL directly.
+#[derive(Debug)]
+pub struct Cors {
+    config: CorsConfig,
+    match_origins: Vec<Regex>,
+}
+
+impl Cors {
+    /// Read the `cors` section from the untyped Router configuration, falling back to the
+    /// Router's defaults if there is none.
+    pub fn from_config(config: &serde_yaml::Value) -> Result<Self, Error> {
+        let config: CorsConfig = match config.get("cors") {
+            Some(cors) => serde_yaml::from_value(cors.clone())
+                .map_err(|e| format!("invalid cors configuration: {e}"))?,
+            None => CorsConfig::default(),
+        };
+        if config.allow_any_origin && config.allow_credentials {
+            return Err("cors: allow_credentials can't be combined with allow_any_origin".into());
+        }
+        let match_origins = config
+            .match_origins
+            .iter()
+            .flatten()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid cors match_origins: {e}"))?;
+        Ok(Cors { config, match_origins })
+    }
+
+    /// The value of `Access-Control-Allow-Origin` for the request, if its origin is allowed.
+    fn allow_origin(&self, request: &HttpRequest) -> Option<HeaderValue> {
+        if self.config.allow_any_origin {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = request.headers.get(ORIGIN)?;
+        let allowed = origin.to_str().is_ok_and(|origin| {
+            self.config.origins.iter().any(|allowed| allowed == origin)
+                || self.match_origins.iter().any(|pattern| pattern.is_match(origin))
+        });
+        allowed.then(|| origin.clone())
+    }
+
+    /// Answer a preflight request, which never reaches the supergraph. A preflight from an origin
+    /// that isn't allowed gets no CORS headers, so the browser blocks the actual request.
+    pub fn preflight(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if request.method != Method::OPTIONS {
+            return None;
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            VARY,
+            HeaderValue::from_static(
+                "origin, access-control-request-method, access-control-request-headers",
+            ),
+        );
+        if let Some(allow_origin) = self.allow_origin(request) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            if self.config.allow_credentials {
+                headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+            if let Ok(methods) = HeaderValue::from_str(&self.config.methods.join(", ")) {
+                headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+            }
+            let allow_headers = if self.config.allow_headers.is_empty() {
+                request.headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+            } else {
+                HeaderValue::from_str(&self.config.allow_headers.join(", ")).ok()
+            };
+            if let Some(allow_headers) = allow_headers {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+            if let Some(max_age) = self.config.max_age {
+                headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+            }
+        }
+        Some(HttpResponse { status: StatusCode::OK, headers, body: String::new() })
+    }
+
+    /// Add the CORS headers to the response of an actual request.
+    pub fn decorate(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        response.headers.append(VARY, HeaderValue::from_static("origin"));
+        let Some(allow_origin) = self.allow_origin(request) else {
+            return;
+        };
+        response.headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.config.allow_credentials {
+            response
+                .headers
+                .insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        let expose_headers = self.config.expose_headers.as_ref().filter(|h| !h.is_empty());
+        if let Some(expose_headers) = expose_headers {
+            if let Ok(expose_headers) = HeaderValue::from_str(&expose_headers.join(", ")) {
+                response.headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventSource;
+    use reqwest::header::HeaderName;
+
+    fn request(json: &str) -> HttpRequest {
+        let event: serde_json::Value = serde_json::from_str(json).unwrap();
+        let source = EventSource::detect(&event).unwrap();
+        HttpRequest::from_event(source, event).unwrap()
+    }
+
+    fn preflight(origin: &str) -> HttpRequest {
+        let mut request = request(include_str!("../../events/function-url-preflight.json"));
+        request.headers.insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+        request
+    }
+
+    fn from_yaml(yaml: &str) -> Result<Cors, Error> {
+        Cors::from_config(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &HeaderName) -> Option<&'a str> {
+        response.headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn answers_preflights_with_the_router_defaults() {
+        let cors = from_yaml("supergraph: {}").unwrap();
+        let response = cors.preflight(&preflight("https://studio.apollographql.com")).unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.body.is_empty());
+        assert_eq!(
+            header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://studio.apollographql.com")
+        );
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_METHODS), Some("GET, POST, OPTIONS"));
+        // Without `allow_headers`, whatever the preflight asks for is allowed.
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_HEADERS), Some("content-type"));
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+        assert_eq!(header(&response, &ACCESS_CONTROL_MAX_AGE), None);
+        assert_eq!(
+            header(&response, &VARY),
+            Some("origin, access-control-request-method, access-control-request-headers")
+        );
+
+        // Preflights from other origins are answered, but without allowing anything.
+        let response = cors.preflight(&preflight("https://example.com")).unwrap();
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_METHODS), None);
+        assert!(response.headers.contains_key(VARY));
+
+        // Anything but OPTIONS goes on to the supergraph.
+        assert!(cors.preflight(&request(include_str!("../../events/function-url.json"))).is_none());
+    }
+
+    #[test]
+    fn allows_listed_and_matching_origins() {
+        let cors = from_yaml(
+            r#"
+            cors:
+              origins: [https://app.example.com]
+              match_origins: ["^https://[a-z]+\\.preview\\.example\\.com$"]
+              allow_credentials: true
+              allow_headers: [content-type, authorization]
+              methods: [POST]
+              max_age: 10m
+            "#,
+        )
+        .unwrap();
+        for origin in ["https://app.example.com", "https://pr.preview.example.com"] {
+            let response = cors.preflight(&preflight(origin)).unwrap();
+            assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN), Some(origin));
+            assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+            assert_eq!(
+                header(&response, &ACCESS_CONTROL_ALLOW_HEADERS),
+                Some("content-type, authorization")
+            );
+            assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_METHODS), Some("POST"));
+            assert_eq!(header(&response, &ACCESS_CONTROL_MAX_AGE), Some("600"));
+        }
+        // Listing origins replaces the default one.
+        for origin in ["https://studio.apollographql.com", "https://pr.preview.example.com.evil"] {
+            let response = cors.preflight(&preflight(origin)).unwrap();
+            assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        }
+
+        let cors = from_yaml("cors: { allow_any_origin: true }").unwrap();
+        let mut request = preflight("https://example.com");
+        request.headers.remove(ORIGIN);
+        let response = cors.preflight(&request).unwrap();
+        assert_eq!(header(&response, &ACCESS_CONTROL_ALLOW_ORIGIN), Some("*"));
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        assert!(from_yaml("cors: { allow_any_origin: true, allow_credentials: true }").is_err());
+        assert!(from_yaml("cors: { match_origins: ['(unclosed'] }").is_err());
+        assert!(from_yaml("cors: { max_age: soon }").is_err());
+        assert!(from_yaml("cors: { allow_origins: [https://example.com] }").is_err());
+    }
+
+    #[test]
+    fn adds_cors_headers_to_responses_from_allowed_origins() {
+        let cors = from_yaml(
+            r#"
+            cors:
+              origins: [https://app.example.com]
+              allow_credentials: true
+              expose_headers: [x-request-id, x-cache]
+            "#,
+        )
+        .unwrap();
+        let mut request = request(include_str!("../../events/function-url.json"));
+        let response = || HttpResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: "{}".to_string(),
+        };
+
+        let mut without_origin = response();
+        cors.decorate(&request, &mut without_origin);
+        assert_eq!(header(&without_origin, &VARY), Some("origin"));
+        assert_eq!(header(&without_origin, &ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+        request.headers.insert(ORIGIN, HeaderValue::from_static("https://app.example.com"));
+        let mut allowed = response();
+        allowed.headers.insert(VARY, HeaderValue::from_static("accept"));
+        cors.decorate(&request, &mut allowed);
+        let vary: Vec<_> = allowed.headers.get_all(VARY).iter().collect();
+        assert_eq!(vary, ["accept", "origin"]);
+        assert_eq!(header(&allowed, &ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+        assert_eq!(header(&allowed, &ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+        assert_eq!(header(&allowed, &ACCESS_CONTROL_EXPOSE_HEADERS), Some("x-request-id, x-cache"));
+        // Preflight-only headers stay off normal responses.
+        assert_eq!(header(&allowed, &ACCESS_CONTROL_ALLOW_METHODS), None);
+    }
+}
@@ -0,0 +1,140 @@
+use apollo_router::graphql;
+use apollo_router::services::router;
+use lambda_runtime::Error;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A GraphQL request sent straight to the function with the Invoke API, without wrapping it in
+/// an HTTP event first. Batches are executed one after the other, and answered with an array of
+/// responses in the same order.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DirectRequest {
+    Batch(Vec<graphql::Request>),
+    Single(graphql::Request),
+}
+
+/// Check if an event looks like a bare GraphQL request, i.e. an object with a `query` (or a
+/// persisted query hash in its `extensions`), or a non-empty array of those.
+pub fn is_direct_request(event: &Value) -> bool {
+    let is_single = |event: &Value| {
+        event.get("query").is_some() || event.pointer("/extensions/persistedQuery").is_some()
+    };
+    match event {
+        Value::Array(requests) => !requests.is_empty() && requests.iter().all(is_single),
+        _ => is_single(event),
+    }
+}
+
+/// Execute a direct request and return the raw GraphQL response(s).
+pub async fn handle(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    event: Value,
+) -> Result<Value, Error> {
+    match serde_json::from_value::<DirectRequest>(event)? {
+        DirectRequest::Single(request) => {
+            let (_status, response) =
+                crate::execute(supergraph, None, request, &HeaderMap::new(), Ok(None), Vec::new())
+                    .await?;
+            Ok(response)
+        }
+        DirectRequest::Batch(requests) => {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                // A failure in one of the operations should not fail the rest of the batch, so
+                // we turn it into a GraphQL error for that operation instead.
+                let result = crate::execute(
+                    supergraph.clone(),
+                    None,
+                    request,
+                    &HeaderMap::new(),
+                    Ok(None),
+                    Vec::new(),
+                )
+                .await;
+                let response = match result {
+                    Ok((_status, response)) => response,
+                    Err(e) => json!({ "errors": [{ "message": e.to_string() }] }),
+                };
+                responses.push(response);
+            }
+            Ok(Value::from(responses))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A supergraph that echoes the requests it gets as data, and fails on `products`.
+    fn supergraph() -> Arc<Mutex<router::BoxCloneService>> {
+        let service = tower::service_fn(|request: router::Request| async move {
+            let body = hyper::body::to_bytes(request.router_request.into_body()).await?;
+            let request: Value = serde_json::from_slice(&body)?;
+            if request["query"].as_str().is_some_and(|query| query.contains("products")) {
+                return Err(tower::BoxError::from("products subgraph is down"));
+            }
+            let response = json!({ "data": { "request": request } });
+            let response = hyper::Response::new(hyper::Body::from(response.to_string()));
+            Ok(router::Response::from(response))
+        });
+        Arc::new(Mutex::new(router::BoxCloneService::new(service)))
+    }
+
+    fn event(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn detects_bare_graphql_requests() {
+        assert!(is_direct_request(&event(include_str!("../../events/direct.json"))));
+        assert!(is_direct_request(&event(include_str!("../../events/direct-batch.json"))));
+        let persisted = json!({ "extensions": { "persistedQuery": { "version": 1 } } });
+        assert!(is_direct_request(&persisted));
+        assert!(!is_direct_request(&json!([])));
+        assert!(!is_direct_request(&json!([{ "query": "{ me { name } }" }, { "body": "{}" }])));
+        for http in [
+            include_str!("../../events/apigw-v2.json"),
+            include_str!("../../events/function-url.json"),
+        ] {
+            assert!(!is_direct_request(&event(http)));
+        }
+    }
+
+    #[tokio::test]
+    async fn executes_a_single_request() {
+        let response =
+            handle(supergraph(), event(include_str!("../../events/direct.json"))).await.unwrap();
+        let request = &response["data"]["request"];
+        assert_eq!(
+            request["query"],
+            "query Me($withName: Boolean!) { me { id name @include(if: $withName) } }"
+        );
+        assert_eq!(request["operationName"], "Me");
+        assert_eq!(request["variables"], json!({ "withName": true }));
+    }
+
+    #[tokio::test]
+    async fn answers_batches_in_order_despite_failures() {
+        let response = handle(supergraph(), event(include_str!("../../events/direct-batch.json")))
+            .await
+            .unwrap();
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["data"]["request"]["query"], "{ me { name } }");
+        assert_eq!(responses[1], json!({ "errors": [{ "message": "products subgraph is down" }] }));
+    }
+
+    #[tokio::test]
+    async fn fails_invalid_and_failed_single_requests() {
+        let invalid = json!({ "query": "{ me { name } }", "variables": ["not", "an", "object"] });
+        assert!(handle(supergraph(), invalid).await.is_err());
+        let error =
+            handle(supergraph(), json!({ "query": "{ products { id } }" })).await.unwrap_err();
+        assert_eq!(error.to_string(), "products subgraph is down");
+    }
+}
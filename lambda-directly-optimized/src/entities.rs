@@ -0,0 +1,576 @@
+use apollo_router::graphql;
+use apollo_router::services::subgraph;
+use lambda_runtime::Error;
+use lru::LruCache;
+use reqwest::header::{HeaderMap, HeaderName, CACHE_CONTROL};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{BoxError, Service, ServiceExt};
+use tracing::info;
+
+/// How many entities each container keeps, unless `APOLLO_ROUTER_ENTITY_CACHE_CAPACITY` says
+/// otherwise.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// The request headers that the client identifies itself with.
+pub const CREDENTIALS: [&str; 3] = ["authorization", "cookie", "x-api-key"];
+
+static CACHE: OnceLock<EntityCache> = OnceLock::new();
+
+/// Caches the entities that subgraphs return from `_entities` fetches, per representation, so
+/// that warm invocations only fetch the entities they haven't seen yet. It's configured with
+/// `APOLLO_ROUTER_ENTITY_CACHE`, a comma-separated list of `Type=ttl` pairs, e.g. `Product=5m`.
+/// Other types are cached for as long as the `Cache-Control` header of the subgraph response
+/// allows, so the variable can be empty to only go by the subgraphs.
+struct EntityCache {
+    ttls: HashMap<String, Duration>,
+    entries: Mutex<LruCache<String, Entry>>,
+    identities: Identities,
+}
+
+/// The request headers and router context entries that tell who the client is: the credentials,
+/// the claims of a verified token, and whatever the authorizer claims are mapped onto. Entities
+/// fetched with any of them could be meant for that client alone, so they are neither cached nor
+/// answered from the cache.
+pub struct Identities {
+    pub headers: Vec<HeaderName>,
+    pub context: Vec<String>,
+}
+
+impl Identities {
+    /// The identities that this function is configured with, once the authorizer mapping is set
+    /// up.
+    pub fn configured() -> Self {
+        let credentials = CREDENTIALS.iter().map(|name| HeaderName::from_static(name));
+        let claims = crate::auth::CLAIMS_CONTEXT_KEY.to_string();
+        Identities {
+            headers: credentials.chain(crate::authorizer::headers()).collect(),
+            context: std::iter::once(claims).chain(crate::authorizer::context_keys()).collect(),
+        }
+    }
+
+    fn identify(&self, headers: &HeaderMap, context: &apollo_router::Context) -> bool {
+        self.headers.iter().any(|name| headers.contains_key(name))
+            || self.context.iter().any(|key| context.contains_key(key))
+    }
+}
+
+struct Entry {
+    representation: Value,
+    entity: Value,
+    expires_at: Instant,
+}
+
+/// Set up the entity cache, if it's configured.
+pub fn init() -> Result<(), Error> {
+    let Ok(ttls) = env::var("APOLLO_ROUTER_ENTITY_CACHE") else {
+        return Ok(());
+    };
+    let ttls = ttls
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let invalid = || {
+                format!("APOLLO_ROUTER_ENTITY_CACHE must be a list of Type=ttl pairs, got {pair}")
+            };
+            let (typename, ttl) = pair.split_once('=').ok_or_else(invalid)?;
+            let ttl = humantime::parse_duration(ttl.trim()).map_err(|_| invalid())?;
+            Ok((typename.trim().to_string(), ttl))
+        })
+        .collect::<Result<_, Error>>()?;
+    let capacity = match env::var("APOLLO_ROUTER_ENTITY_CACHE_CAPACITY") {
+        Ok(capacity) => capacity.parse()?,
+        Err(_) => DEFAULT_CAPACITY,
+    };
+    let capacity =
+        NonZeroUsize::new(capacity).ok_or("the entity cache capacity must be at least 1")?;
+    let entries = Mutex::new(LruCache::new(capacity));
+    let _ = CACHE.set(EntityCache { ttls, entries, identities: Identities::configured() });
+    Ok(())
+}
+
+/// Answer `_entities` fetches from the cache where we can.
+pub fn instrument_subgraph(name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+    let Some(cache) = CACHE.get() else {
+        return service;
+    };
+    EntityCacheService { subgraph: name.to_string(), cache, inner: service }.boxed()
+}
+
+/// How long a subgraph response can be shared for, which is zero unless it says otherwise, or
+/// `None` if it must not be shared at all, whatever the configured TTLs say.
+pub fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|value| value.to_str().ok())
+    else {
+        return Some(Duration::ZERO);
+    };
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for directive in cache_control.split(',') {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            Some(("max-age", seconds)) => max_age = seconds.trim_matches('"').parse().ok(),
+            Some(("s-maxage", seconds)) => shared_max_age = seconds.trim_matches('"').parse().ok(),
+            None if matches!(directive.as_str(), "private" | "no-store" | "no-cache") => {
+                return None
+            }
+            _ => {}
+        }
+    }
+    Some(Duration::from_secs(shared_max_age.or(max_age).unwrap_or(0)))
+}
+
+/// Whether the event asks us to invalidate cached entities, which is only possible with the
+/// Invoke API, since HTTP events are never mistaken for it.
+pub fn is_invalidation(event: &Value) -> bool {
+    event.get("invalidateEntities").is_some()
+}
+
+/// Drop the cached entities that match any of the given representations, e.g.
+/// `{ "invalidateEntities": [{ "__typename": "Product", "id": "1" }, { "__typename": "User" }] }`
+/// drops product 1 and every user. A representation matches the entities whose representation
+/// has the same value for each of its fields.
+pub fn invalidate(event: &Value) -> Result<Value, Error> {
+    let selectors = event["invalidateEntities"]
+        .as_array()
+        .filter(|selectors| {
+            selectors
+                .iter()
+                .all(|selector| selector.get("__typename").is_some_and(Value::is_string))
+        })
+        .ok_or("invalidateEntities must be a list of representations with a __typename")?;
+    let Some(cache) = CACHE.get() else {
+        return Ok(json!({ "invalidated": 0 }));
+    };
+    let invalidated = cache.invalidate(selectors);
+    info!(invalidated, "invalidated cached entities");
+    Ok(json!({ "invalidated": invalidated }))
+}
+
+impl EntityCache {
+    /// Drop the entities that match any of the selectors, returning how many there were.
+    fn invalidate(&self, selectors: &[Value]) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let matches = |selector: &Value, representation: &Value| {
+            selector
+                .as_object()
+                .into_iter()
+                .flatten()
+                .all(|(field, value)| representation.get(field) == Some(value))
+        };
+        let invalidated: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| selectors.iter().any(|s| matches(s, &entry.representation)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &invalidated {
+            entries.pop(key);
+        }
+        invalidated.len()
+    }
+
+    /// The cached entity, and how much longer it's fresh for.
+    fn get(&self, key: &str) -> Option<(Value, Duration)> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let remaining = entry.expires_at.checked_duration_since(Instant::now())?;
+        Some((entry.entity.clone(), remaining))
+    }
+
+    /// Cache the entities that the subgraph returned for the representations we asked for, unless
+    /// they are `null` or have errors, or the subgraph said that its response is not to be shared.
+    fn put(&self, fetch: &Fetch, fetched: &[Value], errors: &[Value], max_age: Option<Duration>) {
+        let Some(max_age) = max_age else {
+            return;
+        };
+        // An error that we can't pin on one of the entities could be about any of them.
+        let mut failed = HashSet::new();
+        for error in errors {
+            let index = error.pointer("/path/1").and_then(Value::as_u64);
+            match index.filter(|_| error.pointer("/path/0") == Some(&json!("_entities"))) {
+                Some(index) => failed.insert(index as usize),
+                None => return,
+            };
+        }
+        let mut entries = self.entries.lock().unwrap();
+        for (i, (index, entity)) in fetch.missing.iter().zip(fetched).enumerate() {
+            if failed.contains(&i) || entity.is_null() {
+                continue;
+            }
+            let representation = &fetch.representations[*index];
+            let typename = representation["__typename"].as_str().unwrap_or_default();
+            let ttl = self.ttls.get(typename).copied().unwrap_or(max_age);
+            if ttl.is_zero() {
+                continue;
+            }
+            let entry = Entry {
+                representation: representation.clone(),
+                entity: entity.clone(),
+                expires_at: Instant::now() + ttl,
+            };
+            entries.put(fetch.keys[*index].clone(), entry);
+        }
+    }
+}
+
+/// An `_entities` fetch, split into the entities we have cached and the ones we're missing.
+struct Fetch {
+    representations: Vec<Value>,
+    /// The cache key of each representation, which includes the subgraph, the query and the
+    /// other variables, since they decide which fields of the entity are selected.
+    keys: Vec<String>,
+    cached: Vec<Option<Value>>,
+    /// The positions of the representations that we need to fetch.
+    missing: Vec<usize>,
+    /// How much longer the cached entities are fresh for.
+    max_age: Duration,
+}
+
+impl Fetch {
+    fn new(
+        cache: &EntityCache,
+        subgraph: &str,
+        request: &http::Request<graphql::Request>,
+        context: &apollo_router::Context,
+    ) -> Option<Self> {
+        if cache.identities.identify(request.headers(), context) {
+            return None;
+        }
+        let request = request.body();
+        let query = request.query.as_deref().filter(|query| query.contains("_entities"))?;
+        let Ok(Value::Object(mut variables)) = serde_json::to_value(&request.variables) else {
+            return None;
+        };
+        let Some(Value::Array(representations)) = variables.remove("representations") else {
+            return None;
+        };
+        let scope = json!([subgraph, query, variables]).to_string();
+        let keys: Vec<String> = representations
+            .iter()
+            .map(|representation| {
+                hex::encode(Sha256::digest(format!("{scope}{representation}").as_bytes()))
+            })
+            .collect();
+        let mut cached = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        let mut max_age = Duration::MAX;
+        for (index, key) in keys.iter().enumerate() {
+            match cache.get(key) {
+                Some((entity, remaining)) => {
+                    cached.push(Some(entity));
+                    max_age = max_age.min(remaining);
+                }
+                None => {
+                    cached.push(None);
+                    missing.push(index);
+                }
+            }
+        }
+        Some(Fetch { representations, keys, cached, missing, max_age })
+    }
+
+    /// Only ask the subgraph for the representations that we're missing.
+    fn narrow(&self, request: &mut graphql::Request) -> Result<(), BoxError> {
+        let Value::Object(mut variables) = serde_json::to_value(&request.variables)? else {
+            return Ok(());
+        };
+        let missing: Vec<Value> =
+            self.missing.iter().map(|index| self.representations[*index].clone()).collect();
+        variables.insert("representations".to_string(), Value::Array(missing));
+        request.variables = serde_json::from_value(Value::Object(variables))?;
+        Ok(())
+    }
+
+    /// Answer the fetch from the cache alone. The response says how long it's fresh for, so that
+    /// the response cache sees the same as if the subgraph had answered.
+    fn respond(self, context: apollo_router::Context) -> Result<subgraph::Response, BoxError> {
+        let entities: Vec<Value> = self.cached.into_iter().flatten().collect();
+        let body: graphql::Response =
+            serde_json::from_value(json!({ "data": { "_entities": entities } }))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, format!("max-age={}", self.max_age.as_secs()).parse()?);
+        Ok(subgraph::Response::builder()
+            .and_data(body.data)
+            .extensions(body.extensions)
+            .headers(headers)
+            .context(context)
+            .build())
+    }
+
+    /// Cache what the subgraph returned, and put the cached entities back in between, pointing
+    /// the errors of the fetched entities at their original positions.
+    fn merge(self, cache: &EntityCache, response: &mut subgraph::Response) -> Result<(), BoxError> {
+        let mut body = serde_json::to_value(response.response.body())?;
+        let fetched =
+            body.pointer("/data/_entities").and_then(Value::as_array).cloned().unwrap_or_default();
+        let errors = body["errors"].as_array().cloned().unwrap_or_default();
+        if fetched.len() == self.missing.len() {
+            cache.put(&self, &fetched, &errors, max_age(response.response.headers()));
+        }
+        if self.missing.len() == self.representations.len() {
+            return Ok(());
+        }
+
+        let mut fetched = fetched.into_iter();
+        let entities: Vec<Value> = self
+            .cached
+            .into_iter()
+            .map(|cached| cached.or_else(|| fetched.next()).unwrap_or(Value::Null))
+            .collect();
+        if let Some(data) = body.get_mut("data").and_then(Value::as_object_mut) {
+            data.insert("_entities".to_string(), Value::Array(entities));
+        }
+        let errors = body.get_mut("errors").and_then(Value::as_array_mut);
+        for error in errors.into_iter().flatten() {
+            if error.pointer("/path/0") != Some(&json!("_entities")) {
+                continue;
+            }
+            let index = error.pointer("/path/1").and_then(Value::as_u64);
+            if let Some(&index) = index.and_then(|index| self.missing.get(index as usize)) {
+                error["path"][1] = json!(index);
+            }
+        }
+        *response.response.body_mut() = serde_json::from_value(body)?;
+        Ok(())
+    }
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<subgraph::Response, BoxError>> + Send>>;
+
+/// Answers `_entities` fetches from the cache, only passing on the representations that aren't
+/// cached to the subgraph.
+struct EntityCacheService {
+    subgraph: String,
+    cache: &'static EntityCache,
+    inner: subgraph::BoxService,
+}
+
+impl Service<subgraph::Request> for EntityCacheService {
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: subgraph::Request) -> ResponseFuture {
+        let fetch =
+            Fetch::new(self.cache, &self.subgraph, &request.subgraph_request, &request.context);
+        let Some(fetch) = fetch else {
+            return self.inner.call(request);
+        };
+        if fetch.missing.is_empty() {
+            let response = fetch.respond(request.context);
+            return Box::pin(async move { response });
+        }
+        if fetch.missing.len() < fetch.representations.len() {
+            if let Err(e) = fetch.narrow(request.subgraph_request.body_mut()) {
+                return Box::pin(async move { Err(e) });
+            }
+        }
+        let cache = self.cache;
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            fetch.merge(cache, &mut response)?;
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "query($representations:[_Any!]!){_entities(representations:\
+        $representations){...on Product{name}}}";
+
+    fn cache(ttls: &[(&str, u64)]) -> EntityCache {
+        let ttls = ttls
+            .iter()
+            .map(|(typename, secs)| (typename.to_string(), Duration::from_secs(*secs)))
+            .collect();
+        EntityCache {
+            ttls,
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap())),
+            identities: Identities {
+                headers: [&CREDENTIALS[..], &["x-user-id"]]
+                    .concat()
+                    .into_iter()
+                    .map(HeaderName::from_static)
+                    .collect(),
+                context: vec![crate::auth::CLAIMS_CONTEXT_KEY.to_string()],
+            },
+        }
+    }
+
+    fn product(id: &str) -> Value {
+        json!({ "__typename": "Product", "id": id })
+    }
+
+    fn request(representations: &[Value]) -> http::Request<graphql::Request> {
+        let request =
+            json!({ "query": QUERY, "variables": { "representations": representations } });
+        http::Request::new(serde_json::from_value(request).unwrap())
+    }
+
+    fn response(cache_control: &str, body: Value) -> subgraph::Response {
+        let body: graphql::Response = serde_json::from_value(body).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, cache_control.parse().unwrap());
+        subgraph::Response::builder()
+            .and_data(body.data)
+            .errors(body.errors)
+            .extensions(body.extensions)
+            .headers(headers)
+            .context(apollo_router::Context::default())
+            .build()
+    }
+
+    fn body(response: &subgraph::Response) -> Value {
+        serde_json::to_value(response.response.body()).unwrap()
+    }
+
+    /// Fetch the products through the cache, with the subgraph answering the missing ones.
+    fn fetch(cache: &EntityCache, ids: &[&str], subgraph: Value) -> (Vec<usize>, Value) {
+        let representations: Vec<Value> = ids.iter().map(|id| product(id)).collect();
+        let mut request = request(&representations);
+        let fetch = Fetch::new(cache, "products", &request, &Default::default()).unwrap();
+        let missing = fetch.missing.clone();
+        fetch.narrow(request.body_mut()).unwrap();
+        let narrowed = &request.body().variables["representations"];
+        assert_eq!(narrowed.as_array().unwrap().len(), missing.len());
+        let mut response = response("max-age=60", subgraph);
+        fetch.merge(cache, &mut response).unwrap();
+        (missing, body(&response))
+    }
+
+    #[test]
+    fn only_fetches_the_missing_entities() {
+        let cache = cache(&[]);
+        let fetched = json!({ "data": { "_entities": [{ "name": "a" }, { "name": "b" }] } });
+        let (missing, _) = fetch(&cache, &["1", "2"], fetched);
+        assert_eq!(missing, [0, 1]);
+
+        let fetched = json!({ "data": { "_entities": [{ "name": "c" }] } });
+        let (missing, response) = fetch(&cache, &["2", "3", "1"], fetched);
+        assert_eq!(missing, [1]);
+        let names = json!([{ "name": "b" }, { "name": "c" }, { "name": "a" }]);
+        assert_eq!(response["data"]["_entities"], names);
+
+        // Once they're all cached, the subgraph isn't needed at all.
+        let fetch = Fetch::new(
+            &cache,
+            "products",
+            &request(&[product("3"), product("1")]),
+            &Default::default(),
+        )
+        .unwrap();
+        assert!(fetch.missing.is_empty());
+        let response = fetch.respond(apollo_router::Context::default()).unwrap();
+        assert_eq!(body(&response)["data"]["_entities"], json!([{ "name": "c" }, { "name": "a" }]));
+        let max_age = response.response.headers()[CACHE_CONTROL].to_str().unwrap();
+        assert!(matches!(max_age, "max-age=59" | "max-age=60"), "{max_age}");
+
+        // Other subgraphs and selections are cached apart.
+        assert_eq!(
+            Fetch::new(&cache, "reviews", &request(&[product("1")]), &Default::default())
+                .unwrap()
+                .missing,
+            [0]
+        );
+    }
+
+    #[test]
+    fn points_errors_at_the_original_positions() {
+        let cache = cache(&[]);
+        fetch(&cache, &["1"], json!({ "data": { "_entities": [{ "name": "a" }] } }));
+
+        let fetched = json!({
+            "data": { "_entities": [null, { "name": "c" }] },
+            "errors": [{ "message": "not found", "path": ["_entities", 0, "name"] }],
+        });
+        let (missing, response) = fetch(&cache, &["1", "2", "3"], fetched);
+        assert_eq!(missing, [1, 2]);
+        assert_eq!(response["data"]["_entities"], json!([{ "name": "a" }, null, { "name": "c" }]));
+        assert_eq!(response["errors"][0]["path"], json!(["_entities", 1, "name"]));
+
+        // The entity that failed isn't cached, unlike the one next to it.
+        let fetch = Fetch::new(
+            &cache,
+            "products",
+            &request(&[product("2"), product("3")]),
+            &Default::default(),
+        );
+        assert_eq!(fetch.unwrap().missing, [0]);
+    }
+
+    #[test]
+    fn respects_the_subgraph_cache_control() {
+        let cache = cache(&[("Product", 300)]);
+        for cache_control in ["private, max-age=60", "no-store", "no-cache"] {
+            let mut request = request(&[product("1")]);
+            let fetch = Fetch::new(&cache, "products", &request, &Default::default()).unwrap();
+            fetch.narrow(request.body_mut()).unwrap();
+            let mut response =
+                response(cache_control, json!({ "data": { "_entities": [{ "name": "a" }] } }));
+            fetch.merge(&cache, &mut response).unwrap();
+            assert_eq!(cache.entries.lock().unwrap().len(), 0, "{cache_control}");
+        }
+
+        assert_eq!(max_age(&HeaderMap::new()), Some(Duration::ZERO));
+        let headers = |value| HeaderMap::from_iter([(CACHE_CONTROL, value)]);
+        let shared = headers(http::HeaderValue::from_static("max-age=60, s-maxage=30"));
+        assert_eq!(max_age(&shared), Some(Duration::from_secs(30)));
+        assert_eq!(max_age(&headers(http::HeaderValue::from_static("Private"))), None);
+    }
+
+    #[test]
+    fn bypasses_the_cache_for_identified_clients() {
+        let cache = cache(&[("Product", 300)]);
+        let anonymous = apollo_router::Context::new();
+        for credentials in CREDENTIALS.into_iter().chain(["x-user-id"]) {
+            let mut request = request(&[product("1")]);
+            request.headers_mut().insert(credentials, http::HeaderValue::from_static("abc"));
+            assert!(Fetch::new(&cache, "products", &request, &anonymous).is_none());
+        }
+
+        let verified = apollo_router::Context::new();
+        verified.insert(crate::auth::CLAIMS_CONTEXT_KEY, json!({ "sub": "ada" })).unwrap();
+        assert!(Fetch::new(&cache, "products", &request(&[product("1")]), &verified).is_none());
+    }
+
+    #[test]
+    fn invalidates_matching_entities() {
+        let cache = cache(&[("Product", 300), ("User", 300)]);
+        let fetched = json!({ "data": { "_entities": [{ "name": "a" }, { "name": "b" }] } });
+        fetch(&cache, &["1", "2"], fetched);
+        let mut users = request(&[json!({ "__typename": "User", "id": "1" })]);
+        let fetch = Fetch::new(&cache, "users", &users, &Default::default()).unwrap();
+        fetch.narrow(users.body_mut()).unwrap();
+        let mut response = response("", json!({ "data": { "_entities": [{ "name": "ada" }] } }));
+        fetch.merge(&cache, &mut response).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 3);
+
+        let selectors = [product("1"), json!({ "__typename": "User" })];
+        assert_eq!(cache.invalidate(&selectors), 2);
+        assert_eq!(cache.invalidate(&selectors), 0);
+        let fetch = Fetch::new(
+            &cache,
+            "products",
+            &request(&[product("1"), product("2")]),
+            &Default::default(),
+        );
+        assert_eq!(fetch.unwrap().missing, [0]);
+        assert!(invalidate(&json!({ "invalidateEntities": [{ "id": "1" }] })).is_err());
+    }
+}
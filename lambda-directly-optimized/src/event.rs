@@ -0,0 +1,492 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, SET_COOKIE};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// The kinds of events that the function knows how to handle.
+#[cfg(feature = "full")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// An HTTP request from one of the front doors.
+    Http(EventSource),
+    /// A bare GraphQL request, or a batch of them, sent with the Invoke API.
+    Direct,
+    /// An AppSync direct Lambda resolver event, or a batch of them.
+    AppSync,
+    /// A batch of SQS messages, each with a GraphQL request as its body.
+    Sqs,
+    /// An EventBridge event, with a GraphQL request as its detail.
+    EventBridge,
+    /// An admin event sent with the Invoke API, to drop entities from the entity cache.
+    InvalidateEntities,
+}
+
+#[cfg(feature = "full")]
+impl EventKind {
+    pub fn detect(event: &Value) -> Option<Self> {
+        if let Some(source) = EventSource::detect(event) {
+            Some(EventKind::Http(source))
+        } else if crate::queue::is_sqs_event(event) {
+            Some(EventKind::Sqs)
+        } else if crate::queue::is_eventbridge_event(event) {
+            Some(EventKind::EventBridge)
+        } else if crate::appsync::is_appsync_event(event) {
+            Some(EventKind::AppSync)
+        } else if crate::entities::is_invalidation(event) {
+            Some(EventKind::InvalidateEntities)
+        } else if crate::direct::is_direct_request(event) {
+            Some(EventKind::Direct)
+        } else {
+            None
+        }
+    }
+}
+
+/// The front doors that can sit in front of the Lambda and hand us an HTTP event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSource {
+    /// API Gateway REST APIs, and HTTP APIs using the 1.0 payload format.
+    ApiGatewayV1,
+    /// API Gateway HTTP APIs using the 2.0 payload format.
+    ApiGatewayV2,
+    /// Lambda Function URLs, which use the 2.0 payload format on a `lambda-url` domain.
+    FunctionUrl,
+    /// Application Load Balancer target groups.
+    Alb,
+}
+
+impl EventSource {
+    /// Detect which front door an event came from by looking at the fields that are unique to
+    /// each of the payload formats. Returns `None` if the event is not an HTTP event at all.
+    pub fn detect(event: &Value) -> Option<Self> {
+        let request_context = event.get("requestContext")?;
+        if request_context.get("elb").is_some() {
+            Some(EventSource::Alb)
+        } else if request_context.get("http").is_some() {
+            let domain_name = request_context.get("domainName").and_then(Value::as_str);
+            if domain_name.is_some_and(|domain| domain.contains(".lambda-url.")) {
+                Some(EventSource::FunctionUrl)
+            } else {
+                Some(EventSource::ApiGatewayV2)
+            }
+        } else if event.get("httpMethod").is_some() {
+            Some(EventSource::ApiGatewayV1)
+        } else {
+            None
+        }
+    }
+}
+
+/// The raw HTTP event as it arrives from Lambda. All of the payload formats are a variation of
+/// the same fields, so we deserialize them into one struct and normalize it afterwards. Every
+/// field is optional since each format only sets a subset of them, and API Gateway likes to
+/// send explicit `null`s for empty maps.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct RawHttpEvent {
+    http_method: Option<String>,
+    path: Option<String>,
+    raw_path: Option<String>,
+    raw_query_string: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    query_string_parameters: Option<HashMap<String, String>>,
+    multi_value_query_string_parameters: Option<HashMap<String, Vec<String>>>,
+    cookies: Option<Vec<String>>,
+    body: Option<String>,
+    is_base64_encoded: Option<bool>,
+    request_context: Value,
+}
+
+/// A normalized HTTP request, independent of which front door it came through.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub source: EventSource,
+    pub method: Method,
+    /// The request path, with any API Gateway stage prefix removed.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub query: HashMap<String, Vec<String>>,
+    /// The request body, with any base64 encoding removed.
+    pub body: Vec<u8>,
+    /// The untouched `requestContext` of the event, e.g. for reading authorizer details.
+    pub request_context: Value,
+    /// ALB only returns multi-value headers if the target group was configured to send them to
+    /// us, so we need to remember which shape to respond with.
+    multi_value_headers: bool,
+}
+
+impl HttpRequest {
+    /// Normalize an HTTP event from any of the supported front doors.
+    pub fn from_event(source: EventSource, event: Value) -> Result<Self, String> {
+        let raw: RawHttpEvent =
+            serde_json::from_value(event).map_err(|e| format!("invalid HTTP event: {e}"))?;
+
+        let method = match source {
+            EventSource::ApiGatewayV2 | EventSource::FunctionUrl => {
+                raw.request_context.pointer("/http/method").and_then(Value::as_str)
+            }
+            EventSource::ApiGatewayV1 | EventSource::Alb => raw.http_method.as_deref(),
+        }
+        .unwrap_or("POST");
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|e| format!("invalid HTTP method {method}: {e}"))?;
+
+        // Payload format 2.0 puts the stage in the path unless it's the `$default` stage, and
+        // REST APIs behind a custom domain keep the base path mapping in it. We strip it so that
+        // the same paths work no matter how the API is deployed.
+        let path = match source {
+            EventSource::ApiGatewayV2 | EventSource::FunctionUrl => raw.raw_path.clone(),
+            EventSource::ApiGatewayV1 | EventSource::Alb => raw.path.clone(),
+        }
+        .unwrap_or_else(|| "/".to_string());
+        let stage = raw.request_context.get("stage").and_then(Value::as_str);
+        let path = match stage {
+            Some(stage) if stage != "$default" => strip_stage(&path, stage),
+            _ => path,
+        };
+
+        // Multi-value headers take precedence when present, since the single-value variant
+        // only contains the last value of each header.
+        let mut headers = HeaderMap::new();
+        let multi_value_headers = raw.multi_value_headers.is_some();
+        if let Some(multi_value_headers) = raw.multi_value_headers {
+            for (name, values) in multi_value_headers {
+                for value in values {
+                    append_header(&mut headers, &name, &value);
+                }
+            }
+        } else if let Some(single_value_headers) = raw.headers {
+            for (name, value) in single_value_headers {
+                append_header(&mut headers, &name, &value);
+            }
+        }
+        // Payload format 2.0 moves cookies out of the headers and into their own field.
+        if let Some(cookies) = raw.cookies {
+            if !cookies.is_empty() {
+                append_header(&mut headers, "cookie", &cookies.join("; "));
+            }
+        }
+
+        let mut query: HashMap<String, Vec<String>> = HashMap::new();
+        if let Some(multi_value_query) = raw.multi_value_query_string_parameters {
+            query = multi_value_query;
+        } else if let Some(raw_query_string) = raw.raw_query_string.filter(|q| !q.is_empty()) {
+            for (key, value) in url::form_urlencoded::parse(raw_query_string.as_bytes()) {
+                query.entry(key.into_owned()).or_default().push(value.into_owned());
+            }
+        } else if let Some(single_value_query) = raw.query_string_parameters {
+            for (key, value) in single_value_query {
+                query.entry(key).or_default().push(value);
+            }
+        }
+        // ALB passes on query string parameters exactly as the client sent them, i.e. still
+        // percent-encoded, whereas API Gateway decodes them for us.
+        if source == EventSource::Alb {
+            query = query
+                .into_iter()
+                .map(|(key, values)| {
+                    (percent_decode(&key), values.iter().map(|v| percent_decode(v)).collect())
+                })
+                .collect();
+        }
+
+        let body = match raw.body {
+            Some(body) if raw.is_base64_encoded.unwrap_or(false) => {
+                BASE64.decode(body).map_err(|e| format!("invalid base64 body: {e}"))?
+            }
+            Some(body) => body.into_bytes(),
+            None => Vec::new(),
+        };
+
+        Ok(HttpRequest {
+            source,
+            method,
+            path,
+            headers,
+            query,
+            body,
+            request_context: raw.request_context,
+            multi_value_headers,
+        })
+    }
+
+    /// Get the first value of a query string parameter.
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).and_then(|values| values.first()).map(String::as_str)
+    }
+
+    /// Extract the GraphQL request from either the JSON body of a POST request, or the query
+    /// string parameters of a GET request, following the GraphQL over HTTP conventions. GET
+    /// requests can only run queries, since browsers send them cross-site without asking, so a
+    /// mutation over GET gets a 405 like it would from the Router.
+    pub fn graphql_request(&self) -> Result<apollo_router::graphql::Request, (StatusCode, String)> {
+        let invalid = |message: String| (StatusCode::BAD_REQUEST, message);
+        if self.method != Method::GET {
+            return serde_json::from_slice(&self.body)
+                .map_err(|e| invalid(format!("invalid GraphQL request: {e}")));
+        }
+        let query = self.query_param("query").ok_or(invalid("missing `query` parameter".into()))?;
+        let mut request = json!({ "query": query });
+        if let Some(operation_name) = self.query_param("operationName") {
+            request["operationName"] = Value::from(operation_name);
+        }
+        // Variables and extensions are sent as JSON encoded strings in the query string.
+        for key in ["variables", "extensions"] {
+            if let Some(value) = self.query_param(key) {
+                request[key] = serde_json::from_str::<Map<String, Value>>(value)
+                    .map(Value::Object)
+                    .map_err(|e| invalid(format!("invalid `{key}` parameter: {e}")))?;
+            }
+        }
+        // Operations that can't be parsed are left for the supergraph to reject.
+        if let Ok(document) = graphql_parser::parse_query::<String>(query) {
+            let operation =
+                crate::ast::select_operation(&document, self.query_param("operationName"));
+            if let Some((root @ ("mutation" | "subscription"), _)) =
+                operation.map(crate::ast::root_selection_set)
+            {
+                let message = format!("a {root} can only be sent with POST");
+                return Err((StatusCode::METHOD_NOT_ALLOWED, message));
+            }
+        }
+        serde_json::from_value(request)
+            .map_err(|e| invalid(format!("invalid GraphQL request: {e}")))
+    }
+}
+
+/// Remove the stage from the start of a path, e.g. `/prod/graphql` becomes `/graphql`.
+fn strip_stage(path: &str, stage: &str) -> String {
+    match path.strip_prefix('/').and_then(|p| p.strip_prefix(stage)) {
+        Some("") => "/".to_string(),
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    url::form_urlencoded::parse(format!("v={value}").as_bytes())
+        .next()
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_else(|| value.to_string())
+}
+
+fn append_header(headers: &mut HeaderMap, name: &str, value: &str) {
+    // Silently skip headers that aren't valid HTTP, rather than failing the whole request.
+    if let (Ok(name), Ok(value)) =
+        (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value))
+    {
+        headers.append(name, value);
+    }
+}
+
+/// An HTTP response, which can be shaped into what each of the front doors expect.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn json(status: StatusCode, body: &Value) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        HttpResponse { status, headers, body: body.to_string() }
+    }
+
+    /// A GraphQL error response, for requests that we reject before they reach the router.
+    pub fn graphql_error(status: StatusCode, message: &str) -> Self {
+        HttpResponse::json(status, &json!({ "errors": [{ "message": message }] }))
+    }
+
+    /// Shape the response into the event format that the request's front door expects.
+    pub fn into_event(self, request: &HttpRequest) -> Value {
+        let mut event = json!({
+            "statusCode": self.status.as_u16(),
+            "body": self.body,
+            "isBase64Encoded": false,
+        });
+        let header_values = |name: &HeaderName| -> Vec<String> {
+            self.headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok().map(str::to_string))
+                .collect()
+        };
+        match request.source {
+            EventSource::ApiGatewayV2 | EventSource::FunctionUrl => {
+                // Payload format 2.0 combines duplicate headers with commas, except for
+                // `set-cookie` which gets its own field.
+                let headers: Map<String, Value> = self
+                    .headers
+                    .keys()
+                    .filter(|name| **name != SET_COOKIE)
+                    .map(|name| (name.to_string(), Value::from(header_values(name).join(","))))
+                    .collect();
+                event["headers"] = Value::from(headers);
+                event["cookies"] = Value::from(header_values(&SET_COOKIE));
+            }
+            EventSource::ApiGatewayV1 | EventSource::Alb => {
+                let multi_value_headers: Map<String, Value> = self
+                    .headers
+                    .keys()
+                    .map(|name| (name.to_string(), Value::from(header_values(name))))
+                    .collect();
+                if request.source == EventSource::Alb {
+                    // ALB requires a status description, and only accepts the header shape that
+                    // it sent to us.
+                    event["statusDescription"] = Value::from(format!(
+                        "{} {}",
+                        self.status.as_u16(),
+                        self.status.canonical_reason().unwrap_or_default()
+                    ));
+                    if request.multi_value_headers {
+                        event["multiValueHeaders"] = Value::from(multi_value_headers);
+                        return event;
+                    }
+                }
+                let headers: Map<String, Value> = self
+                    .headers
+                    .keys()
+                    .filter_map(|name| {
+                        header_values(name)
+                            .pop()
+                            .map(|value| (name.to_string(), Value::from(value)))
+                    })
+                    .collect();
+                event["headers"] = Value::from(headers);
+                if request.source == EventSource::ApiGatewayV1 {
+                    event["multiValueHeaders"] = Value::from(multi_value_headers);
+                }
+            }
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn request(json: &str) -> HttpRequest {
+        let event = event(json);
+        let source = EventSource::detect(&event).unwrap();
+        HttpRequest::from_event(source, event).unwrap()
+    }
+
+    #[test]
+    fn detects_each_front_door() {
+        for (json, source) in [
+            (include_str!("../../events/apigw-v1.json"), EventSource::ApiGatewayV1),
+            (include_str!("../../events/apigw-v2.json"), EventSource::ApiGatewayV2),
+            (include_str!("../../events/function-url.json"), EventSource::FunctionUrl),
+            (include_str!("../../events/alb.json"), EventSource::Alb),
+        ] {
+            assert_eq!(EventSource::detect(&event(json)), Some(source));
+            #[cfg(feature = "full")]
+            assert_eq!(EventKind::detect(&event(json)), Some(EventKind::Http(source)));
+        }
+        assert_eq!(EventSource::detect(&json!({ "body": "{}" })), None);
+    }
+
+    #[test]
+    fn decodes_base64_bodies() {
+        for json in
+            [include_str!("../../events/apigw-v1.json"), include_str!("../../events/alb.json")]
+        {
+            let request = request(json);
+            assert_eq!(request.body, br#"{"query":"{me { name } }"}"#);
+            assert_eq!(request.graphql_request().unwrap().query.as_deref(), Some("{me { name } }"));
+        }
+    }
+
+    #[test]
+    fn keeps_every_value_of_multi_value_headers() {
+        let request = request(include_str!("../../events/alb.json"));
+        let forwarded_for: Vec<_> = request.headers.get_all("x-forwarded-for").iter().collect();
+        assert_eq!(forwarded_for, ["127.0.0.1", "10.0.0.1"]);
+
+        // ALB only accepts the header shape that it sent.
+        let mut response = HttpResponse::json(StatusCode::OK, &json!({}));
+        response.headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+        response.headers.append(SET_COOKIE, HeaderValue::from_static("b=2"));
+        let event = response.into_event(&request);
+        assert_eq!(event["multiValueHeaders"]["set-cookie"], json!(["a=1", "b=2"]));
+        assert!(event.get("headers").is_none());
+        assert_eq!(event["statusDescription"], "200 OK");
+    }
+
+    #[test]
+    fn moves_cookies_of_payload_format_2() {
+        let request = request(include_str!("../../events/apigw-v2.json"));
+        assert_eq!(request.headers["cookie"], "session=abc");
+
+        let mut response = HttpResponse::json(StatusCode::OK, &json!({}));
+        response.headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+        let event = response.into_event(&request);
+        assert_eq!(event["cookies"], json!(["a=1"]));
+        assert!(event["headers"].get("set-cookie").is_none());
+    }
+
+    #[test]
+    fn strips_the_stage_prefix() {
+        assert_eq!(request(include_str!("../../events/apigw-v2.json")).path, "/graphql");
+        assert_eq!(request(include_str!("../../events/apigw-v1.json")).path, "/graphql");
+        assert_eq!(strip_stage("/prod/graphql", "prod"), "/graphql");
+        assert_eq!(strip_stage("/prod", "prod"), "/");
+        assert_eq!(strip_stage("/production/graphql", "prod"), "/production/graphql");
+    }
+
+    #[test]
+    fn reads_get_requests_from_the_query_string() {
+        let request = request(
+            &json!({
+                "httpMethod": "GET",
+                "path": "/graphql",
+                "multiValueQueryStringParameters": {
+                    "query": ["query Me { me { name } }"],
+                    "operationName": ["Me"],
+                    "variables": ["{\"id\":\"1\"}"],
+                },
+                "requestContext": { "stage": "$default" },
+            })
+            .to_string(),
+        );
+        let graphql_request = request.graphql_request().unwrap();
+        assert_eq!(graphql_request.operation_name.as_deref(), Some("Me"));
+        assert_eq!(serde_json::to_value(&graphql_request.variables).unwrap(), json!({ "id": "1" }));
+    }
+
+    #[test]
+    fn only_runs_queries_over_get() {
+        let get = |query: &str, operation_name: Option<&str>| {
+            let mut event = json!({
+                "httpMethod": "GET",
+                "path": "/graphql",
+                "multiValueQueryStringParameters": { "query": [query] },
+                "requestContext": { "stage": "$default" },
+            });
+            if let Some(operation_name) = operation_name {
+                event["multiValueQueryStringParameters"]["operationName"] = json!([operation_name]);
+            }
+            request(&event.to_string()).graphql_request()
+        };
+        let (status, message) = get("mutation { deleteMe }", None).unwrap_err();
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(message, "a mutation can only be sent with POST");
+        let document = "query Me { me { name } } mutation Delete { deleteMe }";
+        assert!(get(document, Some("Me")).is_ok());
+        assert_eq!(get(document, Some("Delete")).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(get(document, None).is_ok());
+        assert_eq!(get("subscription { me }", None).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+        // Operations that can't be parsed are left for the supergraph to reject.
+        assert!(get("mutation {", None).is_ok());
+    }
+}
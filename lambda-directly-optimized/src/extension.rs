@@ -0,0 +1,389 @@
+use futures::future::BoxFuture;
+use lambda_runtime::Error;
+use reqwest::Client;
+use serde_json::json;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Notify;
+use tracing::warn;
+
+const EXTENSION_NAME: &str = "apollo-router-lambda";
+const EXTENSION_ID_HEADER: &str = "Lambda-Extension-Identifier";
+
+/// How long we wait for in-flight work during shutdown. Lambda gives the runtime 500ms after the
+/// SIGTERM, so we leave some room for the shutdown hooks.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(300);
+
+type Hook = Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// State shared between the invocation handler and the extension, which lets us flush buffered
+/// telemetry once an invocation has been answered, and finish any in-flight work before the
+/// execution environment shuts down.
+#[derive(Default)]
+pub struct Lifecycle {
+    started: Option<Instant>,
+    in_flight: AtomicUsize,
+    /// Notified whenever an invocation finishes.
+    settled: Notify,
+    invocations: AtomicU64,
+    finished: AtomicU64,
+    errors: AtomicU64,
+    /// Whether the extension is handling events, and so runs the after-invocation hooks.
+    registered: AtomicBool,
+    after_invocation: Mutex<Vec<Hook>>,
+    hooks: Mutex<Vec<Hook>>,
+}
+
+/// Marks an invocation as in-flight for as long as it's alive.
+pub struct InFlight<'a>(&'a Lifecycle);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.finished.fetch_add(1, Ordering::SeqCst);
+        self.0.settled.notify_waiters();
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Lifecycle { started: Some(Instant::now()), ..Default::default() }
+    }
+
+    /// Track an invocation, until the returned guard is dropped.
+    pub fn start_invocation(&self) -> InFlight<'_> {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Register a hook that runs after each invocation, e.g. to flush buffered telemetry. With the
+    /// extension registered these run once the response has gone out, while Lambda waits for the
+    /// extension before freezing the execution environment.
+    pub fn after_invocation<F>(&self, hook: F)
+    where
+        F: Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.after_invocation.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Whether the extension runs the after-invocation hooks. If it doesn't, e.g. because it's
+    /// disabled, the invocation has to run them itself before returning.
+    pub fn is_registered(&self) -> bool {
+        self.registered.load(Ordering::SeqCst)
+    }
+
+    pub async fn run_after_invocation(&self) {
+        let hooks: Vec<_> =
+            self.after_invocation.lock().unwrap().iter().map(|hook| hook()).collect();
+        futures::future::join_all(hooks).await;
+    }
+
+    /// Wait until `count` invocations have finished. The extension can get the INVOKE event
+    /// before or after the runtime handles the invocation, so it counts them instead of waiting
+    /// for the next one to finish.
+    async fn finished(&self, count: u64) {
+        loop {
+            // Create the notification before checking, so we can't miss the invocation
+            // finishing in between.
+            let settled = self.settled.notified();
+            if self.finished.load(Ordering::SeqCst) >= count {
+                break;
+            }
+            settled.await;
+        }
+    }
+
+    /// Register a hook that runs when the execution environment shuts down, e.g. to flush
+    /// buffered telemetry.
+    pub fn on_shutdown<F>(&self, hook: F)
+    where
+        F: Fn() -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Wait for in-flight invocations to finish, run the shutdown hooks, and print a summary of
+    /// the execution environment's lifetime.
+    async fn shutdown(&self) {
+        let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            loop {
+                let settled = self.settled.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                settled.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Shutting down with {} invocation(s) in flight",
+                self.in_flight.load(Ordering::SeqCst)
+            );
+        }
+
+        let hooks: Vec<_> = self.hooks.lock().unwrap().iter().map(|hook| hook()).collect();
+        futures::future::join_all(hooks).await;
+
+        println!(
+            "{}",
+            json!({
+                "message": "shutdown",
+                "reason": "sigterm",
+                "uptimeMs": self.started.map(|started| started.elapsed().as_millis() as u64),
+                "invocations": self.invocations.load(Ordering::Relaxed),
+                "errors": self.errors.load(Ordering::Relaxed),
+            })
+        );
+    }
+}
+
+/// An internal extension registered with the Extensions API.
+struct Extension {
+    client: Client,
+    base_url: String,
+    id: String,
+}
+
+impl Extension {
+    async fn register(runtime_api: &str) -> Result<Self, Error> {
+        let base_url = format!("http://{runtime_api}/2020-01-01/extension");
+        let client = Client::new();
+        let response = client
+            .post(format!("{base_url}/register"))
+            .header("Lambda-Extension-Name", EXTENSION_NAME)
+            .json(&json!({ "events": ["INVOKE"] }))
+            .send()
+            .await?
+            .error_for_status()?;
+        let id = response
+            .headers()
+            .get(EXTENSION_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .ok_or("missing extension identifier in register response")?
+            .to_string();
+        Ok(Extension { client, base_url, id })
+    }
+
+    /// Wait for the next event. Internal extensions can only subscribe to INVOKE events, so
+    /// there is nothing in it we need.
+    async fn next_event(&self) -> Result<(), reqwest::Error> {
+        self.client
+            .get(format!("{}/event/next", self.base_url))
+            .header(EXTENSION_ID_HEADER, &self.id)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Handle events until the Extensions API fails us. Lambda doesn't freeze the
+    /// execution environment until the extension asks for its next event, so for each INVOKE we
+    /// wait for the invocation to be answered and then run the after-invocation hooks, which
+    /// keeps them off the response's path.
+    async fn run(&self, lifecycle: &Lifecycle) -> reqwest::Error {
+        let mut invocations = 0;
+        loop {
+            if let Err(e) = self.next_event().await {
+                return e;
+            }
+            invocations += 1;
+            lifecycle.finished(invocations).await;
+            lifecycle.run_after_invocation().await;
+        }
+    }
+}
+
+/// Register an internal extension with the Extensions API, and handle its events in the
+/// background. Registering an extension is also what makes Lambda send the runtime a SIGTERM
+/// before shutting down the execution environment, which is how internal extensions learn about
+/// the shutdown since only external extensions can subscribe to the SHUTDOWN event.
+///
+/// The extension is enabled by default, and can be disabled by setting
+/// `APOLLO_ROUTER_LAMBDA_EXTENSION=false`. See more at
+/// https://docs.aws.amazon.com/lambda/latest/dg/runtimes-extensions-api.html.
+pub async fn register(lifecycle: Arc<Lifecycle>) -> Result<(), Error> {
+    if env::var("APOLLO_ROUTER_LAMBDA_EXTENSION").is_ok_and(|enabled| enabled == "false") {
+        return Ok(());
+    }
+    let extension = Extension::register(&env::var("AWS_LAMBDA_RUNTIME_API")?).await?;
+    lifecycle.registered.store(true, Ordering::SeqCst);
+
+    // Lambda won't finish the init phase until the extension asks for its next event, so this
+    // needs to run alongside the runtime rather than before it.
+    let events_lifecycle = Arc::clone(&lifecycle);
+    tokio::spawn(async move {
+        let e = extension.run(&events_lifecycle).await;
+        warn!("Failed to get the next extension event: {}", e);
+        // Fall back to running the after-invocation hooks in the invocations.
+        events_lifecycle.registered.store(false, Ordering::SeqCst);
+    });
+
+    let sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        shutdown_on(sigterm, &lifecycle).await;
+        std::process::exit(0);
+    });
+
+    Ok(())
+}
+
+/// Wait for the SIGTERM that Lambda sends before shutting down the execution environment, then
+/// finish in-flight invocations and run the shutdown hooks.
+async fn shutdown_on(mut sigterm: Signal, lifecycle: &Lifecycle) {
+    sigterm.recv().await;
+    lifecycle.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+
+    /// A stand-in for the Extensions API, which hands out the given events in order and records
+    /// the requests it gets. Once the events run out it fails, as it would if the runtime API
+    /// went away.
+    fn extensions_api(events: Vec<serde_json::Value>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let events = Arc::new(Mutex::new(events.into_iter()));
+        let recorded = Arc::clone(&requests);
+        let make_service = make_service_fn(move |_| {
+            let requests = Arc::clone(&recorded);
+            let events = Arc::clone(&events);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let requests = Arc::clone(&requests);
+                    let events = Arc::clone(&events);
+                    async move {
+                        let id = request.headers().get(EXTENSION_ID_HEADER).cloned();
+                        let path = request.uri().path().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        requests.lock().unwrap().push(format!(
+                            "{path} {} {}",
+                            id.as_ref().map_or("-", |id| id.to_str().unwrap()),
+                            String::from_utf8_lossy(&body)
+                        ));
+                        let response = match path.as_str() {
+                            "/2020-01-01/extension/register" => Response::builder()
+                                .header(EXTENSION_ID_HEADER, "extension-id")
+                                .body(Body::from("{}")),
+                            "/2020-01-01/extension/event/next" => {
+                                match events.lock().unwrap().next() {
+                                    Some(event) => {
+                                        Response::builder().body(Body::from(event.to_string()))
+                                    }
+                                    None => Response::builder().status(500).body(Body::empty()),
+                                }
+                            }
+                            _ => Response::builder().status(404).body(Body::empty()),
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr().to_string();
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    fn counter(lifecycle: &Lifecycle, after_invocation: bool) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let hook_count = Arc::clone(&count);
+        let hook = move || {
+            hook_count.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async {}) as BoxFuture<'static, ()>
+        };
+        if after_invocation {
+            lifecycle.after_invocation(hook);
+        } else {
+            lifecycle.on_shutdown(hook);
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn runs_the_hooks_after_each_invocation() {
+        let (address, requests) = extensions_api(vec![
+            json!({ "eventType": "INVOKE", "requestId": "1", "deadlineMs": 0 }),
+            json!({ "eventType": "INVOKE", "requestId": "2", "deadlineMs": 0 }),
+        ]);
+        let lifecycle = Arc::new(Lifecycle::new());
+        let flushes = counter(&lifecycle, true);
+        let shutdowns = counter(&lifecycle, false);
+
+        let extension = Extension::register(&address).await.unwrap();
+        // The first invocation is answered before the extension gets its INVOKE event, while
+        // the second one is still in flight when it does.
+        drop(lifecycle.start_invocation());
+        let in_flight = lifecycle.start_invocation();
+        let events_lifecycle = Arc::clone(&lifecycle);
+        let run = tokio::spawn(async move { extension.run(&events_lifecycle).await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        drop(in_flight);
+
+        let error = run.await.unwrap();
+        assert_eq!(error.status().map(|status| status.as_u16()), Some(500));
+        assert_eq!(flushes.load(Ordering::SeqCst), 2);
+        assert_eq!(shutdowns.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                r#"/2020-01-01/extension/register - {"events":["INVOKE"]}"#,
+                "/2020-01-01/extension/event/next extension-id ",
+                "/2020-01-01/extension/event/next extension-id ",
+                "/2020-01-01/extension/event/next extension-id ",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finishes_in_flight_invocations_and_flushes_on_sigterm() {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let flushes = counter(&lifecycle, true);
+        let shutdowns = counter(&lifecycle, false);
+        let in_flight = lifecycle.start_invocation();
+
+        // Listening replaces the default action of SIGTERM, so this doesn't end the test run.
+        let sigterm = signal(SignalKind::terminate()).unwrap();
+        let shutdown_lifecycle = Arc::clone(&lifecycle);
+        let shutdown = tokio::spawn(async move { shutdown_on(sigterm, &shutdown_lifecycle).await });
+        let killed = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+
+        // The shutdown hooks wait for the invocation that is still in flight.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!shutdown.is_finished());
+        assert_eq!(shutdowns.load(Ordering::SeqCst), 0);
+        drop(in_flight);
+
+        shutdown.await.unwrap();
+        assert_eq!(shutdowns.load(Ordering::SeqCst), 1);
+        assert_eq!(flushes.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn runs_the_hooks_inline_without_the_extension() {
+        let lifecycle = Lifecycle::new();
+        let flushes = counter(&lifecycle, true);
+        assert!(!lifecycle.is_registered());
+        lifecycle.run_after_invocation().await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//! The bootstrap of the Lambda, as a library, so that teams can ship their own bootstrap with
+//! their own Rust plugins and tower layers, see [`Bootstrap`].
+//!
+//! Everything but the modules that `lambda-directly` shares is behind the `full` feature, which
+//! is on by default, so that `lambda-directly` doesn't build the AWS SDKs and the OpenTelemetry
+//! exporter that only the bootstrap uses.
+// Without `full`, the shared modules don't use everything in the modules they depend on.
+#![cfg_attr(not(feature = "full"), allow(dead_code))]
+
+// The modules that `lambda-directly` shares are public, but hidden from the docs since they're
+// not meant for bootstraps of teams.
+#[cfg(feature = "full")]
+mod appsync;
+mod ast;
+mod auth;
+mod authorizer;
+#[cfg(feature = "full")]
+mod bootstrap;
+#[cfg(feature = "full")]
+mod cache;
+#[cfg(feature = "full")]
+mod contracts;
+#[cfg(feature = "full")]
+mod coprocessor;
+#[doc(hidden)]
+pub mod cors;
+#[cfg(feature = "full")]
+mod direct;
+#[doc(hidden)]
+pub mod entities;
+#[doc(hidden)]
+pub mod event;
+#[cfg(feature = "full")]
+mod extension;
+#[cfg(feature = "full")]
+mod limits;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod metrics;
+#[cfg(feature = "full")]
+mod plans;
+#[cfg(feature = "full")]
+mod queue;
+#[cfg(feature = "full")]
+mod ratelimit;
+#[doc(hidden)]
+pub mod rhai;
+#[cfg(feature = "full")]
+mod telemetry;
+#[cfg(feature = "full")]
+mod tenants;
+#[cfg(feature = "full")]
+mod usage;
+#[cfg(feature = "full")]
+mod xray;
+
+#[cfg(feature = "full")]
+pub use bootstrap::Bootstrap;
+// The event sources and the plan cache run operations the way HTTP requests do.
+#[cfg(feature = "full")]
+use bootstrap::{call_supergraph, execute};
@@ -0,0 +1,501 @@
+use crate::ast;
+use apollo_router::graphql;
+use graphql_parser::query::{
+    Definition, FragmentDefinition, Selection, SelectionSet, TypeCondition, Value,
+};
+use graphql_parser::schema::{self, TypeDefinition, TypeExtension};
+use lambda_runtime::Error;
+use reqwest::StatusCode;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// The arguments that tell us how many items a list field returns.
+const LIST_SIZE_ARGUMENTS: [&str; 3] = ["first", "last", "limit"];
+
+/// The limits of the default supergraph, while tenants have their own.
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Limits on the shape of an operation, checked before it reaches the supergraph, since a single
+/// deeply nested operation can keep the Lambda busy (and billed) for a long time. Each limit is
+/// only enforced if it's set:
+///
+/// - `APOLLO_ROUTER_MAX_DEPTH`: how deeply fields can be nested, with root fields at depth 1.
+/// - `APOLLO_ROUTER_MAX_ALIASES`: how many aliased fields an operation can have.
+/// - `APOLLO_ROUTER_MAX_ROOT_FIELDS`: how many fields an operation can select at its root.
+/// - `APOLLO_ROUTER_MAX_COST`: the maximum cost score of an operation.
+///
+/// Introspection fields aren't counted, since the Router answers them without the subgraphs. The
+/// cost depends on the schema, so every supergraph that the function serves has its own limits.
+pub struct Limits {
+    max_depth: Option<u64>,
+    max_aliases: Option<u64>,
+    max_root_fields: Option<u64>,
+    max_cost: Option<u64>,
+    schema: CostSchema,
+}
+
+/// What we need to know about the supergraph to compute the cost of an operation.
+///
+/// Every field that returns an object, interface or union costs 1, while scalars and enums are
+/// free. A field can be given a different weight with `@cost(weight: 5)` in the schema, or with
+/// `APOLLO_ROUTER_FIELD_COSTS`, a comma-separated list like `Query.products=10`. The cost of
+/// the selections of a list field is multiplied by its `first`, `last` or `limit` argument.
+#[derive(Default)]
+struct CostSchema {
+    // Keyed by `(parent type name, field name)`.
+    fields: HashMap<(String, String), FieldInfo>,
+    composite_types: HashSet<String>,
+    roots: HashMap<&'static str, String>,
+}
+
+struct FieldInfo {
+    type_name: String,
+    is_list: bool,
+    weight: Option<u64>,
+}
+
+impl CostSchema {
+    fn from_schema(schema: &str, field_costs: Option<&str>) -> Result<Self, Error> {
+        let document = graphql_parser::parse_schema::<String>(schema)
+            .map_err(|e| format!("failed to parse the supergraph schema: {e}"))?;
+        let mut cost_schema = CostSchema::default();
+        for (root, name) in
+            [("query", "Query"), ("mutation", "Mutation"), ("subscription", "Subscription")]
+        {
+            cost_schema.roots.insert(root, name.to_string());
+        }
+        for definition in document.definitions {
+            let (name, fields) = match definition {
+                schema::Definition::SchemaDefinition(schema) => {
+                    for (root, name) in [
+                        ("query", schema.query),
+                        ("mutation", schema.mutation),
+                        ("subscription", schema.subscription),
+                    ] {
+                        if let Some(name) = name {
+                            cost_schema.roots.insert(root, name);
+                        }
+                    }
+                    continue;
+                }
+                schema::Definition::TypeDefinition(TypeDefinition::Object(object)) => {
+                    (object.name, object.fields)
+                }
+                schema::Definition::TypeDefinition(TypeDefinition::Interface(interface)) => {
+                    (interface.name, interface.fields)
+                }
+                schema::Definition::TypeDefinition(TypeDefinition::Union(union)) => {
+                    (union.name, Vec::new())
+                }
+                schema::Definition::TypeExtension(TypeExtension::Object(object)) => {
+                    (object.name, object.fields)
+                }
+                schema::Definition::TypeExtension(TypeExtension::Interface(interface)) => {
+                    (interface.name, interface.fields)
+                }
+                _ => continue,
+            };
+            for field in fields {
+                let weight = field
+                    .directives
+                    .iter()
+                    .filter(|directive| directive.name == "cost")
+                    .flat_map(|directive| &directive.arguments)
+                    .find_map(|(argument, value)| match (argument.as_str(), value) {
+                        ("weight", schema::Value::Int(weight)) => {
+                            weight.as_i64().and_then(|weight| u64::try_from(weight).ok())
+                        }
+                        _ => None,
+                    });
+                let info = FieldInfo {
+                    type_name: ast::named_type(&field.field_type),
+                    is_list: is_list(&field.field_type),
+                    weight,
+                };
+                cost_schema.fields.insert((name.clone(), field.name), info);
+            }
+            cost_schema.composite_types.insert(name);
+        }
+
+        if let Some(costs) = field_costs {
+            for cost in costs.split(',').filter(|cost| !cost.trim().is_empty()) {
+                let invalid = || format!("invalid field cost {cost}, expected Type.field=weight");
+                let (coordinate, weight) = cost.trim().split_once('=').ok_or_else(invalid)?;
+                let (type_name, field) = coordinate.split_once('.').ok_or_else(invalid)?;
+                let weight = weight.parse().map_err(|_| invalid())?;
+                // Tenants can serve supergraphs of their own, which may not have every field.
+                match cost_schema.fields.get_mut(&(type_name.to_string(), field.to_string())) {
+                    Some(info) => info.weight = Some(weight),
+                    None => {
+                        warn!("Ignoring the cost of {}, which isn't in the supergraph", coordinate)
+                    }
+                }
+            }
+        }
+        Ok(cost_schema)
+    }
+}
+
+fn is_list(field_type: &schema::Type<'_, String>) -> bool {
+    match field_type {
+        schema::Type::NamedType(_) => false,
+        schema::Type::ListType(_) => true,
+        schema::Type::NonNullType(inner) => is_list(inner),
+    }
+}
+
+/// Set up the limits of the default supergraph, if any of them are configured.
+pub fn init(schema: &str) -> Result<(), Error> {
+    if let Some(limits) = Limits::from_env(schema)? {
+        let _ = LIMITS.set(limits);
+    }
+    Ok(())
+}
+
+/// The limits of the default supergraph, if any of them are configured.
+pub fn default_limits() -> Option<&'static Limits> {
+    LIMITS.get()
+}
+
+impl Limits {
+    /// The limits for a supergraph, if any of them are configured.
+    pub fn from_env(schema: &str) -> Result<Option<Self>, Error> {
+        Self::from_vars(schema, |name| env::var(name).ok())
+    }
+
+    fn from_vars(
+        schema: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, Error> {
+        let limit = |name: &str| -> Result<Option<u64>, Error> {
+            match var(name) {
+                Some(limit) => {
+                    Ok(Some(limit.parse().map_err(|_| format!("{name} must be a number"))?))
+                }
+                None => Ok(None),
+            }
+        };
+        let max_depth = limit("APOLLO_ROUTER_MAX_DEPTH")?;
+        let max_aliases = limit("APOLLO_ROUTER_MAX_ALIASES")?;
+        let max_root_fields = limit("APOLLO_ROUTER_MAX_ROOT_FIELDS")?;
+        let max_cost = limit("APOLLO_ROUTER_MAX_COST")?;
+        if max_depth.is_none()
+            && max_aliases.is_none()
+            && max_root_fields.is_none()
+            && max_cost.is_none()
+        {
+            return Ok(None);
+        }
+        let field_costs = var("APOLLO_ROUTER_FIELD_COSTS");
+        let schema = CostSchema::from_schema(schema, field_costs.as_deref())?;
+        Ok(Some(Limits { max_depth, max_aliases, max_root_fields, max_cost, schema }))
+    }
+}
+
+/// The shape of a selection set, or of a whole operation.
+#[derive(Debug, Clone, Copy, Default)]
+struct Measure {
+    depth: u64,
+    aliases: u64,
+    root_fields: u64,
+    cost: u64,
+}
+
+/// Check an operation against the limits of its supergraph, returning the response to send
+/// instead if it exceeds any of them. Operations that can't be parsed are left for the supergraph
+/// to reject.
+pub fn check(
+    limits: Option<&Limits>,
+    request: &graphql::Request,
+) -> Result<(), (StatusCode, serde_json::Value)> {
+    let Some(limits) = limits else {
+        return Ok(());
+    };
+    let Some(query) = request.query.as_deref() else {
+        return Ok(());
+    };
+    let variables = serde_json::to_value(&request.variables).unwrap_or_default();
+    let Some(measure) =
+        measure(&limits.schema, query, request.operation_name.as_deref(), &variables)
+    else {
+        return Ok(());
+    };
+
+    let errors: Vec<serde_json::Value> = [
+        ("depth", measure.depth, limits.max_depth, "MAX_DEPTH_LIMIT"),
+        ("number of aliases", measure.aliases, limits.max_aliases, "MAX_ALIASES_LIMIT"),
+        (
+            "number of root fields",
+            measure.root_fields,
+            limits.max_root_fields,
+            "MAX_ROOT_FIELDS_LIMIT",
+        ),
+        ("cost", measure.cost, limits.max_cost, "MAX_COST_LIMIT"),
+    ]
+    .into_iter()
+    .filter_map(|(name, value, limit, code)| {
+        let limit = limit.filter(|limit| value > *limit)?;
+        Some(json!({
+            "message": format!("the operation's {name} of {value} exceeds the limit of {limit}"),
+            "extensions": {
+                "code": code,
+                "depth": measure.depth,
+                "aliases": measure.aliases,
+                "rootFields": measure.root_fields,
+                "cost": measure.cost,
+            },
+        }))
+    })
+    .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err((StatusCode::BAD_REQUEST, json!({ "errors": errors })))
+    }
+}
+
+fn measure(
+    schema: &CostSchema,
+    query: &str,
+    operation_name: Option<&str>,
+    variables: &serde_json::Value,
+) -> Option<Measure> {
+    let document = graphql_parser::parse_query::<String>(query).ok()?;
+    let fragments: HashMap<&str, &FragmentDefinition<'_, String>> = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name.as_str(), fragment)),
+            _ => None,
+        })
+        .collect();
+    let operation = ast::select_operation(&document, operation_name)?;
+    let (root, selection_set) = ast::root_selection_set(operation);
+
+    let mut walker = Walker { schema, fragments, variables, measured: HashMap::new() };
+    let root_type = schema.roots.get(root)?;
+    Some(walker.selection_set(root_type, selection_set, &mut Vec::new()))
+}
+
+/// Walks the selections of an operation. Fragments are measured once and then reused, so that an
+/// operation spreading the same fragment many times doesn't take exponentially long to check.
+struct Walker<'a> {
+    schema: &'a CostSchema,
+    fragments: HashMap<&'a str, &'a FragmentDefinition<'a, String>>,
+    variables: &'a serde_json::Value,
+    measured: HashMap<&'a str, Measure>,
+}
+
+impl<'a> Walker<'a> {
+    fn selection_set(
+        &mut self,
+        parent: &str,
+        selection_set: &'a SelectionSet<'a, String>,
+        visiting: &mut Vec<&'a str>,
+    ) -> Measure {
+        let mut measure = Measure::default();
+        for selection in &selection_set.items {
+            let child = match selection {
+                Selection::Field(field) => {
+                    if field.name.starts_with("__") {
+                        continue;
+                    }
+                    let info = self.schema.fields.get(&(parent.to_string(), field.name.clone()));
+                    let type_name = info.map(|info| info.type_name.as_str()).unwrap_or_default();
+                    let selections = self.selection_set(type_name, &field.selection_set, visiting);
+                    let default_weight = u64::from(self.schema.composite_types.contains(type_name));
+                    let weight = info.and_then(|info| info.weight).unwrap_or(default_weight);
+                    let multiplier = match info {
+                        Some(info) if info.is_list => self.list_size(&field.arguments),
+                        _ => 1,
+                    };
+                    Measure {
+                        depth: selections.depth + 1,
+                        aliases: selections.aliases + u64::from(field.alias.is_some()),
+                        root_fields: 1,
+                        cost: weight.saturating_add(selections.cost.saturating_mul(multiplier)),
+                    }
+                }
+                Selection::InlineFragment(fragment) => {
+                    let parent = match &fragment.type_condition {
+                        Some(TypeCondition::On(type_name)) => type_name.as_str(),
+                        None => parent,
+                    };
+                    self.selection_set(parent, &fragment.selection_set, visiting)
+                }
+                Selection::FragmentSpread(spread) => {
+                    self.fragment_spread(&spread.fragment_name, visiting).unwrap_or_default()
+                }
+            };
+            measure.depth = measure.depth.max(child.depth);
+            measure.aliases = measure.aliases.saturating_add(child.aliases);
+            measure.root_fields = measure.root_fields.saturating_add(child.root_fields);
+            measure.cost = measure.cost.saturating_add(child.cost);
+        }
+        measure
+    }
+
+    fn fragment_spread(&mut self, name: &str, visiting: &mut Vec<&'a str>) -> Option<Measure> {
+        let (name, fragment) = self.fragments.get_key_value(name)?;
+        let (name, fragment) = (*name, *fragment);
+        if let Some(measure) = self.measured.get(name) {
+            return Some(*measure);
+        }
+        // Fragments can't be recursive in a valid operation, but we guard against it since we
+        // haven't validated the operation yet.
+        if visiting.contains(&name) {
+            return None;
+        }
+        visiting.push(name);
+        let TypeCondition::On(parent) = &fragment.type_condition;
+        let measure = self.selection_set(parent, &fragment.selection_set, visiting);
+        visiting.pop();
+        self.measured.insert(name, measure);
+        Some(measure)
+    }
+
+    /// How many items a list field asks for, either as a literal or through a variable.
+    fn list_size(&self, arguments: &[(String, Value<'a, String>)]) -> u64 {
+        arguments
+            .iter()
+            .filter(|(name, _)| LIST_SIZE_ARGUMENTS.contains(&name.as_str()))
+            .find_map(|(_, value)| match value {
+                Value::Int(size) => size.as_i64().and_then(|size| u64::try_from(size).ok()),
+                Value::Variable(variable) => self.variables.get(variable)?.as_u64(),
+                _ => None,
+            })
+            .unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        type Query {
+            me: User
+            products(first: Int): [Product]
+            search(limit: Int): [Product] @cost(weight: 3)
+        }
+        type User { name: String friends(first: Int): [User] }
+        type Product { upc: String reviews(first: Int): [Review] }
+        type Review { body: String author: User }
+    "#;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn measure_of(query: &str, variables: serde_json::Value) -> Measure {
+        let schema = CostSchema::from_schema(SCHEMA, Some("Product.reviews=2")).unwrap();
+        measure(&schema, query, None, &variables).unwrap()
+    }
+
+    fn request(query: &str) -> graphql::Request {
+        serde_json::from_value(json!({ "query": query })).unwrap()
+    }
+
+    #[test]
+    fn measures_depth_aliases_and_root_fields() {
+        let measure = measure_of(
+            "{ me { friends { friends { name } } } other: me { name } __typename }",
+            json!({}),
+        );
+        assert_eq!(measure.depth, 4);
+        assert_eq!(measure.aliases, 1);
+        assert_eq!(measure.root_fields, 2);
+    }
+
+    #[test]
+    fn weighs_fields_by_the_schema_and_the_configured_costs() {
+        // Objects cost 1 and scalars are free.
+        assert_eq!(measure_of("{ me { name } }", json!({})).cost, 1);
+        // `@cost` in the schema.
+        assert_eq!(measure_of("{ search { upc } }", json!({})).cost, 3);
+        // `APOLLO_ROUTER_FIELD_COSTS`.
+        assert_eq!(measure_of("{ products { reviews { body } } }", json!({})).cost, 3);
+    }
+
+    #[test]
+    fn multiplies_the_cost_of_lists_by_their_size() {
+        assert_eq!(measure_of("{ products(first: 10) { upc } }", json!({})).cost, 1);
+        assert_eq!(measure_of("{ products(first: 10) { reviews { body } } }", json!({})).cost, 21);
+        assert_eq!(
+            measure_of(
+                "query($n: Int) { products(first: $n) { reviews(first: 5) { author { name } } } }",
+                json!({ "n": 4 }),
+            )
+            .cost,
+            // 1 + 4 * (2 + 5 * 1)
+            29
+        );
+    }
+
+    #[test]
+    fn measures_fragment_spreads() {
+        let measure = measure_of(
+            "{ me { ...Friend friends { ...Friend } } }
+             fragment Friend on User { alias: friends { name } }",
+            json!({}),
+        );
+        assert_eq!(measure.depth, 4);
+        assert_eq!(measure.aliases, 2);
+        assert_eq!(measure.cost, 4);
+        // A recursive fragment is cut short instead of overflowing the stack.
+        let measure = measure_of(
+            "{ me { ...Loop } } fragment Loop on User { friends { ...Loop } }",
+            json!({}),
+        );
+        assert_eq!(measure.depth, 2);
+    }
+
+    #[test]
+    fn reports_every_exceeded_limit() {
+        let limits = Limits::from_vars(
+            SCHEMA,
+            vars(&[("APOLLO_ROUTER_MAX_DEPTH", "2"), ("APOLLO_ROUTER_MAX_COST", "2")]),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(check(Some(&limits), &request("{ me { name } }")).is_ok());
+        let (status, body) =
+            check(Some(&limits), &request("{ me { friends { friends { name } } } }")).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let codes: Vec<_> =
+            body["errors"].as_array().unwrap().iter().map(|e| &e["extensions"]["code"]).collect();
+        assert_eq!(codes, [&json!("MAX_DEPTH_LIMIT"), &json!("MAX_COST_LIMIT")]);
+        // Without limits, or with an operation we can't parse, the supergraph decides.
+        assert!(check(None, &request("{ me { friends { friends { name } } } }")).is_ok());
+        assert!(check(Some(&limits), &request("{ me {")).is_ok());
+    }
+
+    #[test]
+    fn reads_the_limits_from_the_environment() {
+        assert!(Limits::from_vars(SCHEMA, vars(&[])).unwrap().is_none());
+        assert!(Limits::from_vars(SCHEMA, vars(&[("APOLLO_ROUTER_MAX_DEPTH", "ten")])).is_err());
+        assert!(Limits::from_vars(
+            SCHEMA,
+            vars(&[("APOLLO_ROUTER_MAX_COST", "10"), ("APOLLO_ROUTER_FIELD_COSTS", "Query.me")]),
+        )
+        .is_err());
+        // Fields that aren't in this supergraph are ignored, since tenants share the variables.
+        let limits = Limits::from_vars(
+            SCHEMA,
+            vars(&[
+                ("APOLLO_ROUTER_MAX_ALIASES", "1"),
+                ("APOLLO_ROUTER_FIELD_COSTS", "Query.me=5, Query.missing=2"),
+            ]),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(limits.max_aliases, Some(1));
+        assert_eq!(limits.max_depth, None);
+        assert_eq!(limits.schema.fields[&("Query".to_string(), "me".to_string())].weight, Some(5));
+    }
+}
@@ -0,0 +1,293 @@
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// The request ID of the invocation we are currently handling. The Lambda only ever handles one
+/// invocation at a time, so there's no need to tell invocations apart.
+static REQUEST_ID: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_request_id(request_id: Option<&str>) {
+    *REQUEST_ID.lock().unwrap() = request_id.map(str::to_string);
+}
+
+/// The log layer, which writes one JSON object per line by default, or plain text if the
+/// function is configured with `AWS_LAMBDA_LOG_FORMAT=Text`.
+///
+/// The level is taken from `RUST_LOG`, which supports the full `EnvFilter` syntax, or otherwise
+/// from the function's `AWS_LAMBDA_LOG_LEVEL`, and defaults to WARN.
+pub fn layer<S>() -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level(env::var("AWS_LAMBDA_LOG_LEVEL").ok())));
+    let layer = tracing_subscriber::fmt::layer()
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        // disable coloring.
+        .with_ansi(false);
+    if env::var("AWS_LAMBDA_LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("text")) {
+        layer.with_filter(filter).boxed()
+    } else {
+        layer.event_format(JsonFormat).with_filter(filter).boxed()
+    }
+}
+
+/// The filter for a Lambda log level. Operations are logged at INFO, but since they're what the
+/// logs are for, they're still logged at WARN, the default, and only left out at ERROR.
+fn level(lambda_level: Option<String>) -> String {
+    let level = match lambda_level.map(|level| level.to_uppercase()).as_deref() {
+        Some("TRACE") => "trace",
+        Some("DEBUG") => "debug",
+        Some("INFO") => "info",
+        Some("ERROR") | Some("FATAL") => "error",
+        _ => return format!("warn,{}=info", module_path!()),
+    };
+    level.to_string()
+}
+
+/// Formats an event as a single line of JSON with the level, the request ID of the invocation,
+/// and the event's fields.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = Map::new();
+        line.insert("level".to_string(), json!(event.metadata().level().as_str()));
+        if let Some(request_id) = REQUEST_ID.lock().unwrap().as_ref() {
+            line.insert("requestId".to_string(), json!(request_id));
+        }
+        event.record(&mut JsonVisitor(&mut line));
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), json_or_string(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json_or_string(value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+}
+
+/// Keep JSON values, such as the redacted variables, as JSON rather than as a string.
+fn json_or_string(value: String) -> Value {
+    if value.starts_with(['{', '[']) {
+        serde_json::from_str(&value).unwrap_or(Value::String(value))
+    } else {
+        Value::String(value)
+    }
+}
+
+/// How much of the variables or the response data ends up in the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Redaction {
+    /// Leave them out entirely.
+    Omit,
+    /// Keep their shape, but replace every value with `"[REDACTED]"`.
+    Redact,
+    /// Log them as they are, which may leak personal data into the logs.
+    Full,
+}
+
+impl Redaction {
+    fn from_env(var: &str) -> Self {
+        Self::parse(env::var(var).ok().as_deref())
+    }
+
+    fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("omit") => Redaction::Omit,
+            Some("full") => Redaction::Full,
+            _ => Redaction::Redact,
+        }
+    }
+
+    fn apply(self, value: &Value) -> Option<Value> {
+        match self {
+            Redaction::Omit => None,
+            Redaction::Redact => Some(redact(value)),
+            Redaction::Full => Some(value.clone()),
+        }
+    }
+}
+
+fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            Value::Object(map.iter().map(|(key, value)| (key.clone(), redact(value))).collect())
+        }
+        Value::Array(values) => Value::Array(values.iter().map(redact).collect()),
+        Value::Null => Value::Null,
+        _ => json!("[REDACTED]"),
+    }
+}
+
+/// The hash of an operation, which identifies it in the logs without logging the whole query.
+pub fn operation_hash(query: Option<&str>) -> String {
+    hex::encode(Sha256::digest(query.unwrap_or_default().as_bytes()))
+}
+
+/// Log a finished operation at INFO, which the default WARN level lets through. The variables and the response data are redacted unless
+/// `APOLLO_ROUTER_LOG_VARIABLES` or `APOLLO_ROUTER_LOG_RESPONSE_DATA` say otherwise, which can
+/// be set to `omit`, `redact` (the default) or `full`. GraphQL errors are always logged.
+pub fn operation(
+    request: &apollo_router::graphql::Request,
+    duration: Duration,
+    status: Option<StatusCode>,
+    response: Option<&Value>,
+) {
+    let variables = serde_json::to_value(&request.variables).unwrap_or_default();
+    let variables = Redaction::from_env("APOLLO_ROUTER_LOG_VARIABLES").apply(&variables);
+    let data = response.and_then(|response| response.get("data"));
+    let data =
+        data.and_then(|data| Redaction::from_env("APOLLO_ROUTER_LOG_RESPONSE_DATA").apply(data));
+    let errors = response.and_then(|response| response.get("errors"));
+    info!(
+        operation_name = request.operation_name.as_deref().unwrap_or("anonymous"),
+        operation_hash = operation_hash(request.query.as_deref()),
+        duration_ms = duration.as_secs_f64() * 1000.0,
+        status = status.map(|status| status.as_u16()),
+        variables = variables.map(|v| v.to_string()),
+        data = data.map(|d| d.to_string()),
+        errors = errors.map(|e| e.to_string()),
+        "operation finished"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collects what the layer writes, so that tests can read the log lines back.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Lines {
+        type Writer = Lines;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// Log an operation with the filter of a Lambda log level, returning the lines written.
+    fn log_operation(lambda_level: Option<&str>) -> Vec<Value> {
+        let lines = Lines::default();
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(lines.clone())
+            .event_format(JsonFormat)
+            .with_filter(EnvFilter::new(level(lambda_level.map(str::to_string))));
+        let request = serde_json::from_value(json!({ "query": "{ me { id } }" })).unwrap();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            operation(&request, Duration::from_millis(5), Some(StatusCode::OK), None);
+            tracing::warn!(target: "apollo_router", "unrelated warning");
+            tracing::info!(target: "apollo_router", "unrelated info");
+        });
+        let lines = lines.0.lock().unwrap();
+        String::from_utf8_lossy(&lines).lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn logs_operations_unless_only_errors_are_wanted() {
+        let messages = |lines: Vec<Value>| -> Vec<String> {
+            lines.iter().map(|line| line["message"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(messages(log_operation(None)), ["operation finished", "unrelated warning"]);
+        assert_eq!(
+            messages(log_operation(Some("warn"))),
+            ["operation finished", "unrelated warning"]
+        );
+        assert_eq!(
+            messages(log_operation(Some("INFO"))),
+            ["operation finished", "unrelated warning", "unrelated info"]
+        );
+        assert!(log_operation(Some("ERROR")).is_empty());
+
+        let line = &log_operation(None)[0];
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["operation_name"], "anonymous");
+        assert_eq!(line["operation_hash"], operation_hash(Some("{ me { id } }")));
+        assert_eq!(line["status"], 200);
+    }
+
+    #[test]
+    fn redacts_values_but_keeps_their_shape() {
+        let value =
+            json!({ "me": { "id": "1", "age": 42, "friends": [{ "id": "2" }], "bio": null } });
+        assert_eq!(Redaction::parse(None), Redaction::Redact);
+        assert_eq!(Redaction::parse(Some("redact")), Redaction::Redact);
+        assert_eq!(Redaction::parse(Some("omit")), Redaction::Omit);
+        assert_eq!(Redaction::parse(Some("full")), Redaction::Full);
+        assert_eq!(Redaction::parse(Some("everything")), Redaction::Redact);
+
+        assert_eq!(Redaction::Omit.apply(&value), None);
+        assert_eq!(Redaction::Full.apply(&value), Some(value.clone()));
+        assert_eq!(
+            Redaction::Redact.apply(&value),
+            Some(json!({
+                "me": {
+                    "id": "[REDACTED]",
+                    "age": "[REDACTED]",
+                    "friends": [{ "id": "[REDACTED]" }],
+                    "bio": null,
+                },
+            }))
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use apollo_router::services::subgraph;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tower::{ServiceBuilder, ServiceExt};
+
+const CLIENT_NAME_HEADER: &str = "apollographql-client-name";
+
+/// Only the first operation in an execution environment is part of a cold start.
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// The subgraph fetches of the operation that is currently being executed. The Lambda only ever
+/// executes one operation at a time, so there's no need to tell operations apart.
+static FETCHES: Mutex<Vec<SubgraphFetch>> = Mutex::new(Vec::new());
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Which metrics to emit, configured through the environment:
+///
+/// - `APOLLO_ROUTER_METRICS=false` disables the metrics.
+/// - `APOLLO_ROUTER_METRICS_NAMESPACE` sets the CloudWatch namespace, which defaults to
+///   `ApolloRouterLambda`.
+/// - `APOLLO_ROUTER_METRICS_DIMENSIONS` is a comma-separated list of `OperationName`,
+///   `ClientName` and `Subgraph`, which defaults to `OperationName`.
+struct Config {
+    enabled: bool,
+    namespace: String,
+    dimensions: Vec<String>,
+    per_subgraph: bool,
+}
+
+impl Config {
+    fn get() -> &'static Config {
+        CONFIG.get_or_init(|| {
+            let dimensions: Vec<String> = env::var("APOLLO_ROUTER_METRICS_DIMENSIONS")
+                .unwrap_or("OperationName".to_string())
+                .split(',')
+                .map(|dimension| dimension.trim().to_string())
+                .filter(|dimension| !dimension.is_empty())
+                .collect();
+            Config {
+                enabled: !env::var("APOLLO_ROUTER_METRICS").is_ok_and(|enabled| enabled == "false"),
+                namespace: env::var("APOLLO_ROUTER_METRICS_NAMESPACE")
+                    .unwrap_or("ApolloRouterLambda".to_string()),
+                per_subgraph: dimensions.iter().any(|dimension| dimension == "Subgraph"),
+                dimensions: dimensions
+                    .into_iter()
+                    .filter(|dimension| dimension == "OperationName" || dimension == "ClientName")
+                    .collect(),
+            }
+        })
+    }
+}
+
+struct SubgraphFetch {
+    subgraph: String,
+    latency_ms: f64,
+    failed: bool,
+}
+
+/// Tracks a single GraphQL operation, and emits its metrics once it has finished.
+pub struct Operation {
+    name: String,
+    client_name: String,
+    started: Instant,
+}
+
+impl Operation {
+    pub fn start(operation_name: Option<&str>, headers: &HeaderMap) -> Self {
+        // Forget about the fetches of an earlier operation that never finished.
+        FETCHES.lock().unwrap().clear();
+        let client_name = headers.get(CLIENT_NAME_HEADER).and_then(|name| name.to_str().ok());
+        Operation {
+            name: operation_name.unwrap_or("anonymous").to_string(),
+            client_name: client_name.unwrap_or("unknown").to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Emit the metrics of the operation, and of the subgraph fetches it made.
+    pub fn finish(self, failed: bool) {
+        let fetches = std::mem::take(&mut *FETCHES.lock().unwrap());
+        let config = Config::get();
+        if !config.enabled {
+            return;
+        }
+        let cold_start = COLD_START.swap(false, Ordering::Relaxed);
+        let dimensions = self.dimensions(config);
+
+        let mut values = dimensions.clone();
+        values.insert("Latency".to_string(), json!(self.started.elapsed().as_secs_f64() * 1000.0));
+        values.insert("Errors".to_string(), json!(u8::from(failed)));
+        values.insert("ColdStart".to_string(), json!(u8::from(cold_start)));
+        values.insert("SubgraphFetches".to_string(), json!(fetches.len()));
+        emit(
+            config,
+            &dimensions,
+            &[
+                ("Latency", "Milliseconds"),
+                ("Errors", "Count"),
+                ("ColdStart", "Count"),
+                ("SubgraphFetches", "Count"),
+            ],
+            values,
+        );
+
+        if !config.per_subgraph {
+            return;
+        }
+        let mut subgraphs: Vec<&str> =
+            fetches.iter().map(|fetch| fetch.subgraph.as_str()).collect();
+        subgraphs.sort_unstable();
+        subgraphs.dedup();
+        for subgraph in subgraphs {
+            let fetches: Vec<&SubgraphFetch> =
+                fetches.iter().filter(|fetch| fetch.subgraph == subgraph).collect();
+            let mut dimensions = dimensions.clone();
+            dimensions.insert("Subgraph".to_string(), json!(subgraph));
+            let mut values = dimensions.clone();
+            values.insert("SubgraphFetches".to_string(), json!(fetches.len()));
+            // EMF accepts a list of values, which CloudWatch aggregates for us.
+            let latencies: Vec<f64> = fetches.iter().map(|fetch| fetch.latency_ms).collect();
+            values.insert("SubgraphLatency".to_string(), json!(latencies));
+            let errors = fetches.iter().filter(|fetch| fetch.failed).count();
+            values.insert("SubgraphErrors".to_string(), json!(errors));
+            emit(
+                config,
+                &dimensions,
+                &[
+                    ("SubgraphFetches", "Count"),
+                    ("SubgraphLatency", "Milliseconds"),
+                    ("SubgraphErrors", "Count"),
+                ],
+                values,
+            );
+        }
+    }
+
+    fn dimensions(&self, config: &Config) -> Map<String, Value> {
+        config
+            .dimensions
+            .iter()
+            .map(|dimension| {
+                let value = match dimension.as_str() {
+                    "OperationName" => &self.name,
+                    _ => &self.client_name,
+                };
+                (dimension.clone(), json!(value))
+            })
+            .collect()
+    }
+}
+
+/// Whether an operation failed, which is when its response has an error status or any GraphQL
+/// errors. Every variant counts `Errors` this way, so that they can be compared.
+pub fn failed(status: StatusCode, response: &Value) -> bool {
+    status.as_u16() >= 400
+        || response.get("errors").and_then(Value::as_array).is_some_and(|errors| !errors.is_empty())
+}
+
+/// Times the phases of the init, so we can see where the cold start goes. Each phase runs from
+/// the end of the previous one, and they are emitted together as a single line once the init is
+/// done, with the function version as the dimension so regressions show up per release.
+pub struct InitPhases {
+    started: Instant,
+    last: Instant,
+    phases: Vec<(String, f64)>,
+}
+
+impl InitPhases {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        InitPhases { started: now, last: now, phases: Vec::new() }
+    }
+
+    /// Mark the end of a phase.
+    pub fn end(&mut self, phase: &str) {
+        let now = Instant::now();
+        let duration_ms = now.duration_since(self.last).as_secs_f64() * 1000.0;
+        self.phases.push((format!("Init{phase}"), duration_ms));
+        self.last = now;
+    }
+
+    pub fn finish(mut self) {
+        let config = Config::get();
+        if !config.enabled {
+            return;
+        }
+        let total_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.phases.push(("InitTotal".to_string(), total_ms));
+
+        let mut dimensions = Map::new();
+        let version = env::var("AWS_LAMBDA_FUNCTION_VERSION").unwrap_or("$LATEST".to_string());
+        dimensions.insert("FunctionVersion".to_string(), json!(version));
+        let mut values = dimensions.clone();
+        for (name, duration_ms) in &self.phases {
+            values.insert(name.clone(), json!(duration_ms));
+        }
+        let metrics: Vec<(&str, &str)> =
+            self.phases.iter().map(|(name, _)| (name.as_str(), "Milliseconds")).collect();
+        emit(config, &dimensions, &metrics, values);
+    }
+}
+
+/// Print a line in the CloudWatch Embedded Metric Format, which CloudWatch Logs turns into
+/// metrics, see https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html.
+fn emit(
+    config: &Config,
+    dimensions: &Map<String, Value>,
+    metrics: &[(&str, &str)],
+    mut values: Map<String, Value>,
+) {
+    let timestamp =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let metrics: Vec<Value> =
+        metrics.iter().map(|(name, unit)| json!({ "Name": name, "Unit": unit })).collect();
+    values.insert(
+        "_aws".to_string(),
+        json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": config.namespace,
+                "Dimensions": [dimensions.keys().collect::<Vec<_>>()],
+                "Metrics": metrics,
+            }],
+        }),
+    );
+    println!("{}", Value::Object(values));
+}
+
+/// Count each subgraph fetch and how long it took, towards the metrics of the current operation.
+pub fn instrument_subgraph(name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+    let name = name.to_string();
+    ServiceBuilder::new()
+        .map_future(move |response| {
+            let subgraph = name.clone();
+            let started = Instant::now();
+            async move {
+                let response: Result<subgraph::Response, _> = response.await;
+                let failed = !matches!(&response, Ok(r) if r.response.status().as_u16() < 400);
+                FETCHES.lock().unwrap().push(SubgraphFetch {
+                    subgraph,
+                    latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                    failed,
+                });
+                response
+            }
+        })
+        .service(service)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_error_statuses_and_graphql_errors_as_failures() {
+        assert!(!failed(StatusCode::OK, &json!({ "data": {} })));
+        assert!(!failed(StatusCode::OK, &json!({ "data": {}, "errors": [] })));
+        assert!(failed(StatusCode::OK, &json!({ "data": null, "errors": [{ "message": "no" }] })));
+        assert!(failed(StatusCode::BAD_REQUEST, &json!({ "data": null })));
+        assert!(failed(StatusCode::TOO_MANY_REQUESTS, &json!({})));
+    }
+}
@@ -0,0 +1,394 @@
+use crate::ast;
+use apollo_router::graphql;
+use apollo_router::layers::ServiceBuilderExt;
+use apollo_router::services::{router, subgraph};
+use graphql_parser::query::Definition;
+use lambda_runtime::Error;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::{info, warn};
+
+/// Set while we only want the Router to plan operations, so that the subgraphs are never called.
+static PLANNING_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// The operations to plan during init, generated with `bootstrap warm-plans`. The Router has no
+/// way to load query plans it didn't make itself, so we keep the operations and plan them again
+/// while the Lambda initializes at full CPU, instead of during the first invocations. The plans
+/// only hold for the supergraph they were made for, so the operations are keyed by the hash of
+/// each supergraph the function serves, and a supergraph that changed finds none of them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PlanCache {
+    supergraphs: BTreeMap<String, Vec<CachedOperation>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedOperation {
+    /// The SHA-256 of the query, like `operation_hash` in the logs.
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    operation_name: Option<String>,
+    query: String,
+}
+
+pub fn supergraph_hash(schema: &str) -> String {
+    hex::encode(Sha256::digest(schema.as_bytes()))
+}
+
+/// Short-circuit subgraph fetches while we're only planning, with an empty response.
+pub fn instrument_subgraph(service: subgraph::BoxService) -> subgraph::BoxService {
+    ServiceBuilder::new()
+        .checkpoint(|request: subgraph::Request| {
+            if PLANNING_ONLY.load(Ordering::Relaxed) {
+                let response = subgraph::Response::fake_builder().context(request.context).build();
+                Ok(ControlFlow::Break(response))
+            } else {
+                Ok(ControlFlow::Continue(request))
+            }
+        })
+        .service(service)
+        .boxed()
+}
+
+impl PlanCache {
+    /// Load the plan cache from `APOLLO_ROUTER_PLAN_CACHE`, which is either a path or an
+    /// `s3://bucket/key` URL. The cache only speeds up the first invocations, so we carry on
+    /// without it if it can't be loaded.
+    pub async fn load() -> Self {
+        let Ok(location) = env::var("APOLLO_ROUTER_PLAN_CACHE") else {
+            return PlanCache::default();
+        };
+        let cache = match read(&location).await {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!("Failed to load the plan cache from {}: {}", location, e);
+                return PlanCache::default();
+            }
+        };
+        match serde_json::from_str(&cache) {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!("Failed to parse the plan cache from {}: {}", location, e);
+                PlanCache::default()
+            }
+        }
+    }
+
+    /// The operations to plan for a supergraph, if the cache was generated for it.
+    pub fn operations(&self, schema: &str) -> Vec<graphql::Request> {
+        if self.supergraphs.is_empty() {
+            return Vec::new();
+        }
+        let hash = supergraph_hash(schema);
+        let Some(operations) = self.supergraphs.get(&hash) else {
+            warn!(
+                "Ignoring the plan cache, which wasn't generated for supergraph {}, run \
+                 `warm-plans` again",
+                hash
+            );
+            return Vec::new();
+        };
+        operations
+            .iter()
+            .map(|operation| {
+                graphql::Request::builder()
+                    .query(operation.query.clone())
+                    .and_operation_name(operation.operation_name.clone())
+                    .build()
+            })
+            .collect()
+    }
+}
+
+async fn read(location: &str) -> Result<String, Error> {
+    let Some(object) = location.strip_prefix("s3://") else {
+        return Ok(fs::read_to_string(location)?);
+    };
+    let (bucket, key) = object.split_once('/').ok_or("expected an s3://bucket/key URL")?;
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest()).load().await;
+    let object =
+        aws_sdk_s3::Client::new(&config).get_object().bucket(bucket).key(key).send().await?;
+    Ok(String::from_utf8(object.body.collect().await?.into_bytes().to_vec())?)
+}
+
+/// A persisted query manifest, as generated by `generate-persisted-query-manifest` or Rover.
+#[derive(Debug, Deserialize)]
+struct PersistedQueryManifest {
+    operations: Vec<PersistedQuery>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PersistedQuery {
+    name: Option<String>,
+    body: String,
+}
+
+/// Load the operations to warm up the query planner with from `APOLLO_ROUTER_WARMUP_PATH`, or
+/// from `warmup.graphql` next to the Router configuration. This is either a GraphQL document,
+/// whose operations are each planned with the whole document as their query, or a persisted
+/// query manifest (a `.json` file). Unlike the plan cache, the file is used as is, so it doesn't
+/// need to be generated again when the supergraph changes.
+pub fn load_warmup(config_path: &str) -> Result<Vec<graphql::Request>, Error> {
+    let path = match env::var("APOLLO_ROUTER_WARMUP_PATH") {
+        Ok(path) => path.into(),
+        Err(_) => Path::new(config_path).with_file_name("warmup.graphql"),
+    };
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(&path)?;
+    let invalid =
+        |e: &dyn std::fmt::Display| format!("invalid warm-up file {}: {e}", path.display());
+    if path.extension().is_some_and(|extension| extension == "json") {
+        let manifest: PersistedQueryManifest =
+            serde_json::from_str(&contents).map_err(|e| invalid(&e))?;
+        let operations = manifest.operations.into_iter().map(|operation| {
+            graphql::Request::builder()
+                .query(operation.body)
+                .and_operation_name(operation.name)
+                .build()
+        });
+        return Ok(operations.collect());
+    }
+    let document = graphql_parser::parse_query::<String>(&contents).map_err(|e| invalid(&e))?;
+    let operations = document.definitions.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(
+            graphql::Request::builder()
+                .query(contents.clone())
+                .and_operation_name(ast::operation_name(operation).map(str::to_string))
+                .build(),
+        ),
+        Definition::Fragment(_) => None,
+    });
+    Ok(operations.collect())
+}
+
+/// Plan the operations without executing them, which leaves their plans in the Router's cache,
+/// so that the first invocations don't pay for planning them. Operations are planned without
+/// variables, which doesn't matter for their plans.
+pub async fn warm(
+    supergraph: &Arc<Mutex<router::BoxCloneService>>,
+    operations: &[graphql::Request],
+) {
+    if operations.is_empty() {
+        return;
+    }
+    let started = Instant::now();
+    let mut failed = 0;
+    PLANNING_ONLY.store(true, Ordering::Relaxed);
+    for operation in operations {
+        let planned = crate::call_supergraph(
+            Arc::clone(supergraph),
+            operation,
+            &HeaderMap::new(),
+            Vec::new(),
+        )
+        .await;
+        // Operations that fail validation or planning get a 4xx, while fetches that we skipped
+        // only leave errors in the response.
+        let error = match planned {
+            Ok((status, _)) if status.as_u16() < 400 => continue,
+            Ok((status, response)) => format!("{status}: {}", response["errors"]),
+            Err(e) => e.to_string(),
+        };
+        let name = operation.operation_name.as_deref().unwrap_or("anonymous");
+        warn!("Failed to plan operation {}: {}", name, error);
+        failed += 1;
+    }
+    PLANNING_ONLY.store(false, Ordering::Relaxed);
+    info!(
+        warmed = operations.len() - failed,
+        failed,
+        duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+        "warmed up the query planner"
+    );
+}
+
+/// The `warm-plans` command, which writes a plan cache from files with one operation each, or
+/// from directories of `.graphql` files:
+///
+/// ```sh
+/// bootstrap warm-plans plans.json operations/
+/// ```
+///
+/// The operations are keyed by the hash of the supergraph, and of the supergraph of each tenant
+/// if the function serves several, so one file covers all of them. The Router caches plans by
+/// the exact text of the query, so the files need to hold the operations exactly as clients
+/// send them.
+pub fn generate(args: &[String]) -> Result<(), Error> {
+    let [output, inputs @ ..] = args else {
+        let usage = "usage: bootstrap warm-plans <output> <operation files or directories>...";
+        return Err(usage.into());
+    };
+    let config_path = env::var("APOLLO_ROUTER_CONFIG_PATH").unwrap_or("./router.yaml".to_string());
+    let schema_path =
+        env::var("APOLLO_ROUTER_SUPERGRAPH_PATH").unwrap_or("./supergraph.graphql".to_string());
+    let mut schemas = vec![fs::read_to_string(&schema_path)?];
+    if let Some(config) = crate::tenants::load(&config_path, &schema_path)? {
+        for tenant in config.tenants.values() {
+            schemas.push(tenant.schema()?);
+        }
+    }
+    let inputs: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+    let cache = PlanCache::generate(&schemas, &inputs)?;
+    fs::write(output, serde_json::to_string_pretty(&cache)?)?;
+    for (hash, operations) in &cache.supergraphs {
+        println!("Wrote {} operations for supergraph {} to {}", operations.len(), hash, output);
+    }
+    Ok(())
+}
+
+impl PlanCache {
+    fn generate(schemas: &[String], inputs: &[PathBuf]) -> Result<Self, Error> {
+        let mut files = Vec::new();
+        for path in inputs {
+            if path.is_dir() {
+                let mut entries = fs::read_dir(path)?
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                entries.retain(|entry| {
+                    entry
+                        .extension()
+                        .is_some_and(|extension| extension == "graphql" || extension == "gql")
+                });
+                entries.sort();
+                files.extend(entries);
+            } else {
+                files.push(path.clone());
+            }
+        }
+
+        let mut operations = Vec::new();
+        let mut seen = HashSet::new();
+        for file in files {
+            let query = fs::read_to_string(&file)?;
+            let document = graphql_parser::parse_query::<String>(&query)
+                .map_err(|e| format!("failed to parse {}: {e}", file.display()))?;
+            let hash = crate::logging::operation_hash(Some(&query));
+            for definition in &document.definitions {
+                let Definition::Operation(operation) = definition else {
+                    continue;
+                };
+                let operation_name = ast::operation_name(operation).map(str::to_string);
+                if seen.insert((hash.clone(), operation_name.clone())) {
+                    operations.push((hash.clone(), operation_name, query.clone()));
+                }
+            }
+        }
+
+        let supergraphs = schemas
+            .iter()
+            .map(|schema| {
+                let operations =
+                    operations.iter().map(|(hash, operation_name, query)| CachedOperation {
+                        hash: hash.clone(),
+                        operation_name: operation_name.clone(),
+                        query: query.clone(),
+                    });
+                (supergraph_hash(schema), operations.collect())
+            })
+            .collect();
+        Ok(PlanCache { supergraphs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of operations, removed once the test is done.
+    struct Operations(PathBuf);
+
+    impl Operations {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = env::temp_dir().join(format!("plans-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+            Operations(dir)
+        }
+    }
+
+    impl Drop for Operations {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(requests: &[graphql::Request]) -> Vec<Option<&str>> {
+        requests.iter().map(|request| request.operation_name.as_deref()).collect()
+    }
+
+    #[test]
+    fn generates_the_operations_of_every_supergraph() {
+        let dir = Operations::new(
+            "generate",
+            &[
+                ("b.graphql", "query Me { me { name } }"),
+                ("a.gql", "query Top { topProducts { upc } } query Again { me { id } }"),
+                ("notes.txt", "not an operation"),
+            ],
+        );
+        let extra = dir.0.join("extra.query");
+        fs::write(&extra, "{ me { id } }").unwrap();
+        let schemas = ["type Query { a: Int }".to_string(), "type Query { b: Int }".to_string()];
+        // The same file twice only adds its operations once.
+        let inputs = [dir.0.clone(), extra, dir.0.join("b.graphql")];
+
+        let cache = PlanCache::generate(&schemas, &inputs).unwrap();
+        assert_eq!(cache.supergraphs.len(), 2);
+        for schema in &schemas {
+            let operations = cache.operations(schema);
+            assert_eq!(names(&operations), [Some("Top"), Some("Again"), Some("Me"), None]);
+            assert_eq!(operations[3].query.as_deref(), Some("{ me { id } }"));
+        }
+        let cached = &cache.supergraphs[&supergraph_hash(&schemas[0])][0];
+        assert_eq!(cached.hash, crate::logging::operation_hash(Some(&cached.query)));
+    }
+
+    #[test]
+    fn fails_on_invalid_operations() {
+        let dir = Operations::new("invalid", &[("broken.graphql", "query { me { ")]);
+        let error = PlanCache::generate(&[String::new()], &[dir.0.clone()]).unwrap_err();
+        assert!(error.to_string().starts_with("failed to parse"));
+    }
+
+    #[test]
+    fn ignores_supergraphs_it_was_not_generated_for() {
+        let dir = Operations::new("stale", &[("me.graphql", "{ me { name } }")]);
+        let schema = "type Query { me: String }".to_string();
+        let cache = PlanCache::generate(&[schema.clone()], &[dir.0.clone()]).unwrap();
+        assert_eq!(cache.operations(&schema).len(), 1);
+        assert!(cache.operations("type Query { me: String, you: String }").is_empty());
+        assert!(PlanCache::default().operations(&schema).is_empty());
+
+        // The file round-trips through JSON, as written by `warm-plans`.
+        let cache: PlanCache =
+            serde_json::from_str(&serde_json::to_string_pretty(&cache).unwrap()).unwrap();
+        assert_eq!(names(&cache.operations(&schema)), [None]);
+    }
+
+    #[tokio::test]
+    async fn loads_the_cache_from_a_file() {
+        let dir = Operations::new("load", &[("me.graphql", "{ me { name } }")]);
+        let schema = "type Query { me: String }".to_string();
+        let cache = PlanCache::generate(&[schema.clone()], &[dir.0.clone()]).unwrap();
+        let path = dir.0.join("plans.json");
+        fs::write(&path, serde_json::to_string(&cache).unwrap()).unwrap();
+        assert_eq!(read(path.to_str().unwrap()).await.unwrap(), fs::read_to_string(&path).unwrap());
+        assert!(read(dir.0.join("missing.json").to_str().unwrap()).await.is_err());
+        assert!(read("s3://bucket-without-key").await.is_err());
+    }
+}
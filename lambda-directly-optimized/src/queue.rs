@@ -0,0 +1,217 @@
+use crate::xray::{self, TraceHeader};
+use apollo_router::services::router;
+use lambda_runtime::Error;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// An SQS event, see https://docs.aws.amazon.com/lambda/latest/dg/with-sqs.html.
+#[derive(Debug, Deserialize)]
+struct SqsEvent {
+    #[serde(rename = "Records")]
+    records: Vec<SqsRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SqsRecord {
+    message_id: String,
+    #[serde(default)]
+    body: String,
+    #[serde(rename = "eventSourceARN", default)]
+    event_source_arn: String,
+    #[serde(default)]
+    attributes: SqsAttributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SqsAttributes {
+    // The trace of the producer, if it sent the message with X-Ray tracing enabled.
+    #[serde(rename = "AWSTraceHeader", default)]
+    aws_trace_header: Option<String>,
+}
+
+/// An EventBridge event, where the GraphQL request is the event's `detail`.
+#[derive(Debug, Deserialize)]
+struct EventBridgeEvent {
+    id: String,
+    detail: Value,
+}
+
+pub fn is_sqs_event(event: &Value) -> bool {
+    event.pointer("/Records/0/eventSource").and_then(Value::as_str) == Some("aws:sqs")
+}
+
+pub fn is_eventbridge_event(event: &Value) -> bool {
+    event.get("detail-type").is_some() && event.get("detail").is_some()
+}
+
+/// Execute a GraphQL request from a queue, treating both an invalid request and any GraphQL
+/// errors in the response as a failure, so that the message gets retried.
+async fn execute(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    request: Value,
+) -> Result<(), Error> {
+    let request: apollo_router::graphql::Request = serde_json::from_value(request)?;
+    let (_status, response) =
+        crate::execute(supergraph, None, request, &HeaderMap::new(), Ok(None), Vec::new()).await?;
+    match response.get("errors") {
+        Some(errors) if errors.as_array().is_some_and(|errors| !errors.is_empty()) => {
+            Err(format!("GraphQL errors: {errors}").into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Execute each SQS record, and report the ones that failed with a partial batch response so
+/// that only those are retried. This requires `ReportBatchItemFailures` to be enabled on the
+/// event source mapping.
+pub async fn handle_sqs(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    event: Value,
+) -> Result<Value, Error> {
+    let event: SqsEvent = serde_json::from_value(event)?;
+    let mut failures = Vec::new();
+    for record in event.records {
+        // FIFO queues must be processed in order, so once a record fails we stop and report the
+        // remaining records as failed too.
+        if !failures.is_empty() && record.event_source_arn.ends_with(".fifo") {
+            failures.push(record.message_id);
+            continue;
+        }
+        // Each message continues the trace of its producer, rather than that of the invocation.
+        let trace_header = match &record.attributes.aws_trace_header {
+            Some(header) => TraceHeader::parse(header),
+            None => xray::current(),
+        };
+        let result = match serde_json::from_str(&record.body) {
+            Ok(request) => xray::scope(trace_header, execute(supergraph.clone(), request)).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to process SQS message {}: {}", record.message_id, e);
+            failures.push(record.message_id);
+        }
+    }
+    let batch_item_failures: Vec<Value> =
+        failures.into_iter().map(|id| json!({ "itemIdentifier": id })).collect();
+    Ok(json!({ "batchItemFailures": batch_item_failures }))
+}
+
+/// Execute the GraphQL request in an EventBridge event. EventBridge invokes the function
+/// asynchronously, so returning an error makes Lambda retry the event.
+pub async fn handle_eventbridge(
+    supergraph: Arc<Mutex<router::BoxCloneService>>,
+    event: Value,
+) -> Result<Value, Error> {
+    let event: EventBridgeEvent = serde_json::from_value(event)?;
+    if let Err(e) = execute(supergraph, event.detail).await {
+        warn!("Failed to process EventBridge event {}: {}", event.id, e);
+        return Err(e);
+    }
+    Ok(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xray::XRAY_HEADER;
+
+    /// A supergraph that records the requests it gets, and answers any `checkout` with GraphQL
+    /// errors.
+    fn supergraph() -> (Arc<Mutex<router::BoxCloneService>>, Arc<std::sync::Mutex<Vec<Value>>>) {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let service = tower::service_fn(move |request: router::Request| {
+            let calls = recorded.clone();
+            async move {
+                let trace = request.router_request.headers().get(XRAY_HEADER).cloned();
+                let body = hyper::body::to_bytes(request.router_request.into_body()).await?;
+                let body: Value = serde_json::from_slice(&body)?;
+                let query = body["query"].as_str().unwrap_or_default().to_string();
+                let response = if query.contains("checkout") {
+                    json!({ "errors": [{ "message": "cart is empty" }] })
+                } else {
+                    json!({ "data": { "ok": true } })
+                };
+                calls.lock().unwrap().push(json!({
+                    "query": query,
+                    "trace": trace.map(|trace| trace.to_str().unwrap().to_string()),
+                }));
+                let response = hyper::Response::new(hyper::Body::from(response.to_string()));
+                Ok::<_, tower::BoxError>(router::Response::from(response))
+            }
+        });
+        (Arc::new(Mutex::new(router::BoxCloneService::new(service))), calls)
+    }
+
+    fn event(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_the_records_that_failed() {
+        let event = event(include_str!("../../events/sqs.json"));
+        assert!(is_sqs_event(&event));
+        assert!(!is_eventbridge_event(&event));
+        let (supergraph, calls) = supergraph();
+        let response = handle_sqs(supergraph, event).await.unwrap();
+        // The second message isn't a GraphQL request, so only the first reaches the supergraph.
+        assert_eq!(
+            response,
+            json!({ "batchItemFailures": [{ "itemIdentifier": "2e1424d4-f796-459a-8184-9c92662be6da" }] })
+        );
+        assert_eq!(*calls.lock().unwrap(), [json!({ "query": "{ me { name } }", "trace": null })]);
+    }
+
+    #[tokio::test]
+    async fn stops_fifo_queues_at_the_first_failure() {
+        let (supergraph, calls) = supergraph();
+        let response = handle_sqs(supergraph, event(include_str!("../../events/sqs-fifo.json")))
+            .await
+            .unwrap();
+        // The checkout has GraphQL errors, and what follows it must wait for it to be retried.
+        assert_eq!(
+            response,
+            json!({ "batchItemFailures": [
+                { "itemIdentifier": "7b0bdbf1-b34f-4bcd-9a45-9e8b3b6fef3a" },
+                { "itemIdentifier": "f2b42fe9-3c0d-4e47-a1e2-1a5c4b7b4f43" },
+            ] })
+        );
+        let calls = calls.lock().unwrap();
+        let queries: Vec<_> = calls.iter().map(|call| call["query"].as_str().unwrap()).collect();
+        assert_eq!(
+            queries,
+            [
+                r#"mutation { addToCart(sku: "a") { id } }"#,
+                r#"mutation { checkout(cart: "1") { id } }"#
+            ]
+        );
+        // Each message continues the trace of its producer.
+        assert_eq!(
+            calls[0]["trace"],
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_eventbridge_events_with_graphql_errors() {
+        let event = event(include_str!("../../events/eventbridge.json"));
+        assert!(is_eventbridge_event(&event));
+        assert!(!is_sqs_event(&event));
+        let (supergraph, calls) = supergraph();
+        assert_eq!(
+            handle_eventbridge(supergraph.clone(), event.clone()).await.unwrap(),
+            Value::Null
+        );
+
+        let mut failing = event;
+        failing["detail"]["query"] = json!("mutation { checkout(cart: \"1\") { id } }");
+        let error = handle_eventbridge(supergraph, failing).await.unwrap_err();
+        assert!(error.to_string().contains("cart is empty"), "{error}");
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::event::{EventSource, HttpRequest, HttpResponse};
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_runtime::Error;
+use reqwest::header::{HeaderValue, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// How often we retry taking a token when another container updated the bucket at the same
+/// time, before we let the request through.
+const MAX_ATTEMPTS: usize = 3;
+
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// The rate limiting configuration, read from `rate_limit.yaml` next to the Router
+/// configuration, or from `APOLLO_ROUTER_RATE_LIMIT_PATH`. Rate limiting is disabled if there is
+/// no such file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The DynamoDB table holding the buckets, with a string partition key named `key`.
+    table: String,
+    /// Where to reach DynamoDB, e.g. `http://127.0.0.1:8000` for DynamoDB Local.
+    #[serde(default)]
+    endpoint_url: Option<String>,
+    /// How to tell clients apart, using the first identity that a request has.
+    #[serde(default = "default_identify_by")]
+    identify_by: Vec<Identity>,
+    /// How many requests a client can burst.
+    capacity: f64,
+    /// How many requests a client gets back per second.
+    refill_per_second: f64,
+}
+
+impl Config {
+    fn parse(yaml: &str) -> Result<Self, String> {
+        let config: Config = serde_yaml::from_str(yaml).map_err(|e| e.to_string())?;
+        if config.capacity < 1.0 || config.refill_per_second <= 0.0 {
+            return Err("the capacity must be at least 1, and the refill positive".to_string());
+        }
+        Ok(config)
+    }
+
+    /// How many tokens a bucket has now, given how many it had when it was last updated.
+    fn refill(&self, tokens: f64, updated_at: f64, now: f64) -> f64 {
+        // Containers' clocks may disagree a little, which mustn't take tokens away.
+        let elapsed = (now - updated_at).max(0.0);
+        (tokens + elapsed * self.refill_per_second).min(self.capacity)
+    }
+
+    /// How long to wait for the next token, if a bucket is empty.
+    fn wait(&self, tokens: f64) -> Option<Duration> {
+        (tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - tokens) / self.refill_per_second))
+    }
+}
+
+fn default_identify_by() -> Vec<Identity> {
+    vec![Identity::JwtSubject, Identity::ApiKey, Identity::SourceIp]
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Identity {
+    /// The `sub` claim of an API Gateway authorizer, or of a validated bearer token.
+    JwtSubject,
+    /// The API Gateway API key of a REST API usage plan.
+    ApiKey,
+    /// The IP address of the client.
+    SourceIp,
+}
+
+/// Limits how many requests each client can make with a token bucket per client. The Lambda
+/// runs many isolated containers, so the buckets are kept in DynamoDB and updated with
+/// conditional writes.
+struct RateLimiter {
+    config: Config,
+    client: aws_sdk_dynamodb::Client,
+}
+
+/// Set up rate limiting, if it's configured.
+pub async fn init(config_path: &str) -> Result<(), Error> {
+    let path = match env::var("APOLLO_ROUTER_RATE_LIMIT_PATH") {
+        Ok(path) => path.into(),
+        Err(_) => Path::new(config_path).with_file_name("rate_limit.yaml"),
+    };
+    if !path.exists() {
+        return Ok(());
+    }
+    let config = Config::parse(&fs::read_to_string(&path)?)
+        .map_err(|e| format!("invalid rate limit configuration in {}: {e}", path.display()))?;
+
+    let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Some(endpoint_url) = &config.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+    let client = aws_sdk_dynamodb::Client::new(&loader.load().await);
+    let _ = RATE_LIMITER.set(RateLimiter { config, client });
+    Ok(())
+}
+
+/// Take a token from the client's bucket, returning the response to send instead if it's empty.
+/// Requests are let through if we can't tell who the client is, or if DynamoDB is unavailable,
+/// since rejecting every request would be worse than not limiting them for a while.
+pub async fn check(request: &HttpRequest, claims: Option<&Value>) -> Option<HttpResponse> {
+    let rate_limiter = RATE_LIMITER.get()?;
+    let key = rate_limiter.client_key(request, claims)?;
+    let retry_after = match rate_limiter.take(&key).await {
+        Ok(retry_after) => retry_after?,
+        Err(e) => {
+            warn!("Failed to check the rate limit of {}: {}", key, e);
+            return None;
+        }
+    };
+
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = HttpResponse::json(
+        StatusCode::TOO_MANY_REQUESTS,
+        &json!({ "errors": [{
+            "message": format!("rate limit exceeded, retry in {retry_after_secs} seconds"),
+            "extensions": { "code": "RATE_LIMITED", "retryAfter": retry_after_secs },
+        }] }),
+    );
+    response.headers.insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    Some(response)
+}
+
+impl RateLimiter {
+    /// Who the client is, from the first of the configured identities it has. `claims` are those
+    /// of the request's verified token, if it had one.
+    fn client_key(&self, request: &HttpRequest, claims: Option<&Value>) -> Option<String> {
+        for identity in &self.config.identify_by {
+            let key = match identity {
+                Identity::JwtSubject => subject(request, claims).map(|sub| format!("sub:{sub}")),
+                Identity::ApiKey => request
+                    .request_context
+                    .pointer("/identity/apiKey")
+                    .and_then(Value::as_str)
+                    .map(|api_key| format!("key:{api_key}")),
+                Identity::SourceIp => source_ip(request).map(|ip| format!("ip:{ip}")),
+            };
+            if key.is_some() {
+                return key;
+            }
+        }
+        None
+    }
+
+    /// Take a token from the bucket, refilling it for the time since it was last updated, and
+    /// return how long to wait if it's empty.
+    async fn take(&self, key: &str) -> Result<Option<Duration>, Error> {
+        let Config { table, capacity, refill_per_second, .. } = &self.config;
+        for _ in 0..MAX_ATTEMPTS {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+            let item = self
+                .client
+                .get_item()
+                .table_name(table)
+                .key("key", AttributeValue::S(key.to_string()))
+                .consistent_read(true)
+                .send()
+                .await?
+                .item;
+            let number = |name: &str| match item.as_ref()?.get(name)? {
+                AttributeValue::N(number) => Some(number.clone()),
+                _ => None,
+            };
+            let previous = number("tokens").zip(number("updated_at"));
+
+            let tokens = match &previous {
+                Some((tokens, updated_at)) => {
+                    self.config.refill(tokens.parse()?, updated_at.parse()?, now)
+                }
+                None => *capacity,
+            };
+            if let Some(wait) = self.config.wait(tokens) {
+                return Ok(Some(wait));
+            }
+
+            // Buckets that have been full for a while are no different from missing ones, so we
+            // let DynamoDB expire them if TTL is enabled on `expires_at`.
+            let expires_at = now + capacity / refill_per_second + 60.0;
+            let put = self
+                .client
+                .put_item()
+                .table_name(table)
+                .item("key", AttributeValue::S(key.to_string()))
+                .item("tokens", AttributeValue::N((tokens - 1.0).to_string()))
+                .item("updated_at", AttributeValue::N(now.to_string()))
+                .item("expires_at", AttributeValue::N((expires_at as u64).to_string()));
+            // Only write if nobody else took a token since we read the bucket.
+            let put = match previous {
+                Some((_, updated_at)) => put
+                    .condition_expression("updated_at = :updated_at")
+                    .expression_attribute_values(":updated_at", AttributeValue::N(updated_at)),
+                None => put
+                    .condition_expression("attribute_not_exists(#key)")
+                    .expression_attribute_names("#key", "key"),
+            };
+            match put.send().await {
+                Ok(_) => return Ok(None),
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The subject of the client, preferring the claims that an API Gateway authorizer verified.
+fn subject(request: &HttpRequest, claims: Option<&Value>) -> Option<String> {
+    let sub = |claims: &serde_json::Map<String, Value>| {
+        claims.get("sub").and_then(Value::as_str).map(str::to_string)
+    };
+    if let Some(sub) = crate::authorizer::claims(&request.request_context).and_then(sub) {
+        return Some(sub);
+    }
+    // Invalid tokens are rejected once the request is executed, so we only get valid ones.
+    sub(claims?.as_object()?)
+}
+
+fn source_ip(request: &HttpRequest) -> Option<String> {
+    match request.source {
+        EventSource::ApiGatewayV1 => {
+            request.request_context.pointer("/identity/sourceIp")?.as_str().map(str::to_string)
+        }
+        EventSource::ApiGatewayV2 | EventSource::FunctionUrl => {
+            request.request_context.pointer("/http/sourceIp")?.as_str().map(str::to_string)
+        }
+        // The load balancer appends the address of the client it saw to the header.
+        EventSource::Alb => {
+            let forwarded_for =
+                request.headers.get_all("x-forwarded-for").iter().next_back()?.to_str().ok()?;
+            forwarded_for.rsplit(',').next().map(|ip| ip.trim().to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+
+    const EXAMPLE: &str = include_str!("../../rate_limit.example.yaml");
+
+    fn rate_limiter(config: &str) -> RateLimiter {
+        let config = Config::parse(config).unwrap();
+        let mut client = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("local", "local", None, None, "tests"));
+        if let Some(endpoint_url) = &config.endpoint_url {
+            client = client.endpoint_url(endpoint_url);
+        }
+        RateLimiter { config, client: aws_sdk_dynamodb::Client::from_conf(client.build()) }
+    }
+
+    fn http_request(event: &str) -> HttpRequest {
+        let event: Value = serde_json::from_str(event).unwrap();
+        HttpRequest::from_event(EventSource::detect(&event).unwrap(), event).unwrap()
+    }
+
+    #[test]
+    fn parses_the_configuration() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.table, "apollo-router-rate-limits");
+        assert_eq!((config.capacity, config.refill_per_second), (20.0, 5.0));
+
+        let config = Config::parse("table: limits\ncapacity: 1\nrefill_per_second: 0.5").unwrap();
+        assert_eq!(config.endpoint_url, None);
+        assert_eq!(config.identify_by.len(), 3);
+
+        for invalid in [
+            "table: limits\ncapacity: 0.5\nrefill_per_second: 1",
+            "table: limits\ncapacity: 10\nrefill_per_second: 0",
+            "table: limits\ncapacity: 10\nrefill_per_second: 1\nburst: 5",
+            "table: limits\ncapacity: 10\nrefill_per_second: 1\nidentify_by: [cookie]",
+        ] {
+            assert!(Config::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn refills_buckets_over_time() {
+        let config = Config::parse("table: limits\ncapacity: 10\nrefill_per_second: 2").unwrap();
+        assert_eq!(config.refill(0.0, 100.0, 101.5), 3.0);
+        assert_eq!(config.refill(4.0, 100.0, 160.0), 10.0);
+        // A bucket updated by a container whose clock is ahead doesn't lose tokens.
+        assert_eq!(config.refill(4.0, 100.0, 99.0), 4.0);
+
+        assert_eq!(config.wait(1.0), None);
+        assert_eq!(config.wait(0.0), Some(Duration::from_millis(500)));
+        assert_eq!(config.wait(0.5), Some(Duration::from_millis(250)));
+    }
+
+    #[tokio::test]
+    async fn identifies_clients_by_the_first_identity_they_have() {
+        let rate_limiter = rate_limiter(EXAMPLE);
+        let mut request = http_request(include_str!("../../events/apigw-v1.json"));
+        assert_eq!(rate_limiter.client_key(&request, None).as_deref(), Some("ip:127.0.0.1"));
+        request.request_context["identity"]["apiKey"] = json!("abc");
+        assert_eq!(rate_limiter.client_key(&request, None).as_deref(), Some("key:abc"));
+
+        let request = http_request(include_str!("../../events/apigw-v2-authorizer.json"));
+        assert_eq!(rate_limiter.client_key(&request, None).as_deref(), Some("sub:1"));
+        // A bearer token that we verified ourselves identifies the client as well.
+        let request = http_request(include_str!("../../events/apigw-v1.json"));
+        let claims = json!({ "sub": "ada" });
+        assert_eq!(rate_limiter.client_key(&request, Some(&claims)).as_deref(), Some("sub:ada"));
+
+        // The load balancer appends the address it saw to any the client sent.
+        let request = http_request(include_str!("../../events/alb.json"));
+        assert_eq!(rate_limiter.client_key(&request, None).as_deref(), Some("ip:10.0.0.1"));
+
+        let rate_limiter = self::rate_limiter(
+            "table: limits\ncapacity: 1\nrefill_per_second: 1\nidentify_by: [api_key]",
+        );
+        let request = http_request(include_str!("../../events/apigw-v2.json"));
+        assert_eq!(rate_limiter.client_key(&request, None), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DynamoDB Local, started with `just dynamodb-local`"]
+    async fn limits_clients_with_dynamodb() {
+        let rate_limiter = rate_limiter(EXAMPLE);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let key = format!("test:{}", now.as_nanos());
+        for _ in 0..20 {
+            assert_eq!(rate_limiter.take(&key).await.unwrap(), None);
+        }
+        let wait = rate_limiter.take(&key).await.unwrap().expect("the bucket should be empty");
+        assert!(wait <= Duration::from_millis(200), "{wait:?}");
+
+        tokio::time::sleep(wait).await;
+        assert_eq!(rate_limiter.take(&key).await.unwrap(), None);
+        // Other clients have their own bucket.
+        assert_eq!(rate_limiter.take(&format!("{key}:other")).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,32 @@
+use lambda_runtime::Error;
+use serde_yaml::{Mapping, Value};
+use std::path::Path;
+
+/// Where the Router looks for Rhai scripts, unless the `rhai` section says otherwise.
+const DEFAULT_SCRIPTS: &str = "./rhai";
+const DEFAULT_MAIN: &str = "main.rhai";
+
+/// Resolve the Rhai scripts directory of the `rhai` section relative to the Router
+/// configuration, rather than the working directory of the Lambda, so that the scripts can be
+/// bundled next to `router.yaml` wherever `APOLLO_ROUTER_CONFIG_PATH` points. A missing main
+/// script fails the init, instead of every request.
+pub fn resolve(config: &mut serde_yaml::Value, config_path: &str) -> Result<(), Error> {
+    let Some(rhai) = config.get_mut("rhai") else {
+        return Ok(());
+    };
+    // A bare `rhai:` enables the scripts with the defaults.
+    if rhai.is_null() {
+        *rhai = Mapping::new().into();
+    }
+    let rhai = rhai.as_mapping_mut().ok_or("the rhai section isn't a mapping")?;
+    let scripts = rhai.get("scripts").and_then(Value::as_str).unwrap_or(DEFAULT_SCRIPTS);
+    let main = rhai.get("main").and_then(Value::as_str).unwrap_or(DEFAULT_MAIN);
+    let config_dir = Path::new(config_path).parent().unwrap_or(Path::new("."));
+    let scripts = config_dir.join(scripts);
+    let main = scripts.join(main);
+    if !main.is_file() {
+        return Err(format!("the Rhai main script {} doesn't exist", main.display()).into());
+    }
+    rhai.insert("scripts".into(), scripts.to_string_lossy().into_owned().into());
+    Ok(())
+}
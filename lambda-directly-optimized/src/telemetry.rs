@@ -0,0 +1,281 @@
+use apollo_router::layers::ServiceBuilderExt;
+use apollo_router::services::subgraph;
+use lambda_runtime::{Context, Error};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace as sdktrace, Resource};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::WithExportConfig;
+use reqwest::header::HeaderMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tower::{ServiceBuilder, ServiceExt};
+use tracing::{field, info_span, warn, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Only the first invocation in an execution environment is a cold start.
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// An OTLP tracing pipeline that is aware of the Lambda lifecycle. Lambda freezes the execution
+/// environment between invocations, so rather than relying on the batch exporter's schedule we
+/// force a flush after every invocation.
+///
+/// The pipeline is only enabled if `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set, and exports over HTTP/protobuf.
+#[derive(Clone, Default)]
+pub struct Telemetry {
+    tracer: Option<sdktrace::Tracer>,
+    provider: Option<sdktrace::TracerProvider>,
+}
+
+impl Telemetry {
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let Some(endpoint) = traces_endpoint(&var) else {
+            return Ok(Telemetry::default());
+        };
+        let resource = resource(&var);
+
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            // Be explicit about the client, since the blocking client would block the runtime.
+            .with_http_client(reqwest::Client::new());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(sdktrace::config().with_resource(Resource::new(resource)))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Telemetry { provider: tracer.provider(), tracer: Some(tracer) })
+    }
+
+    /// The `tracing` layer that turns spans into OpenTelemetry spans, if the pipeline is enabled.
+    pub fn layer<S>(&self) -> Option<impl Layer<S>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        self.tracer.clone().map(|tracer| {
+            tracing_opentelemetry::layer().with_tracer(tracer).with_filter(LevelFilter::INFO)
+        })
+    }
+
+    /// Export all finished spans. The batch exporter's flush blocks until the export is done, so
+    /// we run it on a blocking thread to keep the runtime free to do the actual exporting.
+    pub async fn flush(&self) {
+        if let Some(provider) = self.provider.clone() {
+            let results = tokio::task::spawn_blocking(move || provider.force_flush()).await;
+            for result in results.into_iter().flatten() {
+                if let Err(e) = result {
+                    warn!("Failed to flush spans: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Flush any remaining spans and shut down the exporter.
+    pub async fn shutdown(&self) {
+        self.flush().await;
+        if self.provider.is_some() {
+            let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+        }
+    }
+}
+
+/// The endpoint to export traces to. The signal-specific variable is used as is, while the
+/// generic one is a base URL that the traces path is appended to, as the OTLP spec requires.
+fn traces_endpoint(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+        let endpoint = var("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+        Some(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+    })
+}
+
+/// Describe the function with the OpenTelemetry FaaS resource attributes.
+fn resource(var: impl Fn(&str) -> Option<String>) -> Vec<KeyValue> {
+    let service_name = var("OTEL_SERVICE_NAME")
+        .or_else(|| var("AWS_LAMBDA_FUNCTION_NAME"))
+        .unwrap_or("apollo-router-lambda".to_string());
+    let mut resource = vec![
+        KeyValue::new("service.name", service_name),
+        KeyValue::new("cloud.provider", "aws"),
+        KeyValue::new("cloud.platform", "aws_lambda"),
+    ];
+    for (key, name) in [
+        ("cloud.region", "AWS_REGION"),
+        ("faas.name", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("faas.version", "AWS_LAMBDA_FUNCTION_VERSION"),
+        ("faas.instance", "AWS_LAMBDA_LOG_STREAM_NAME"),
+    ] {
+        if let Some(value) = var(name) {
+            resource.push(KeyValue::new(key, value));
+        }
+    }
+    resource
+}
+
+/// The root span of an invocation, following the OpenTelemetry FaaS semantic conventions.
+pub fn invocation_span(context: &Context) -> Span {
+    let cold_start = COLD_START.swap(false, Ordering::Relaxed);
+    info_span!(
+        "lambda_invocation",
+        "otel.kind" = "SERVER",
+        "faas.invocation_id" = %context.request_id,
+        "faas.coldstart" = cold_start,
+        "faas.trigger" = field::Empty,
+        "cloud.resource_id" = %context.invoked_function_arn,
+    )
+}
+
+/// Continue the trace of the caller, if the incoming headers carry a `traceparent`. This needs
+/// to happen before any child spans are created.
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Wrap each subgraph fetch in its own span, and propagate the trace to the subgraph.
+pub fn instrument_subgraph(name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+    let name = name.to_string();
+    ServiceBuilder::new()
+        .instrument(move |_request: &subgraph::Request| {
+            info_span!("subgraph_fetch", "otel.kind" = "CLIENT", "apollo.subgraph.name" = %name)
+        })
+        .map_request(|mut request: subgraph::Request| {
+            let context = Span::current().context();
+            global::get_text_map_propagator(|propagator| {
+                propagator.inject_context(
+                    &context,
+                    &mut HeaderInjector(request.subgraph_request.headers_mut()),
+                )
+            });
+            request
+        })
+        .service(service)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn picks_the_traces_endpoint() {
+        assert_eq!(traces_endpoint(vars(&[])), None);
+        assert_eq!(
+            traces_endpoint(vars(&[("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318/")])),
+            Some("http://collector:4318/v1/traces".to_string())
+        );
+        assert_eq!(
+            traces_endpoint(vars(&[
+                ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318"),
+                ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "http://traces:4318/custom"),
+            ])),
+            Some("http://traces:4318/custom".to_string())
+        );
+    }
+
+    #[test]
+    fn describes_the_function() {
+        let attributes = |resource: Vec<KeyValue>| {
+            resource
+                .into_iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let defaults = attributes(resource(vars(&[])));
+        assert_eq!(defaults["service.name"], "apollo-router-lambda");
+        assert_eq!(defaults["cloud.platform"], "aws_lambda");
+        assert!(!defaults.contains_key("faas.name"));
+
+        let function = attributes(resource(vars(&[
+            ("AWS_LAMBDA_FUNCTION_NAME", "router"),
+            ("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST"),
+            ("AWS_REGION", "eu-west-1"),
+        ])));
+        assert_eq!(function["service.name"], "router");
+        assert_eq!(function["faas.name"], "router");
+        assert_eq!(function["faas.version"], "$LATEST");
+        assert_eq!(function["cloud.region"], "eu-west-1");
+    }
+
+    type Exports = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// A stand-in for an OTLP collector, which records the path and body of each export.
+    fn collector() -> (String, Exports) {
+        let exports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&exports);
+        let make_service = make_service_fn(move |_| {
+            let exports = Arc::clone(&recorded);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let exports = Arc::clone(&exports);
+                    async move {
+                        let path = request.uri().path().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        exports.lock().unwrap().push((path, body.to_vec()));
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (format!("http://{address}"), exports)
+    }
+
+    #[tokio::test]
+    async fn exports_spans_when_flushed() {
+        let (endpoint, exports) = collector();
+        let telemetry = Telemetry::from_vars(vars(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", &endpoint),
+            ("OTEL_SERVICE_NAME", "router-under-test"),
+        ]))
+        .unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("lambda_invocation").in_scope(|| {
+                info_span!("subgraph_fetch").in_scope(|| {});
+            });
+        });
+        assert!(exports.lock().unwrap().is_empty(), "spans are batched until flushed");
+
+        telemetry.flush().await;
+        let exports = exports.lock().unwrap();
+        assert_eq!(exports.len(), 1);
+        let (path, body) = &exports[0];
+        assert_eq!(path, "/v1/traces");
+        let contains = |needle: &str| body.windows(needle.len()).any(|w| w == needle.as_bytes());
+        assert!(contains("lambda_invocation"));
+        assert!(contains("subgraph_fetch"));
+        assert!(contains("router-under-test"));
+    }
+
+    #[tokio::test]
+    async fn is_disabled_without_an_endpoint() {
+        let telemetry = Telemetry::from_vars(vars(&[])).unwrap();
+        assert!(telemetry.provider.is_none());
+        telemetry.flush().await;
+    }
+}
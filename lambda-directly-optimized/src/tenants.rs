@@ -0,0 +1,398 @@
+use crate::cors::Cors;
+use crate::event::{HttpRequest, HttpResponse};
+use crate::limits::Limits;
+use apollo_router::services::router;
+use lambda_runtime::Error;
+use reqwest::header::{HeaderName, HOST};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// How we tell which tenant a request is for. Clients choose their own path and headers, so
+/// only routing by claim keeps them from picking a tenant they weren't given.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RouteBy {
+    /// The first segment of the path, e.g. `/shop/graphql` is for the `shop` tenant.
+    #[default]
+    Path,
+    /// The `Host` header, which matches the `hosts` of a tenant, or a subdomain named after it.
+    Host,
+    /// A custom header that holds the name of the tenant.
+    Header,
+    /// The `apollographql-client-name` header, which matches the `clients` of a tenant, or its
+    /// name.
+    Client,
+    /// The claim named in `claim`, of an API Gateway authorizer or of a validated bearer token,
+    /// which matches the `clients` of a tenant, or its name.
+    Claim,
+}
+
+fn default_header() -> String {
+    "x-tenant".to_string()
+}
+
+fn default_claim() -> String {
+    "tenant".to_string()
+}
+
+/// The `tenants.yaml` file, which lists the supergraphs that a single function serves:
+///
+/// ```yaml
+/// route_by: host
+/// tenants:
+///   shop:
+///     config: shop/router.yaml
+///     supergraph: shop/supergraph.graphql
+///     hosts: [shop.example.com]
+/// ```
+///
+/// Relative paths are resolved against the directory of `tenants.yaml`. A tenant without a
+/// `config` or `supergraph` uses the default one, which together with `exclude_tags` makes it a
+/// contract of the default supergraph:
+///
+/// ```yaml
+/// route_by: client
+/// default: public
+/// tenants:
+///   public:
+///     exclude_tags: [internal]
+///   internal:
+///     clients: [backoffice]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    route_by: RouteBy,
+    /// The header that names the tenant, when routing by header.
+    #[serde(default = "default_header")]
+    header: String,
+    /// The claim that names the tenant, when routing by claim.
+    #[serde(default = "default_claim")]
+    claim: String,
+    /// The tenant of requests that don't match any, instead of turning them away.
+    default: Option<String>,
+    pub tenants: BTreeMap<String, TenantConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    #[serde(default)]
+    pub config: PathBuf,
+    #[serde(default)]
+    pub supergraph: PathBuf,
+    /// Hide the elements of the supergraph with any of these `@tag`s from the tenant.
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    clients: Vec<String>,
+}
+
+impl TenantConfig {
+    /// The schema of the tenant, which is a contract of its supergraph if it excludes tags.
+    pub fn schema(&self) -> Result<String, Error> {
+        let schema = fs::read_to_string(&self.supergraph)?;
+        if self.exclude_tags.is_empty() {
+            return Ok(schema);
+        }
+        crate::contracts::derive(&schema, &self.exclude_tags)
+    }
+}
+
+/// A tenant, with its own pre-built supergraph, CORS configuration and operation limits.
+pub struct Tenant {
+    pub name: String,
+    pub supergraph: Arc<Mutex<router::BoxCloneService>>,
+    pub cors: Cors,
+    pub limits: Option<Limits>,
+}
+
+/// Picks the tenant of each HTTP request.
+pub struct Tenants {
+    route_by: RouteBy,
+    header: HeaderName,
+    claim: String,
+    hosts: HashMap<String, String>,
+    clients: HashMap<String, String>,
+    default: Option<String>,
+    tenants: HashMap<String, Tenant>,
+}
+
+/// Read the tenants configuration, if there is one.
+pub fn load(config_path: &str, schema_path: &str) -> Result<Option<Config>, Error> {
+    let path = match env::var("APOLLO_ROUTER_TENANTS_PATH") {
+        Ok(path) => path.into(),
+        Err(_) => Path::new(config_path).with_file_name("tenants.yaml"),
+    };
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut config: Config = serde_yaml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| format!("invalid tenants configuration in {}: {e}", path.display()))?;
+    if config.tenants.is_empty() {
+        return Err(format!("{} doesn't list any tenants", path.display()).into());
+    }
+    if let Some(default) = config.default.as_ref().filter(|d| !config.tenants.contains_key(*d)) {
+        return Err(
+            format!("the default tenant {default} isn't listed in {}", path.display()).into()
+        );
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let resolve = |path: &Path, default: &str| {
+        if path.as_os_str().is_empty() {
+            PathBuf::from(default)
+        } else {
+            dir.join(path)
+        }
+    };
+    for tenant in config.tenants.values_mut() {
+        tenant.config = resolve(&tenant.config, config_path);
+        tenant.supergraph = resolve(&tenant.supergraph, schema_path);
+    }
+    Ok(Some(config))
+}
+
+impl Tenants {
+    pub fn new(config: &Config, tenants: Vec<Tenant>) -> Result<Self, Error> {
+        let header = HeaderName::try_from(config.header.as_str())
+            .map_err(|_| format!("{} is not a valid tenant header", config.header))?;
+        let hosts = config
+            .tenants
+            .iter()
+            .flat_map(|(name, tenant)| {
+                tenant.hosts.iter().map(move |host| (host.to_ascii_lowercase(), name.clone()))
+            })
+            .collect();
+        let clients = config
+            .tenants
+            .iter()
+            .flat_map(|(name, tenant)| {
+                tenant.clients.iter().map(move |client| (client.clone(), name.clone()))
+            })
+            .collect();
+        let tenants = tenants.into_iter().map(|tenant| (tenant.name.clone(), tenant)).collect();
+        Ok(Tenants {
+            route_by: config.route_by,
+            header,
+            claim: config.claim.clone(),
+            hosts,
+            clients,
+            default: config.default.clone(),
+            tenants,
+        })
+    }
+
+    /// The tenant that a request is for, or `None` if it doesn't name one that we serve and
+    /// there's no default tenant. `claims` are those of the request's verified token, if it had
+    /// one.
+    pub fn route(&self, request: &HttpRequest, claims: Option<&Value>) -> Option<&Tenant> {
+        self.matching(request, claims).or_else(|| self.tenants.get(self.default.as_ref()?))
+    }
+
+    fn matching(&self, request: &HttpRequest, claims: Option<&Value>) -> Option<&Tenant> {
+        let header = |name: &HeaderName| request.headers.get(name).and_then(|v| v.to_str().ok());
+        match self.route_by {
+            RouteBy::Path => {
+                let name = request.path.trim_start_matches('/').split('/').next()?;
+                self.tenants.get(name)
+            }
+            RouteBy::Host => {
+                // Hosts are case-insensitive.
+                let host = header(&HOST)?.to_ascii_lowercase();
+                let host = without_port(&host);
+                if let Some(name) = self.hosts.get(host) {
+                    return self.tenants.get(name);
+                }
+                // IP addresses have no subdomains.
+                if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+                    return None;
+                }
+                self.tenants.get(host.split('.').next()?)
+            }
+            RouteBy::Header => self.tenants.get(header(&self.header)?),
+            RouteBy::Client => {
+                let client = header(&HeaderName::from_static("apollographql-client-name"))?;
+                let name = self.clients.get(client).map_or(client, String::as_str);
+                self.tenants.get(name)
+            }
+            RouteBy::Claim => {
+                let claim = claim(request, claims, &self.claim)?;
+                let name = self.clients.get(&claim).unwrap_or(&claim);
+                self.tenants.get(name)
+            }
+        }
+    }
+}
+
+/// A claim of the client, preferring the claims that an API Gateway authorizer verified.
+fn claim(request: &HttpRequest, claims: Option<&Value>, name: &str) -> Option<String> {
+    let claim = |claims: &serde_json::Map<String, Value>| {
+        claims.get(name).and_then(Value::as_str).map(str::to_string)
+    };
+    if let Some(claim) = crate::authorizer::claims(&request.request_context).and_then(claim) {
+        return Some(claim);
+    }
+    // Requests with an invalid token are turned away once they're executed.
+    claim(claims?.as_object()?)
+}
+
+/// The response to a request for a tenant that we don't serve.
+pub fn unknown() -> HttpResponse {
+    HttpResponse::graphql_error(StatusCode::NOT_FOUND, "unknown tenant")
+}
+
+/// The host of a `Host` header without its port, if it has one. IPv6 addresses keep their
+/// brackets, e.g. `[::1]:4000` is `[::1]`.
+fn without_port(host: &str) -> &str {
+    let port = match host.rfind(']') {
+        Some(bracket) => host[bracket..].find(':').map(|colon| bracket + colon),
+        None => host.find(':'),
+    };
+    port.map_or(host, |colon| &host[..colon])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventSource;
+    use apollo_router::services::router;
+    use serde_json::json;
+
+    fn serving(yaml: &str) -> Tenants {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let tenants = config
+            .tenants
+            .keys()
+            .map(|name| Tenant {
+                name: name.clone(),
+                supergraph: Arc::new(Mutex::new(router::BoxCloneService::new(tower::service_fn(
+                    |_: router::Request| async { Err::<router::Response, _>("not called".into()) },
+                )))),
+                cors: Cors::from_config(&serde_yaml::Value::Null).unwrap(),
+                limits: None,
+            })
+            .collect();
+        Tenants::new(&config, tenants).unwrap()
+    }
+
+    /// The tenant of a request to the path, with the headers and the claims of an authorizer.
+    fn routed(tenants: &Tenants, path: &str, headers: Value, claims: Value) -> Option<String> {
+        let event = json!({
+            "httpMethod": "POST",
+            "path": path,
+            "headers": headers,
+            "requestContext": { "stage": "$default", "authorizer": { "claims": claims } },
+        });
+        let request = HttpRequest::from_event(EventSource::detect(&event).unwrap(), event).unwrap();
+        tenants.route(&request, None).map(|tenant| tenant.name.clone())
+    }
+
+    fn routed_by_path(tenants: &Tenants, path: &str) -> Option<String> {
+        routed(tenants, path, json!({}), json!({}))
+    }
+
+    fn routed_by_headers(tenants: &Tenants, headers: Value) -> Option<String> {
+        routed(tenants, "/graphql", headers, json!({}))
+    }
+
+    #[test]
+    fn routes_by_path() {
+        let tenants = serving("tenants:\n  shop: {}\n  blog: {}");
+        assert_eq!(routed_by_path(&tenants, "/shop/graphql").as_deref(), Some("shop"));
+        assert_eq!(routed_by_path(&tenants, "/blog").as_deref(), Some("blog"));
+        assert_eq!(routed_by_path(&tenants, "/graphql"), None);
+        assert_eq!(routed_by_path(&tenants, "/"), None);
+    }
+
+    #[test]
+    fn routes_by_host() {
+        let tenants = serving(
+            "route_by: host\ntenants:\n  shop:\n    hosts: [Shop.Example.com, '[::1]']\n  blog: {}",
+        );
+        for (host, tenant) in [
+            ("shop.example.com", Some("shop")),
+            ("SHOP.example.com:443", Some("shop")),
+            ("[::1]:4000", Some("shop")),
+            ("[::1]", Some("shop")),
+            // Tenants without hosts are found by subdomain, which IP addresses don't have.
+            ("blog.example.com:8080", Some("blog")),
+            ("[::2]:4000", None),
+            ("127.0.0.1:4000", None),
+            ("www.example.com", None),
+        ] {
+            let routed = routed_by_headers(&tenants, json!({ "host": host }));
+            assert_eq!(routed.as_deref(), tenant, "{host}");
+        }
+        assert_eq!(routed_by_headers(&tenants, json!({})), None);
+    }
+
+    #[test]
+    fn routes_by_header() {
+        let tenants = serving("route_by: header\nheader: x-site\ntenants:\n  shop: {}");
+        let routed = routed_by_headers(&tenants, json!({ "x-site": "shop" }));
+        assert_eq!(routed.as_deref(), Some("shop"));
+        assert_eq!(routed_by_headers(&tenants, json!({ "x-site": "blog" })), None);
+        assert_eq!(routed_by_headers(&tenants, json!({ "x-tenant": "shop" })), None);
+    }
+
+    #[test]
+    fn routes_by_verified_claim() {
+        let tenants = serving(
+            "route_by: claim\nclaim: org\ndefault: public\ntenants:\n  public: {}\n  \
+             internal:\n    clients: [backoffice]",
+        );
+        let as_org = |org| routed(&tenants, "/graphql", json!({}), json!({ "org": org }));
+        assert_eq!(as_org("backoffice").as_deref(), Some("internal"));
+        assert_eq!(as_org("internal").as_deref(), Some("internal"));
+        assert_eq!(as_org("acme").as_deref(), Some("public"));
+
+        // The claims of a token that we verified ourselves count too.
+        let event = json!({
+            "httpMethod": "POST",
+            "path": "/graphql",
+            "headers": {},
+            "requestContext": { "stage": "$default" },
+        });
+        let request = HttpRequest::from_event(EventSource::detect(&event).unwrap(), event).unwrap();
+        let claims = json!({ "org": "backoffice" });
+        let tenant = tenants.route(&request, Some(&claims)).map(|tenant| tenant.name.as_str());
+        assert_eq!(tenant, Some("internal"));
+
+        // Headers that clients set themselves are ignored.
+        let spoofed = json!({ "apollographql-client-name": "backoffice", "x-tenant": "internal" });
+        let routed = routed(&tenants, "/internal/graphql", spoofed, json!({}));
+        assert_eq!(routed.as_deref(), Some("public"));
+    }
+
+    #[test]
+    fn turns_away_unknown_tenants_without_a_default() {
+        let tenants = serving("tenants:\n  shop: {}");
+        assert_eq!(routed_by_path(&tenants, "/blog/graphql"), None);
+        let response = unknown();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(body["errors"][0]["message"], "unknown tenant");
+
+        let tenants = serving("default: shop\ntenants:\n  shop: {}\n  blog: {}");
+        assert_eq!(routed_by_path(&tenants, "/graphql").as_deref(), Some("shop"));
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(without_port("example.com"), "example.com");
+        assert_eq!(without_port("example.com:443"), "example.com");
+        assert_eq!(without_port("[2001:db8::1]:443"), "[2001:db8::1]");
+        assert_eq!(without_port("[2001:db8::1]"), "[2001:db8::1]");
+    }
+}
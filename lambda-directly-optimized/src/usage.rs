@@ -0,0 +1,854 @@
+use crate::ast;
+use apollo_router::graphql;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use graphql_parser::query::{
+    Definition, Document, FragmentDefinition, OperationDefinition, Selection, SelectionSet,
+    TypeCondition, Value,
+};
+use graphql_parser::schema::{self, TypeDefinition, TypeExtension};
+use lambda_runtime::Error;
+use prost::Message;
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const DEFAULT_ENDPOINT: &str = "https://usage-reporting.api.apollographql.com/api/ingress/traces";
+const CLIENT_NAME_HEADER: &str = "apollographql-client-name";
+const CLIENT_VERSION_HEADER: &str = "apollographql-client-version";
+
+/// The number of buckets in a duration histogram, see `DurationHistogram` in Apollo Server.
+const HISTOGRAM_BUCKETS: usize = 384;
+
+/// We only keep the signatures of this many distinct queries around.
+const MAX_CACHED_SIGNATURES: usize = 1000;
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+/// Reports the usage of the supergraph to GraphOS, which the Router normally does itself but
+/// which is lost when running it through the `TestHarness`. For every operation we record its
+/// signature, the fields it references and its latency, batch those across warm invocations,
+/// and send them to the usage reporting endpoint once enough operations or time have passed, as
+/// well as when the execution environment shuts down.
+///
+/// Usage reporting is enabled when both `APOLLO_KEY` and `APOLLO_GRAPH_REF` are set, and can be
+/// configured with:
+///
+/// - `APOLLO_USAGE_REPORTING_INGRESS_URL`: the endpoint to send the reports to.
+/// - `APOLLO_ROUTER_USAGE_REPORTING_MAX_OPERATIONS`: flush after this many operations (100).
+/// - `APOLLO_ROUTER_USAGE_REPORTING_INTERVAL_SECS`: flush after this many seconds (10).
+/// - `APOLLO_ROUTER_USAGE_REPORTING=false`: disables usage reporting.
+struct Reporter {
+    client: Client,
+    endpoint: String,
+    api_key: String,
+    header: ReportHeader,
+    schema: SchemaFields,
+    max_operations: u64,
+    interval: Duration,
+    signatures: Mutex<SignatureCache>,
+    batch: Mutex<Batch>,
+}
+
+/// The signatures of the queries we have seen, keyed by the query and operation name. Queries
+/// that can't be parsed have no signature.
+type SignatureCache = HashMap<(String, Option<String>), Option<Signature>>;
+
+/// The usage that has been recorded since the last flush, starting with its first operation.
+struct Batch {
+    started: Instant,
+    operation_count: u64,
+    queries: HashMap<String, QueryUsage>,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Batch { started: Instant::now(), operation_count: 0, queries: HashMap::new() }
+    }
+}
+
+#[derive(Default)]
+struct QueryUsage {
+    referenced_fields: BTreeMap<String, (BTreeSet<String>, bool)>,
+    // Keyed by the client name and version.
+    stats: HashMap<(String, String), LatencyStats>,
+}
+
+struct LatencyStats {
+    histogram: Vec<i64>,
+    request_count: u64,
+    requests_with_errors_count: u64,
+}
+
+/// The signature of an operation, and the fields it references per type along with whether the
+/// type is an interface.
+#[derive(Clone)]
+struct Signature {
+    key: String,
+    referenced_fields: BTreeMap<String, (BTreeSet<String>, bool)>,
+}
+
+/// Set up usage reporting, if it's enabled.
+pub fn init(schema: &str) -> Result<(), Error> {
+    if env::var("APOLLO_ROUTER_USAGE_REPORTING").is_ok_and(|enabled| enabled == "false") {
+        return Ok(());
+    }
+    let (Ok(api_key), Ok(graph_ref)) = (env::var("APOLLO_KEY"), env::var("APOLLO_GRAPH_REF"))
+    else {
+        return Ok(());
+    };
+    let max_operations = match env::var("APOLLO_ROUTER_USAGE_REPORTING_MAX_OPERATIONS") {
+        Ok(max_operations) => max_operations.parse()?,
+        Err(_) => 100,
+    };
+    let interval = match env::var("APOLLO_ROUTER_USAGE_REPORTING_INTERVAL_SECS") {
+        Ok(interval) => Duration::from_secs(interval.parse()?),
+        Err(_) => Duration::from_secs(10),
+    };
+    let header = ReportHeader {
+        graph_ref,
+        hostname: env::var("AWS_LAMBDA_LOG_STREAM_NAME")
+            .or_else(|_| env::var("AWS_LAMBDA_FUNCTION_NAME"))
+            .unwrap_or_default(),
+        agent_version: format!("apollo-router-lambda@{}", env!("CARGO_PKG_VERSION")),
+        service_version: env::var("AWS_LAMBDA_FUNCTION_VERSION").unwrap_or_default(),
+        runtime_version: "rust".to_string(),
+        uname: format!("{}, {}", env::consts::OS, env::consts::ARCH),
+        executable_schema_id: hex::encode(Sha256::digest(schema.as_bytes())),
+    };
+    let reporter = Reporter {
+        client: Client::new(),
+        endpoint: env::var("APOLLO_USAGE_REPORTING_INGRESS_URL")
+            .unwrap_or(DEFAULT_ENDPOINT.to_string()),
+        api_key,
+        header,
+        schema: SchemaFields::from_schema(schema)?,
+        max_operations,
+        interval,
+        signatures: Mutex::new(HashMap::new()),
+        batch: Mutex::new(Batch::new()),
+    };
+    let _ = REPORTER.set(reporter);
+    Ok(())
+}
+
+/// Record a finished operation.
+pub fn record(
+    request: &graphql::Request,
+    headers: &HeaderMap,
+    duration: Duration,
+    has_errors: bool,
+) {
+    if let Some(reporter) = REPORTER.get() {
+        reporter.record(request, headers, duration, has_errors);
+    }
+}
+
+/// Send the batch if it has grown large or old enough. Lambda freezes the execution environment
+/// between invocations, so this runs after every invocation instead of on a timer.
+pub async fn flush_if_due() {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+    let due = {
+        let batch = reporter.batch.lock().unwrap();
+        batch.operation_count >= reporter.max_operations
+            || (batch.operation_count > 0 && batch.started.elapsed() >= reporter.interval)
+    };
+    if due {
+        reporter.flush().await;
+    }
+}
+
+/// Send everything that has been recorded so far.
+pub async fn flush() {
+    if let Some(reporter) = REPORTER.get() {
+        reporter.flush().await;
+    }
+}
+
+impl Reporter {
+    fn record(
+        &self,
+        request: &graphql::Request,
+        headers: &HeaderMap,
+        duration: Duration,
+        has_errors: bool,
+    ) {
+        // Operations that only send the hash of an automatic persisted query are reported once the
+        // client sends the full query.
+        let Some(query) = request.query.as_deref() else {
+            return;
+        };
+        let signature = {
+            let cache_key = (query.to_string(), request.operation_name.clone());
+            let mut signatures = self.signatures.lock().unwrap();
+            if signatures.len() >= MAX_CACHED_SIGNATURES {
+                signatures.clear();
+            }
+            signatures
+                .entry(cache_key)
+                .or_insert_with(|| {
+                    signature(&self.schema, query, request.operation_name.as_deref())
+                })
+                .clone()
+        };
+        // Operations that can't be parsed fail before they reach the subgraphs, so there's no
+        // usage worth reporting.
+        let Some(signature) = signature else {
+            return;
+        };
+
+        let header = |name: &str| {
+            headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string()
+        };
+        let client = (header(CLIENT_NAME_HEADER), header(CLIENT_VERSION_HEADER));
+
+        let mut batch = self.batch.lock().unwrap();
+        if batch.operation_count == 0 {
+            batch.started = Instant::now();
+        }
+        batch.operation_count += 1;
+        let usage = batch.queries.entry(signature.key).or_default();
+        usage.referenced_fields = signature.referenced_fields;
+        let stats = usage.stats.entry(client).or_insert_with(|| LatencyStats {
+            histogram: vec![0; HISTOGRAM_BUCKETS],
+            request_count: 0,
+            requests_with_errors_count: 0,
+        });
+        stats.histogram[duration_bucket(duration)] += 1;
+        stats.request_count += 1;
+        if has_errors {
+            stats.requests_with_errors_count += 1;
+        }
+    }
+
+    async fn flush(&self) {
+        let batch = std::mem::replace(&mut *self.batch.lock().unwrap(), Batch::new());
+        if batch.operation_count == 0 {
+            return;
+        }
+        if let Err(e) = self.send(batch).await {
+            warn!("Failed to send the usage report: {}", e);
+        }
+    }
+
+    async fn send(&self, batch: Batch) -> Result<(), Error> {
+        let end_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let traces_per_query = batch
+            .queries
+            .into_iter()
+            .map(|(key, usage)| {
+                let stats_with_context = usage
+                    .stats
+                    .into_iter()
+                    .map(|((client_name, client_version), stats)| ContextualizedStats {
+                        context: Some(StatsContext { client_name, client_version }),
+                        query_latency_stats: Some(QueryLatencyStats {
+                            latency_count: encode_histogram(&stats.histogram),
+                            request_count: stats.request_count,
+                            requests_with_errors_count: stats.requests_with_errors_count,
+                        }),
+                    })
+                    .collect();
+                let referenced_fields_by_type = usage
+                    .referenced_fields
+                    .into_iter()
+                    .map(|(type_name, (field_names, is_interface))| {
+                        let field_names = field_names.into_iter().collect();
+                        (type_name, ReferencedFieldsForType { field_names, is_interface })
+                    })
+                    .collect();
+                (key, TracesAndStats { stats_with_context, referenced_fields_by_type })
+            })
+            .collect();
+        let report = Report {
+            header: Some(self.header.clone()),
+            end_time: Some(Timestamp {
+                seconds: end_time.as_secs() as i64,
+                nanos: end_time.subsec_nanos() as i32,
+            }),
+            traces_per_query,
+            operation_count: batch.operation_count,
+        };
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&report.encode_to_vec())?;
+        let body = encoder.finish()?;
+        self.client
+            .post(&self.endpoint)
+            .header("X-Api-Key", &self.api_key)
+            .header("Content-Encoding", "gzip")
+            .header("Content-Type", "application/protobuf")
+            .header("Accept", "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// The bucket of a duration in Apollo's duration histogram, where each bucket is 10% larger than
+/// the previous one, starting at 1µs.
+fn duration_bucket(duration: Duration) -> usize {
+    let micros = duration.as_nanos() as f64 / 1000.0;
+    let bucket = (micros.ln() / 1.1_f64.ln()).ceil();
+    if bucket.is_nan() || bucket <= 0.0 {
+        0
+    } else {
+        (bucket as usize).min(HISTOGRAM_BUCKETS - 1)
+    }
+}
+
+/// Encode a histogram the way the reporting protocol expects, where runs of empty buckets are
+/// written as their negated length and trailing empty buckets are left out.
+fn encode_histogram(histogram: &[i64]) -> Vec<i64> {
+    let mut encoded = Vec::new();
+    let mut zeroes = 0;
+    for &count in histogram {
+        if count == 0 {
+            zeroes += 1;
+            continue;
+        }
+        match zeroes {
+            0 => {}
+            1 => encoded.push(0),
+            _ => encoded.push(-zeroes),
+        }
+        encoded.push(count);
+        zeroes = 0;
+    }
+    encoded
+}
+
+/// The fields of each object and interface type in the supergraph, with the name of the type
+/// they return, so we can follow an operation's selections through the schema.
+#[derive(Default)]
+struct SchemaFields {
+    types: HashMap<String, (HashMap<String, String>, bool)>,
+    roots: HashMap<&'static str, String>,
+}
+
+impl SchemaFields {
+    fn from_schema(schema: &str) -> Result<Self, Error> {
+        let document = graphql_parser::parse_schema::<String>(schema)
+            .map_err(|e| format!("failed to parse the supergraph schema: {e}"))?;
+        let mut fields = SchemaFields::default();
+        for (root, name) in
+            [("query", "Query"), ("mutation", "Mutation"), ("subscription", "Subscription")]
+        {
+            fields.roots.insert(root, name.to_string());
+        }
+        for definition in document.definitions {
+            let (name, type_fields, is_interface) = match definition {
+                schema::Definition::SchemaDefinition(schema) => {
+                    for (root, name) in [
+                        ("query", schema.query),
+                        ("mutation", schema.mutation),
+                        ("subscription", schema.subscription),
+                    ] {
+                        if let Some(name) = name {
+                            fields.roots.insert(root, name);
+                        }
+                    }
+                    continue;
+                }
+                schema::Definition::TypeDefinition(TypeDefinition::Object(object)) => {
+                    (object.name, object.fields, false)
+                }
+                schema::Definition::TypeDefinition(TypeDefinition::Interface(interface)) => {
+                    (interface.name, interface.fields, true)
+                }
+                schema::Definition::TypeExtension(TypeExtension::Object(object)) => {
+                    (object.name, object.fields, false)
+                }
+                schema::Definition::TypeExtension(TypeExtension::Interface(interface)) => {
+                    (interface.name, interface.fields, true)
+                }
+                _ => continue,
+            };
+            let entry = fields.types.entry(name).or_insert_with(|| (HashMap::new(), is_interface));
+            for field in type_fields {
+                entry.0.insert(field.name, ast::named_type(&field.field_type));
+            }
+        }
+        Ok(fields)
+    }
+
+    fn field_type(&self, parent: &str, field: &str) -> Option<&str> {
+        self.types.get(parent)?.0.get(field).map(String::as_str)
+    }
+
+    fn is_interface(&self, type_name: &str) -> bool {
+        self.types.get(type_name).is_some_and(|(_, is_interface)| *is_interface)
+    }
+}
+
+/// Compute the usage reporting signature of an operation, following Apollo's default signature
+/// algorithm: drop the other operations and unused fragments, hide literals, remove aliases,
+/// sort everything, and print it with as little whitespace as possible.
+fn signature(
+    schema: &SchemaFields,
+    query: &str,
+    operation_name: Option<&str>,
+) -> Option<Signature> {
+    let document = graphql_parser::parse_query::<String>(query).ok()?;
+    let fragments: HashMap<String, FragmentDefinition<'_, String>> = document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.name.clone(), fragment.clone())),
+            _ => None,
+        })
+        .collect();
+    let operation = ast::select_operation(&document, operation_name)?.clone();
+
+    let (root, selection_set) = ast::root_selection_set(&operation);
+    let mut referenced_fields = BTreeMap::new();
+    let mut used_fragments = HashSet::new();
+    if let Some(root_type) = schema.roots.get(root) {
+        collect_fields(
+            schema,
+            &fragments,
+            root_type,
+            selection_set,
+            &mut referenced_fields,
+            &mut used_fragments,
+        );
+    }
+
+    let mut definitions = vec![Definition::Operation(operation)];
+    definitions.extend(
+        fragments
+            .into_values()
+            .filter(|fragment| used_fragments.contains(&fragment.name))
+            .map(Definition::Fragment),
+    );
+    for definition in &mut definitions {
+        normalize_definition(definition);
+    }
+    definitions.sort_by_cached_key(|definition| match definition {
+        Definition::Fragment(fragment) => (0, fragment.name.clone()),
+        Definition::Operation(operation) => {
+            (1, ast::operation_name(operation).unwrap_or_default().to_string())
+        }
+    });
+    let printed = Document { definitions }.to_string();
+    let key = format!("# {}\n{}", operation_name.unwrap_or("-"), reduce_whitespace(&printed));
+    Some(Signature { key, referenced_fields })
+}
+
+/// Walk the selections of an operation, recording each field it references on each type, and
+/// which fragments it uses.
+fn collect_fields(
+    schema: &SchemaFields,
+    fragments: &HashMap<String, FragmentDefinition<'_, String>>,
+    parent: &str,
+    selection_set: &SelectionSet<'_, String>,
+    referenced_fields: &mut BTreeMap<String, (BTreeSet<String>, bool)>,
+    used_fragments: &mut HashSet<String>,
+) {
+    for selection in &selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                if field.name.starts_with("__") {
+                    continue;
+                }
+                referenced_fields
+                    .entry(parent.to_string())
+                    .or_insert_with(|| (BTreeSet::new(), schema.is_interface(parent)))
+                    .0
+                    .insert(field.name.clone());
+                if let Some(field_type) = schema.field_type(parent, &field.name) {
+                    collect_fields(
+                        schema,
+                        fragments,
+                        field_type,
+                        &field.selection_set,
+                        referenced_fields,
+                        used_fragments,
+                    );
+                }
+            }
+            Selection::InlineFragment(fragment) => {
+                let parent = match &fragment.type_condition {
+                    Some(TypeCondition::On(type_name)) => type_name.as_str(),
+                    None => parent,
+                };
+                collect_fields(
+                    schema,
+                    fragments,
+                    parent,
+                    &fragment.selection_set,
+                    referenced_fields,
+                    used_fragments,
+                );
+            }
+            Selection::FragmentSpread(spread) => {
+                // Fragments can't be recursive in a valid operation, but we guard against it
+                // since we haven't validated the operation yet.
+                if !used_fragments.insert(spread.fragment_name.clone()) {
+                    continue;
+                }
+                if let Some(fragment) = fragments.get(&spread.fragment_name) {
+                    let TypeCondition::On(parent) = &fragment.type_condition;
+                    collect_fields(
+                        schema,
+                        fragments,
+                        parent,
+                        &fragment.selection_set,
+                        referenced_fields,
+                        used_fragments,
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn normalize_definition(definition: &mut Definition<'_, String>) {
+    let (variable_definitions, directives, selection_set) = match definition {
+        Definition::Fragment(fragment) => {
+            (None, &mut fragment.directives, &mut fragment.selection_set)
+        }
+        Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+            normalize_selection_set(selection_set);
+            return;
+        }
+        Definition::Operation(OperationDefinition::Query(query)) => {
+            (Some(&mut query.variable_definitions), &mut query.directives, &mut query.selection_set)
+        }
+        Definition::Operation(OperationDefinition::Mutation(mutation)) => (
+            Some(&mut mutation.variable_definitions),
+            &mut mutation.directives,
+            &mut mutation.selection_set,
+        ),
+        Definition::Operation(OperationDefinition::Subscription(subscription)) => (
+            Some(&mut subscription.variable_definitions),
+            &mut subscription.directives,
+            &mut subscription.selection_set,
+        ),
+    };
+    if let Some(variable_definitions) = variable_definitions {
+        for variable_definition in variable_definitions.iter_mut() {
+            if let Some(default_value) = &mut variable_definition.default_value {
+                hide_literals(default_value);
+            }
+        }
+        variable_definitions.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    normalize_directives(directives);
+    normalize_selection_set(selection_set);
+}
+
+fn normalize_selection_set(selection_set: &mut SelectionSet<'_, String>) {
+    for selection in &mut selection_set.items {
+        match selection {
+            Selection::Field(field) => {
+                field.alias = None;
+                for (_, value) in &mut field.arguments {
+                    hide_literals(value);
+                }
+                field.arguments.sort_by(|(a, _), (b, _)| a.cmp(b));
+                normalize_directives(&mut field.directives);
+                normalize_selection_set(&mut field.selection_set);
+            }
+            Selection::FragmentSpread(spread) => normalize_directives(&mut spread.directives),
+            Selection::InlineFragment(fragment) => {
+                normalize_directives(&mut fragment.directives);
+                normalize_selection_set(&mut fragment.selection_set);
+            }
+        }
+    }
+    // Sort by kind and then by name, where the kinds are ordered the way Apollo's `sortAST`
+    // orders them: fields, then fragment spreads, then inline fragments.
+    selection_set.items.sort_by_cached_key(|selection| match selection {
+        Selection::Field(field) => (0, field.name.clone()),
+        Selection::FragmentSpread(spread) => (1, spread.fragment_name.clone()),
+        Selection::InlineFragment(fragment) => match &fragment.type_condition {
+            Some(TypeCondition::On(type_name)) => (2, type_name.clone()),
+            None => (2, String::new()),
+        },
+    });
+}
+
+fn normalize_directives(directives: &mut [graphql_parser::query::Directive<'_, String>]) {
+    for directive in directives.iter_mut() {
+        for (_, value) in &mut directive.arguments {
+            hide_literals(value);
+        }
+        directive.arguments.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    directives.sort_by(|a, b| a.name.cmp(&b.name));
+}
+
+/// Replace literals with empty values, so that operations that only differ in their inline
+/// arguments share a signature and no personal data ends up in the report.
+fn hide_literals(value: &mut Value<'_, String>) {
+    *value = match value {
+        Value::Int(_) | Value::Float(_) => Value::Int(0.into()),
+        Value::String(_) => Value::String(String::new()),
+        Value::List(_) => Value::List(Vec::new()),
+        Value::Object(_) => Value::Object(BTreeMap::new()),
+        _ => return,
+    };
+}
+
+/// Collapse whitespace the way Apollo's `printWithReducedWhitespace` does: every run of
+/// whitespace becomes a single space, which is then dropped next to anything that isn't part of
+/// a name.
+fn reduce_whitespace(printed: &str) -> String {
+    let collapsed = printed.split_whitespace().collect::<Vec<_>>().join(" ");
+    let is_word = |c: char| c == '_' || c.is_ascii_alphanumeric();
+    let chars: Vec<char> = collapsed.chars().collect();
+    let mut reduced = String::with_capacity(collapsed.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if c == ' ' {
+            let before = i.checked_sub(1).map(|i| chars[i]);
+            let after = chars.get(i + 1).copied();
+            if !before.is_some_and(is_word) || !after.is_some_and(is_word) {
+                continue;
+            }
+        }
+        reduced.push(c);
+    }
+    reduced
+}
+
+// The parts of Apollo's reporting protocol that we use, see `reports.proto` in the Router.
+
+#[derive(Clone, PartialEq, Message)]
+struct Report {
+    #[prost(message, optional, tag = "1")]
+    header: Option<ReportHeader>,
+    #[prost(message, optional, tag = "2")]
+    end_time: Option<Timestamp>,
+    #[prost(map = "string, message", tag = "5")]
+    traces_per_query: HashMap<String, TracesAndStats>,
+    #[prost(uint64, tag = "6")]
+    operation_count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReportHeader {
+    #[prost(string, tag = "5")]
+    hostname: String,
+    #[prost(string, tag = "6")]
+    agent_version: String,
+    #[prost(string, tag = "7")]
+    service_version: String,
+    #[prost(string, tag = "8")]
+    runtime_version: String,
+    #[prost(string, tag = "9")]
+    uname: String,
+    #[prost(string, tag = "11")]
+    executable_schema_id: String,
+    #[prost(string, tag = "12")]
+    graph_ref: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TracesAndStats {
+    #[prost(message, repeated, tag = "2")]
+    stats_with_context: Vec<ContextualizedStats>,
+    #[prost(map = "string, message", tag = "4")]
+    referenced_fields_by_type: HashMap<String, ReferencedFieldsForType>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReferencedFieldsForType {
+    #[prost(string, repeated, tag = "1")]
+    field_names: Vec<String>,
+    #[prost(bool, tag = "2")]
+    is_interface: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ContextualizedStats {
+    #[prost(message, optional, tag = "1")]
+    context: Option<StatsContext>,
+    #[prost(message, optional, tag = "2")]
+    query_latency_stats: Option<QueryLatencyStats>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StatsContext {
+    #[prost(string, tag = "2")]
+    client_name: String,
+    #[prost(string, tag = "3")]
+    client_version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct QueryLatencyStats {
+    #[prost(uint64, tag = "2")]
+    request_count: u64,
+    #[prost(uint64, tag = "8")]
+    requests_with_errors_count: u64,
+    #[prost(sint64, repeated, tag = "13")]
+    latency_count: Vec<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    const SCHEMA: &str = "
+        type Query { me: User, user(id: ID!): User }
+        interface Node { id: ID! }
+        type User implements Node { id: ID! name: String, friends(first: Int): [User!]! }
+    ";
+
+    fn key(query: &str, operation_name: Option<&str>) -> Option<String> {
+        let schema = SchemaFields::from_schema(SCHEMA).unwrap();
+        signature(&schema, query, operation_name).map(|signature| signature.key)
+    }
+
+    #[test]
+    fn normalizes_signatures() {
+        assert_eq!(
+            key(
+                r#"query Friends($n: Int = 3) {
+                    me { pal: friends(first: 10) { ...F name } id }
+                    user(id: "1") { name }
+                }
+                fragment F on User { id }
+                fragment Unused on User { name }
+                query Other { me { id } }"#,
+                Some("Friends"),
+            )
+            .as_deref(),
+            Some(
+                "# Friends\nfragment F on User{id}\
+                 query Friends($n:Int=0){me{friends(first:0){name...F}id}user(id:\"\"){name}}"
+            )
+        );
+        assert_eq!(key("{ me { name } }", None).as_deref(), Some("# -\n{me{name}}"));
+        assert_eq!(key("query A { me { id } }", Some("B")), None);
+        assert_eq!(key("{ me {", None), None);
+    }
+
+    #[test]
+    fn buckets_and_encodes_durations() {
+        assert_eq!(duration_bucket(Duration::ZERO), 0);
+        assert_eq!(duration_bucket(Duration::from_micros(1)), 0);
+        assert_eq!(duration_bucket(Duration::from_millis(1)), 73);
+        assert_eq!(duration_bucket(Duration::from_secs(3600)), 231);
+        assert_eq!(duration_bucket(Duration::MAX), HISTOGRAM_BUCKETS - 1);
+        assert_eq!(encode_histogram(&[0, 0, 2, 0, 1, 0, 0]), [-2, 2, 0, 1]);
+        assert_eq!(encode_histogram(&[0; 4]), Vec::<i64>::new());
+    }
+
+    type Reports = Arc<Mutex<Vec<(HeaderMap, Report)>>>;
+
+    /// A stand-in for the usage reporting endpoint, like `scripts/mock-usage-reporting.ts`,
+    /// which decodes every report it receives.
+    fn endpoint() -> (String, Reports) {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&reports);
+        let make_service = make_service_fn(move |_| {
+            let reports = Arc::clone(&received);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let reports = Arc::clone(&reports);
+                    async move {
+                        if request.uri().path() != "/api/ingress/traces" {
+                            return Ok::<_, Infallible>(
+                                Response::builder().status(404).body(Body::empty()).unwrap(),
+                            );
+                        }
+                        let headers = request.headers().clone();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let mut report = Vec::new();
+                        GzDecoder::new(&body[..]).read_to_end(&mut report).unwrap();
+                        let report = Report::decode(report.as_slice()).unwrap();
+                        reports.lock().unwrap().push((headers, report));
+                        Ok(Response::new(Body::from("{}")))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (format!("http://{address}/api/ingress/traces"), reports)
+    }
+
+    #[tokio::test]
+    async fn reports_batched_operations() {
+        let (endpoint, reports) = endpoint();
+        let reporter = Reporter {
+            client: Client::new(),
+            endpoint,
+            api_key: "service:graph:key".to_string(),
+            header: ReportHeader { graph_ref: "graph@current".to_string(), ..Default::default() },
+            schema: SchemaFields::from_schema(SCHEMA).unwrap(),
+            max_operations: 100,
+            interval: Duration::from_secs(10),
+            signatures: Mutex::new(HashMap::new()),
+            batch: Mutex::new(Batch::new()),
+        };
+        let request = graphql::Request::builder()
+            .query("query Me { me { name ... on Node { id } } }")
+            .operation_name("Me")
+            .build();
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_NAME_HEADER, "web".parse().unwrap());
+        headers.insert(CLIENT_VERSION_HEADER, "1.0".parse().unwrap());
+        reporter.record(&request, &headers, Duration::from_millis(5), false);
+        reporter.record(&request, &headers, Duration::from_millis(7), true);
+        // Persisted query hashes without the query aren't reported.
+        reporter.record(&graphql::Request::builder().build(), &headers, Duration::ZERO, false);
+
+        reporter.flush().await;
+        // An empty batch isn't sent.
+        reporter.flush().await;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        let (headers, report) = &reports[0];
+        assert_eq!(headers["x-api-key"], "service:graph:key");
+        assert_eq!(headers["content-type"], "application/protobuf");
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(report.header.as_ref().unwrap().graph_ref, "graph@current");
+        assert_eq!(report.operation_count, 2);
+
+        let usage = &report.traces_per_query["# Me\nquery Me{me{name...on Node{id}}}"];
+        let fields = |type_name: &str| {
+            let fields = &usage.referenced_fields_by_type[type_name];
+            (fields.field_names.clone(), fields.is_interface)
+        };
+        assert_eq!(fields("Query"), (vec!["me".to_string()], false));
+        assert_eq!(fields("User"), (vec!["name".to_string()], false));
+        assert_eq!(fields("Node"), (vec!["id".to_string()], true));
+        let [stats] = usage.stats_with_context.as_slice() else {
+            panic!("expected the stats of a single client");
+        };
+        let context = stats.context.as_ref().unwrap();
+        assert_eq!((context.client_name.as_str(), context.client_version.as_str()), ("web", "1.0"));
+        let latency = stats.query_latency_stats.as_ref().unwrap();
+        assert_eq!((latency.request_count, latency.requests_with_errors_count), (2, 1));
+        assert_eq!(latency.latency_count.iter().filter(|&&count| count > 0).sum::<i64>(), 2);
+    }
+}
@@ -0,0 +1,295 @@
+use apollo_router::services::subgraph;
+use apollo_router::Context;
+use futures::future::BoxFuture;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde_json::json;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::UdpSocket;
+use std::task::Poll;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower::{BoxError, Service};
+use tracing::warn;
+
+/// The header that X-Ray uses to propagate traces over HTTP.
+pub const XRAY_HEADER: &str = "x-amzn-trace-id";
+
+/// The key under which we keep the trace header in the router's request context, so that it is
+/// available when the subgraphs are fetched.
+const CONTEXT_KEY: &str = "apollo_router_lambda::xray_trace_header";
+
+tokio::task_local! {
+    static TRACE_HEADER: Option<TraceHeader>;
+}
+
+/// A parsed X-Ray trace header, e.g.
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub root: String,
+    pub parent: Option<String>,
+    pub sampled: bool,
+}
+
+impl TraceHeader {
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = false;
+        for part in header.split(';') {
+            match part.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value.to_string()),
+                Some(("Parent", value)) => parent = Some(value.to_string()),
+                Some(("Sampled", value)) => sampled = value == "1",
+                _ => {}
+            }
+        }
+        Some(TraceHeader { root: root?, parent, sampled })
+    }
+
+    /// Convert the trace into a W3C `traceparent`. The X-Ray root is a version, an epoch and a
+    /// random part, which together make up the 32 hex characters of a W3C trace ID.
+    pub fn traceparent(&self) -> Option<String> {
+        let trace_id: String = self.root.split_once('-')?.1.replace('-', "");
+        let parent = self.parent.as_deref()?;
+        if trace_id.len() != 32 || parent.len() != 16 {
+            return None;
+        }
+        Some(format!("00-{}-{}-{:02}", trace_id, parent, u8::from(self.sampled)))
+    }
+
+    /// The propagation headers for the trace, in both X-Ray and W3C format.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Ok(value) = HeaderValue::from_str(&self.to_string()) {
+            headers.insert(XRAY_HEADER, value);
+        }
+        if let Some(value) = self.traceparent().and_then(|t| HeaderValue::from_str(&t).ok()) {
+            headers.insert("traceparent", value);
+        }
+        headers
+    }
+}
+
+impl fmt::Display for TraceHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Root={}", self.root)?;
+        if let Some(parent) = &self.parent {
+            write!(f, ";Parent={parent}")?;
+        }
+        write!(f, ";Sampled={}", u8::from(self.sampled))
+    }
+}
+
+/// Read the trace header of the current invocation, preferring the one from the invocation's
+/// context over the `_X_AMZN_TRACE_ID` environment variable.
+pub fn from_invocation(xray_trace_id: Option<&str>) -> Option<TraceHeader> {
+    match xray_trace_id {
+        Some(header) => TraceHeader::parse(header),
+        None => env::var("_X_AMZN_TRACE_ID").ok().and_then(|header| TraceHeader::parse(&header)),
+    }
+}
+
+/// Run a future with the given trace header as the current one.
+pub async fn scope<F: Future>(header: Option<TraceHeader>, future: F) -> F::Output {
+    TRACE_HEADER.scope(header, future).await
+}
+
+/// The trace header of the invocation we are currently handling, if any.
+pub fn current() -> Option<TraceHeader> {
+    TRACE_HEADER.try_with(Clone::clone).ok().flatten()
+}
+
+/// Add the current trace to a supergraph request, so that the `headers` rules can propagate it to
+/// the subgraphs and we can find it again when they are fetched. Headers that the caller already
+/// sent take precedence.
+pub fn propagate(headers: &mut HeaderMap, context: &Context) {
+    let Some(header) = current() else {
+        return;
+    };
+    for (name, value) in &header.headers() {
+        if !headers.contains_key(name) {
+            headers.insert(name, value.clone());
+        }
+    }
+    let _ = context.insert(CONTEXT_KEY, header.to_string());
+}
+
+/// Whether to send a subsegment to the X-Ray daemon for each subgraph fetch, enabled by setting
+/// `APOLLO_ROUTER_XRAY_SUBSEGMENTS=true`.
+pub fn subsegments_enabled() -> bool {
+    env::var("APOLLO_ROUTER_XRAY_SUBSEGMENTS").is_ok_and(|enabled| enabled == "true")
+}
+
+/// Record each subgraph fetch as an X-Ray subsegment of the function's segment.
+pub fn instrument_subgraph(name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+    subgraph::BoxService::new(SubsegmentService { name: name.to_string(), inner: service })
+}
+
+struct SubsegmentService {
+    name: String,
+    inner: subgraph::BoxService,
+}
+
+impl Service<subgraph::Request> for SubsegmentService {
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: subgraph::Request) -> Self::Future {
+        let header = request
+            .context
+            .get::<_, String>(CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .and_then(|header| TraceHeader::parse(&header));
+        let name = self.name.clone();
+        let start_time = epoch_seconds();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            if let Some(header) = header.filter(|header| header.sampled) {
+                let status = response.as_ref().ok().map(|r| r.response.status().as_u16());
+                send_subsegment(&header, &name, start_time, epoch_seconds(), status);
+            }
+            response
+        })
+    }
+}
+
+fn epoch_seconds() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+/// Send an independent subsegment to the X-Ray daemon over UDP, see
+/// https://docs.aws.amazon.com/xray/latest/devguide/xray-api-sendingdata.html#xray-api-daemon.
+fn send_subsegment(
+    header: &TraceHeader,
+    name: &str,
+    start_time: f64,
+    end_time: f64,
+    status: Option<u16>,
+) {
+    let Some(parent_id) = &header.parent else {
+        return;
+    };
+    let address = env::var("AWS_XRAY_DAEMON_ADDRESS").unwrap_or("127.0.0.1:2000".to_string());
+    // Every `RandomState` is seeded randomly, which is random enough for a segment ID.
+    let id = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let subsegment = json!({
+        "name": name,
+        "id": id,
+        "trace_id": header.root,
+        "parent_id": parent_id,
+        "type": "subsegment",
+        "namespace": "remote",
+        "start_time": start_time,
+        "end_time": end_time,
+        "error": status.filter(|status| *status < 400).is_none(),
+        "http": { "response": { "status": status } },
+    });
+    let packet = format!("{{\"format\": \"json\", \"version\": 1}}\n{subsegment}");
+    let sent =
+        UdpSocket::bind("0.0.0.0:0").and_then(|socket| socket.send_to(packet.as_bytes(), &address));
+    if let Err(e) = sent {
+        warn!("Failed to send X-Ray subsegment to {}: {}", address, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str =
+        "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+    #[test]
+    fn parses_trace_headers() {
+        let header = TraceHeader::parse(HEADER).unwrap();
+        assert_eq!(
+            header,
+            TraceHeader {
+                root: "1-5759e988-bd862e3fe1be46a994272793".to_string(),
+                parent: Some("53995c3f42cd8ad8".to_string()),
+                sampled: true,
+            }
+        );
+        assert_eq!(header.to_string(), HEADER);
+
+        // Lambda puts other fields in there too, and the order isn't fixed.
+        let header = TraceHeader::parse(
+            "Sampled=0; Lineage=a87bd80c:0; Root=1-5759e988-bd862e3fe1be46a994272793",
+        )
+        .unwrap();
+        assert_eq!(header.parent, None);
+        assert!(!header.sampled);
+        assert_eq!(header.to_string(), "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0");
+        assert!(!TraceHeader::parse("Root=1-5759e988-bd862e3fe1be46a994272793").unwrap().sampled);
+
+        assert_eq!(TraceHeader::parse("Parent=53995c3f42cd8ad8;Sampled=1"), None);
+        assert_eq!(TraceHeader::parse(""), None);
+    }
+
+    #[test]
+    fn converts_to_a_w3c_traceparent() {
+        let mut header = TraceHeader::parse(HEADER).unwrap();
+        // The epoch and the random part of the root make up the trace ID, without the version.
+        assert_eq!(
+            header.traceparent().as_deref(),
+            Some("00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-01")
+        );
+        header.sampled = false;
+        assert_eq!(
+            header.traceparent().as_deref(),
+            Some("00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-00")
+        );
+
+        let headers = header.headers();
+        assert_eq!(headers[XRAY_HEADER], header.to_string().as_str());
+        assert_eq!(
+            headers["traceparent"],
+            "00-5759e988bd862e3fe1be46a994272793-53995c3f42cd8ad8-00"
+        );
+
+        // Without a parent or with a malformed root there is no W3C equivalent, but the X-Ray
+        // header is still propagated.
+        for malformed in [
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1",
+            "Root=1-5759e988-bd862e3f;Parent=53995c3f42cd8ad8;Sampled=1",
+            "Root=5759e988bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f;Sampled=1",
+        ] {
+            let header = TraceHeader::parse(malformed).unwrap();
+            assert_eq!(header.traceparent(), None, "{malformed}");
+            let headers = header.headers();
+            assert!(headers.contains_key(XRAY_HEADER) && !headers.contains_key("traceparent"));
+        }
+    }
+
+    #[tokio::test]
+    async fn propagates_the_current_trace() {
+        let context = Context::new();
+        let mut headers = HeaderMap::new();
+        propagate(&mut headers, &context);
+        assert!(headers.is_empty());
+
+        let header = from_invocation(Some(HEADER));
+        scope(header, async {
+            let mut headers = HeaderMap::new();
+            headers.insert("traceparent", HeaderValue::from_static("00-from-the-caller-01"));
+            propagate(&mut headers, &context);
+            assert_eq!(headers[XRAY_HEADER], HEADER);
+            assert_eq!(headers["traceparent"], "00-from-the-caller-01");
+        })
+        .await;
+        assert_eq!(context.get::<_, String>(CONTEXT_KEY).unwrap().as_deref(), Some(HEADER));
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,89 @@
+use apollo_router::services::{subgraph, supergraph};
+use apollo_router::TestHarness;
+use reqwest::header::HeaderMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower::ServiceExt;
+
+/// The example script in rhai/ runs on the TestHarness path that the Lambda uses, and changes
+/// the headers of every subgraph request.
+#[tokio::test]
+async fn example_script_sets_subgraph_headers() {
+    let scripts = concat!(env!("CARGO_MANIFEST_DIR"), "/../rhai");
+    let configuration = serde_json::json!({ "rhai": { "scripts": scripts, "main": "main.rhai" } });
+    let sent: Arc<Mutex<Vec<HeaderMap>>> = Arc::default();
+    let recorded = Arc::clone(&sent);
+    let supergraph = TestHarness::builder()
+        .configuration_json(configuration)
+        .unwrap()
+        .schema(include_str!("../../supergraph.graphql"))
+        // Runs inside the Rhai plugin, so it sees the headers that the subgraphs would get.
+        .subgraph_hook(move |_name, service| {
+            let recorded = Arc::clone(&recorded);
+            service
+                .map_request(move |request: subgraph::Request| {
+                    recorded.lock().unwrap().push(request.subgraph_request.headers().clone());
+                    request
+                })
+                .boxed()
+        })
+        .build_supergraph()
+        .await
+        .unwrap();
+
+    let request = supergraph::Request::fake_builder()
+        .query("{ me { name } }")
+        .header("apollographql-client-name", "web")
+        .build()
+        .unwrap();
+    let mut response = supergraph.oneshot(request).await.unwrap();
+    response.next_response().await;
+
+    let sent = sent.lock().unwrap();
+    assert!(!sent.is_empty(), "no subgraph was called");
+    for headers in sent.iter() {
+        assert_eq!(headers.get("x-client-name").unwrap(), "web");
+    }
+}
+
+/// Scripts are found next to the configuration, wherever it is, rather than in the working
+/// directory.
+#[test]
+fn resolves_scripts_next_to_the_configuration() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let dir = std::env::temp_dir().join(format!("rhai-resolve-{nanos}"));
+    fs::create_dir_all(dir.join("rhai")).unwrap();
+    fs::create_dir_all(dir.join("scripts")).unwrap();
+    fs::write(dir.join("rhai/main.rhai"), "").unwrap();
+    fs::write(dir.join("scripts/custom.rhai"), "").unwrap();
+    let config_path = dir.join("router.yaml");
+    let config_path = config_path.to_str().unwrap();
+    let resolve = |yaml: &str| {
+        let mut config: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        apollo_router_lambda::rhai::resolve(&mut config, config_path).map(|_| config)
+    };
+    let scripts =
+        |config: &serde_yaml::Value| config["rhai"]["scripts"].as_str().unwrap().to_owned();
+
+    // A bare `rhai:` uses the default directory and main script.
+    let config = resolve("rhai:").unwrap();
+    assert_eq!(Path::new(&scripts(&config)), dir.join("rhai"));
+
+    let config = resolve("rhai:\n  scripts: ./scripts\n  main: custom.rhai").unwrap();
+    assert_eq!(Path::new(&scripts(&config)), dir.join("scripts"));
+    assert_eq!(config["rhai"]["main"].as_str(), Some("custom.rhai"));
+
+    // Absolute directories are left as they are.
+    let absolute = format!("rhai:\n  scripts: {}", dir.join("rhai").display());
+    assert_eq!(Path::new(&scripts(&resolve(&absolute).unwrap())), dir.join("rhai"));
+
+    let missing = resolve("rhai:\n  scripts: ./scripts").unwrap_err().to_string();
+    assert!(missing.contains("scripts/main.rhai"), "{missing}");
+    // Configurations without Rhai scripts are left alone.
+    let unrelated: serde_yaml::Value = serde_yaml::from_str("supergraph: {}").unwrap();
+    assert_eq!(resolve("supergraph: {}").unwrap(), unrelated);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
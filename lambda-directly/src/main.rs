@@ -1,20 +1,28 @@
 use apollo_router::services::supergraph;
 use apollo_router::Configuration;
 use apollo_router::TestHarness;
-use lambda_http::{run, service_fn, Error, IntoResponse, Request};
+use apollo_router_lambda::event::{EventSource, HttpRequest, HttpResponse};
+use apollo_router_lambda::{cors, entities, logging, metrics, rhai};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, StatusCode};
 use std::env;
 use std::fs;
 use std::sync::Arc;
+use std::time::Instant;
 use tower::util::ServiceExt;
-use tracing::info;
+use tracing_subscriber::prelude::*;
 
-async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
+async fn handle_request(request: &HttpRequest) -> Result<HttpResponse, Error> {
+    // This variant sets up the router for every request, so every request pays for the phases
+    // that the other variants only go through during init.
+    let mut phases = metrics::InitPhases::start();
     let config_path = env::var("APOLLO_ROUTER_CONFIG_PATH").unwrap_or("./router.yaml".to_string());
     let schema_path =
         env::var("APOLLO_ROUTER_SUPERGRAPH_PATH").unwrap_or("./supergraph.graphql".to_string());
-    let config = fs::read_to_string(config_path)?;
+    let config = fs::read_to_string(&config_path)?;
     let schema = fs::read_to_string(schema_path)?;
+    phases.end("ConfigRead");
 
     // Before we cast the Router YAML configuration to a strongly typed struct, we need to
     // manually handle any environment variables that are used in the configuration. This is
@@ -36,64 +44,134 @@ async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
             }
         }
     }
+    rhai::resolve(&mut untyped_config, &config_path)?;
+    phases.end("EnvExpansion");
+
+    // The Router applies its CORS configuration in its HTTP server, which we bypass.
+    let cors = cors::Cors::from_config(&untyped_config)?;
+    if let Some(response) = cors.preflight(request) {
+        return Ok(response);
+    }
+    let mut response = execute(request, untyped_config, &schema, phases).await?;
+    cors.decorate(request, &mut response);
+    Ok(response)
+}
+
+/// Build a router from the configuration, and run the GraphQL request through it.
+async fn execute(
+    request: &HttpRequest,
+    untyped_config: serde_yaml::Value,
+    schema: &str,
+    mut phases: metrics::InitPhases,
+) -> Result<HttpResponse, Error> {
+    if request.method != Method::GET && request.method != Method::POST {
+        return Ok(HttpResponse::graphql_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "only GET and POST requests are supported",
+        ));
+    }
+    let event_payload = match request.graphql_request() {
+        Ok(graphql_request) => graphql_request,
+        Err((status, message)) => return Ok(HttpResponse::graphql_error(status, &message)),
+    };
 
     // We can finally convert our untyped YAML configuration into a strongly typed Configuration
     // struct.
     let configuration = serde_yaml::from_value::<Configuration>(untyped_config).unwrap();
-
-    let body = event.body();
-    // let event_payload = std::str::from_utf8(body).expect("invalid utf-8 sequence");
-    let event_payload: apollo_router::graphql::Request =
-        serde_json::from_slice(body).expect("invalid graphql request");
-    info!("👉 Proxying request to router: {:?}", event_payload);
-    println!("👉 Proxying request to router: {:?}", event_payload);
+    phases.end("ConfigDeserialization");
 
     let builder = supergraph::Request::fake_builder()
         .header(CONTENT_TYPE, "application/json")
-        .query(event_payload.query.unwrap_or("".to_string()))
-        .variables(event_payload.variables)
-        .extensions(event_payload.extensions);
+        .query(event_payload.query.clone().unwrap_or("".to_string()))
+        .variables(event_payload.variables.clone())
+        .extensions(event_payload.extensions.clone());
     // TODO: Avoid this weird hack.
-    let request = if let Some(operation_name) = event_payload.operation_name {
+    let mut supergraph_request = if let Some(operation_name) = event_payload.operation_name.clone()
+    {
         builder.operation_name(operation_name).build().unwrap()
     } else {
         builder.build().unwrap()
     };
+    // Pass on the incoming headers, so the `headers` rules in the Router configuration can
+    // propagate them to the subgraphs.
+    for (name, value) in &request.headers {
+        if *name != CONTENT_TYPE {
+            supergraph_request.supergraph_request.headers_mut().append(name, value.clone());
+        }
+    }
 
     let supergraph = TestHarness::builder()
         .configuration(Arc::new(configuration))
-        .schema(&schema)
+        .schema(schema)
         // Without this all subgraphs get an empty response by default.
         .with_subgraph_network_requests()
+        // The router is built again for every request, but the entity cache outlives it. The
+        // cache is outside of the metrics, so that they only count actual fetches.
+        .subgraph_hook(|name, service| {
+            entities::instrument_subgraph(name, metrics::instrument_subgraph(name, service))
+        })
         .build_router()
         .await?;
-    let mut response = supergraph.oneshot(request.try_into().unwrap()).await?;
+    phases.end("BuildRouter");
+    let started = Instant::now();
+    let operation =
+        metrics::Operation::start(event_payload.operation_name.as_deref(), &request.headers);
+    let result: Result<_, Error> = async {
+        let mut response = supergraph.oneshot(supergraph_request.try_into().unwrap()).await?;
+        let status = response.response.status();
+        // Alternatively, deserialize to apollo_router::graphql::Response.
+        let resp: serde_json::Value = serde_json::from_slice(
+            response.next_response().await.unwrap().unwrap().to_vec().as_slice(),
+        )?;
+        Ok((status, resp))
+    }
+    .await;
+    operation.finish(match &result {
+        Ok((status, resp)) => metrics::failed(*status, resp),
+        Err(_) => true,
+    });
+    let (status, resp) = result?;
+    // Planning the query happens as part of the request, so it's included in its phase.
+    phases.end("FirstRequest");
+    phases.finish();
+    logging::operation(&event_payload, started.elapsed(), Some(status), Some(&resp));
 
-    // Alternatively, deserialize to apollo_router::graphql::Response.
-    let resp: serde_json::Value = serde_json::from_slice(
-        response.next_response().await.unwrap().unwrap().to_vec().as_slice(),
-    )?;
-    info!("👉 Deserialized Response: {:?}", resp);
-    println!("👉 Deserialized Response: {:?}", resp);
+    Ok(HttpResponse::json(status, &resp))
+}
 
-    Ok(resp)
+/// Detect which front door the event came through, handle it, and shape the response back into
+/// the format that front door expects. Admin events that invalidate cached entities come straight
+/// from the Invoke API instead.
+async fn handle_event(event: LambdaEvent<serde_json::Value>) -> Result<serde_json::Value, Error> {
+    let (payload, context) = event.into_parts();
+    logging::set_request_id(Some(&context.request_id));
+    if entities::is_invalidation(&payload) {
+        return entities::invalidate(&payload);
+    }
+    let source = EventSource::detect(&payload).ok_or("unsupported event type")?;
+    let request = HttpRequest::from_event(source, payload)?;
+    let response = handle_request(&request).await?;
+    Ok(response.into_event(&request))
+}
+
+/// The router is only built once a request comes in, so we check that its Rhai scripts are there
+/// during init, rather than failing every request.
+fn verify_rhai() -> Result<(), Error> {
+    let config_path = env::var("APOLLO_ROUTER_CONFIG_PATH").unwrap_or("./router.yaml".to_string());
+    let mut config = serde_yaml::from_str::<serde_yaml::Value>(&fs::read_to_string(&config_path)?)?;
+    rhai::resolve(&mut config, &config_path)
 }
 
 async fn handler() -> Result<(), Error> {
     // Set up the Lambda event handler.
-    run(service_fn(|event: Request| async { handle_request(event).await })).await
+    run(service_fn(|event: LambdaEvent<serde_json::Value>| async { handle_event(event).await }))
+        .await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::WARN)
-        // disable printing the name of the module in every log line.
-        .with_target(false)
-        // disabling time is handy because CloudWatch will add the ingestion time.
-        .without_time()
-        // disable coloring.
-        .with_ansi(false)
-        .init();
+    tracing_subscriber::registry().with(logging::layer()).init();
+    entities::init()?;
+    verify_rhai()?;
     handler().await
 }
@@ -0,0 +1,160 @@
+//! The CloudWatch metrics of the variants that proxy Lambda events to a router server running
+//! next to them, `lambda-with-server` and `lambda-cosmo`.
+
+use lambda_http::http::StatusCode;
+use lambda_http::Request;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const CLIENT_NAME_HEADER: &str = "apollographql-client-name";
+
+/// Only the first request in an execution environment is part of a cold start.
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// Tracks a single request to the router, and emits its metrics in the CloudWatch Embedded Metric
+/// Format once it has finished, see
+/// https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html.
+///
+/// The metrics are configured through the environment:
+///
+/// - `APOLLO_ROUTER_METRICS=false` disables the metrics.
+/// - `APOLLO_ROUTER_METRICS_NAMESPACE` sets the CloudWatch namespace, which defaults to
+///   `ApolloRouterLambda`.
+/// - `APOLLO_ROUTER_METRICS_DIMENSIONS` is a comma-separated list of `OperationName` and
+///   `ClientName`, which defaults to `OperationName`.
+pub struct Operation {
+    name: String,
+    client_name: String,
+    started: Instant,
+}
+
+impl Operation {
+    pub fn start(event: &Request) -> Self {
+        let name = serde_json::from_slice::<Value>(event.body())
+            .ok()
+            .and_then(|body| body.get("operationName")?.as_str().map(str::to_string));
+        let client_name = event.headers().get(CLIENT_NAME_HEADER).and_then(|n| n.to_str().ok());
+        Operation {
+            name: name.unwrap_or("anonymous".to_string()),
+            client_name: client_name.unwrap_or("unknown").to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Emit the metrics of the request, including how long we waited for the router to start.
+    pub fn finish(self, retries: u64, failed: bool) {
+        if env::var("APOLLO_ROUTER_METRICS").is_ok_and(|enabled| enabled == "false") {
+            return;
+        }
+        let namespace =
+            env::var("APOLLO_ROUTER_METRICS_NAMESPACE").unwrap_or("ApolloRouterLambda".to_string());
+        let dimensions: Map<String, Value> = env::var("APOLLO_ROUTER_METRICS_DIMENSIONS")
+            .unwrap_or("OperationName".to_string())
+            .split(',')
+            .filter_map(|dimension| match dimension.trim() {
+                "OperationName" => Some(("OperationName".to_string(), json!(self.name))),
+                "ClientName" => Some(("ClientName".to_string(), json!(self.client_name))),
+                _ => None,
+            })
+            .collect();
+        let cold_start = COLD_START.swap(false, Ordering::Relaxed);
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        let mut values = dimensions.clone();
+        values.insert("Latency".to_string(), json!(self.started.elapsed().as_secs_f64() * 1000.0));
+        values.insert("Errors".to_string(), json!(u8::from(failed)));
+        values.insert("ColdStart".to_string(), json!(u8::from(cold_start)));
+        values.insert("RouterStartupRetries".to_string(), json!(retries));
+        values.insert("RouterStartupWait".to_string(), json!(retries * 10));
+        values.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": namespace,
+                    "Dimensions": [dimensions.keys().collect::<Vec<_>>()],
+                    "Metrics": [
+                        { "Name": "Latency", "Unit": "Milliseconds" },
+                        { "Name": "Errors", "Unit": "Count" },
+                        { "Name": "ColdStart", "Unit": "Count" },
+                        { "Name": "RouterStartupRetries", "Unit": "Count" },
+                        { "Name": "RouterStartupWait", "Unit": "Milliseconds" },
+                    ],
+                }],
+            }),
+        );
+        println!("{}", Value::Object(values));
+    }
+}
+
+/// Whether a request failed, which is when its response has an error status or any GraphQL
+/// errors, the same as in the other variants.
+pub fn failed(status: StatusCode, response: &Value) -> bool {
+    status.as_u16() >= 400
+        || response.get("errors").and_then(Value::as_array).is_some_and(|errors| !errors.is_empty())
+}
+
+/// Times the phases of the init, so we can see where the cold start goes. Each phase runs from
+/// the end of the previous one, and they are emitted together as a single line once the init is
+/// done, with the function version as the dimension so regressions show up per release. The
+/// router starts in the background, so the init is only done once it listens, which can be after
+/// the first request came in.
+pub struct InitPhases {
+    started: Instant,
+    last: Instant,
+    phases: Vec<(String, f64)>,
+}
+
+impl InitPhases {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        InitPhases { started: now, last: now, phases: Vec::new() }
+    }
+
+    /// Mark the end of a phase.
+    pub fn end(&mut self, phase: &str) {
+        let now = Instant::now();
+        let duration_ms = now.duration_since(self.last).as_secs_f64() * 1000.0;
+        self.phases.push((format!("Init{phase}"), duration_ms));
+        self.last = now;
+    }
+
+    pub fn finish(mut self) {
+        if env::var("APOLLO_ROUTER_METRICS").is_ok_and(|enabled| enabled == "false") {
+            return;
+        }
+        let namespace =
+            env::var("APOLLO_ROUTER_METRICS_NAMESPACE").unwrap_or("ApolloRouterLambda".to_string());
+        let total_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.phases.push(("InitTotal".to_string(), total_ms));
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        let version = env::var("AWS_LAMBDA_FUNCTION_VERSION").unwrap_or("$LATEST".to_string());
+        let mut values = Map::new();
+        values.insert("FunctionVersion".to_string(), json!(version));
+        for (name, duration_ms) in &self.phases {
+            values.insert(name.clone(), json!(duration_ms));
+        }
+        let metrics: Vec<Value> = self
+            .phases
+            .iter()
+            .map(|(name, _)| json!({ "Name": name, "Unit": "Milliseconds" }))
+            .collect();
+        values.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": namespace,
+                    "Dimensions": [["FunctionVersion"]],
+                    "Metrics": metrics,
+                }],
+            }),
+        );
+        println!("{}", Value::Object(values));
+    }
+}
@@ -1,13 +1,18 @@
-use apollo_router::{ApolloRouterError, Configuration, RouterHttpServer};
+use apollo_router::{Configuration, RouterHttpServer};
 use lambda_http::{run, service_fn, Error, IntoResponse, Request};
+use lambda_proxy_metrics as metrics;
 use reqwest::{header::CONTENT_TYPE, Client, Response};
 use std::env;
 use std::fs;
+use tokio::net::TcpStream;
 use tracing::info;
 
+/// Where the local router listens, as set in `router.yaml`.
+const ROUTER_ADDRESS: &str = "127.0.0.1:4000";
+
 /// Invoke the router locally by sending the event to the router's local HTTP server.
 async fn invoke(event: &Request) -> Result<Response, Error> {
-    let url = format!("http://127.0.0.1:4000");
+    let url = format!("http://{ROUTER_ADDRESS}");
 
     let body = event.body();
     let event_payload = std::str::from_utf8(body).expect("invalid utf-8 sequence");
@@ -29,8 +34,10 @@ async fn invoke(event: &Request) -> Result<Response, Error> {
 /// Pass on the Lambda event to the router and return the response.
 ///
 /// NOTE: We keep retrying the request every 10ms until we get a response from
-/// the router. This is because the router takes a short time to start up.
+/// the router. This is because the router takes a short time to start up. How long we waited
+/// ends up in the metrics of the request.
 async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
+    let operation = metrics::Operation::start(&event);
     let mut retries = 0;
     let mut response = invoke(&event).await;
     while retries < 500 && response.is_err() {
@@ -38,31 +45,57 @@ async fn handle_request(event: Request) -> Result<impl IntoResponse, Error> {
         response = invoke(&event).await;
         retries += 1;
     }
-    println!("Retries: {}, waited a total {}ms", retries, retries * 10);
-    let resp = response?;
-    let status = resp.status();
-    let payload = resp.json::<serde_json::Value>().await?;
+    let result: Result<_, Error> = async {
+        let resp = response?;
+        let status = resp.status();
+        let payload = resp.json::<serde_json::Value>().await?;
+        Ok((status, payload))
+    }
+    .await;
+    let failed = match &result {
+        Ok((status, payload)) => metrics::failed(*status, payload),
+        Err(_) => true,
+    };
+    operation.finish(retries, failed);
 
     // TODO: Return whitelisted headers from the Router response.
-    Ok((status, payload))
+    result
 }
 
-async fn start_router(schema: String, configuration: Configuration) -> Result<(), Error> {
-    RouterHttpServer::builder()
-        .configuration(configuration)
-        .schema(schema)
-        .start()
-        .await
-        .map_err(|e| Error::from(e.to_string()))
+/// Start the router, and finish the init metrics once it accepts connections, so that they
+/// include how long it took to start. We give up on them after as long as requests wait for the
+/// router.
+async fn start_router(
+    schema: String,
+    configuration: Configuration,
+    mut phases: metrics::InitPhases,
+) -> Result<(), Error> {
+    let server = tokio::spawn(
+        RouterHttpServer::builder().configuration(configuration).schema(schema).start(),
+    );
+    let mut retries = 0;
+    let mut listening = TcpStream::connect(ROUTER_ADDRESS).await.is_ok();
+    while retries < 500 && !listening {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        listening = TcpStream::connect(ROUTER_ADDRESS).await.is_ok();
+        retries += 1;
+    }
+    if listening {
+        phases.end("RouterStart");
+        phases.finish();
+    }
+    server.await?.map_err(|e| Error::from(e.to_string()))
 }
 
 async fn handler() -> Result<(), Error> {
     // Load configurations during the init phase of the Lambda.
+    let mut phases = metrics::InitPhases::start();
     let config_path = env::var("APOLLO_ROUTER_CONFIG_PATH").unwrap_or("./router.yaml".to_string());
     let schema_path =
         env::var("APOLLO_ROUTER_SUPERGRAPH_PATH").unwrap_or("./supergraph.graphql".to_string());
     let config = fs::read_to_string(config_path)?;
     let schema = fs::read_to_string(schema_path)?;
+    phases.end("ConfigRead");
 
     // Before we cast the Router YAML configuration to a strongly typed struct, we need to
     // manually handle any environment variables that are used in the configuration. This is
@@ -84,13 +117,15 @@ async fn handler() -> Result<(), Error> {
             }
         }
     }
+    phases.end("EnvExpansion");
 
     // We can finally convert our untyped YAML configuration into a strongly typed Configuration
     // struct.
     let configuration = serde_yaml::from_value::<Configuration>(untyped_config).unwrap();
+    phases.end("ConfigDeserialization");
 
     // Start a local Apollo Router server.
-    tokio::spawn(async move { start_router(schema, configuration).await });
+    tokio::spawn(async move { start_router(schema, configuration, phases).await });
 
     // Set up the Lambda event handler.
     run(service_fn(|event: Request| async { handle_request(event).await })).await
@@ -0,0 +1,32 @@
+// A stand-in for Apollo's usage reporting endpoint, which prints a summary of every report it
+// receives. Point APOLLO_USAGE_REPORTING_INGRESS_URL at it to try out usage reporting locally.
+const port = Number(process.env.PORT ?? 4100);
+
+Bun.serve({
+  port,
+  async fetch(request) {
+    const url = new URL(request.url);
+    if (request.method !== "POST" || url.pathname !== "/api/ingress/traces") {
+      return new Response("Not Found", { status: 404 });
+    }
+    const body = new Uint8Array(await request.arrayBuffer());
+    const report =
+      request.headers.get("content-encoding") === "gzip" ? Bun.gunzipSync(body) : body;
+    console.log(
+      JSON.stringify({
+        apiKey: request.headers.get("x-api-key") ? "[set]" : "[missing]",
+        contentType: request.headers.get("content-type"),
+        compressedBytes: body.length,
+        reportBytes: report.length,
+        // The report is protobuf, but the operation signatures are readable as plain text.
+        signatures: new TextDecoder()
+          .decode(report)
+          .match(/# [^\n]*\n[^\x00-\x1f]*/g)
+          ?.map((signature) => signature.replace("\n", " ")),
+      }),
+    );
+    return Response.json({});
+  },
+});
+
+console.log(`Mock usage reporting endpoint listening on http://127.0.0.1:${port}/api/ingress/traces`);